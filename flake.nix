@@ -153,6 +153,7 @@
           zonewatch-test-soa-change = pkgs.callPackage ./nix/tests/test-soa-change.nix { };
           zonewatch-test-dynamic-zone-update = pkgs.callPackage ./nix/tests/test-dynamic-zone-update.nix { };
           zonewatch-test-with-bind = pkgs.callPackage ./nix/tests/test-with-bind.nix { };
+          zonewatch-test-special-characters-with-bind = pkgs.callPackage ./nix/tests/test-special-characters-with-bind.nix { };
           zonewatch-test-serial-overflow = pkgs.callPackage ./nix/tests/test-serial-overflow.nix { };
           zonewatch-test-symlink = pkgs.callPackage ./nix/tests/test-symlink.nix { };
           zonewatch-test-two-zones = pkgs.callPackage ./nix/tests/test-two-zones.nix { };
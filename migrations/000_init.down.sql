@@ -1,4 +1,4 @@
-DROP TABLE zones;
-DROP INDEX zones_index;
-DROP TABLE includes;
-DROP INDEX includes_index;
+DROP INDEX IF EXISTS includes_index;
+DROP TABLE IF EXISTS includes;
+DROP INDEX IF EXISTS zones_index;
+DROP TABLE IF EXISTS zones;
@@ -0,0 +1 @@
+ALTER TABLE zones DROP COLUMN last_reloaded_serial;
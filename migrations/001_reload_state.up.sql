@@ -0,0 +1,5 @@
+-- The serial number the DNS server was last successfully told about.
+-- NULL means the zone was never reloaded successfully.
+ALTER TABLE zones ADD COLUMN last_reloaded_serial INTEGER;
+-- Assume that existing zones were reloaded successfully, since this was not tracked before
+UPDATE zones SET last_reloaded_serial = soa_serial;
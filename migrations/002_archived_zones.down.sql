@@ -0,0 +1,2 @@
+DROP TABLE archived_includes;
+DROP TABLE archived_zones;
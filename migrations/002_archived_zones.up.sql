@@ -0,0 +1,27 @@
+-- Zones which were removed from the config and archived according to `orphan_policy`.
+-- The same name can be archived multiple times, so it is not unique.
+CREATE TABLE IF NOT EXISTS archived_zones (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL,
+	archived_at INTEGER NOT NULL,
+	dir TEXT NOT NULL,
+	ttl TEXT NOT NULL,
+	soa_ttl TEXT NOT NULL,
+	soa_mname TEXT NOT NULL,
+	soa_rname TEXT NOT NULL,
+	soa_serial INTEGER NOT NULL,
+	soa_refresh TEXT NOT NULL,
+	soa_retry TEXT NOT NULL,
+	soa_expire TEXT NOT NULL,
+	soa_minimum TEXT NOT NULL,
+	last_reloaded_serial INTEGER
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS archived_includes (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	zoneid INTEGER NOT NULL,
+	path TEXT NOT NULL,
+	hash BLOB,
+	error INTEGER,
+	FOREIGN KEY(zoneid) REFERENCES archived_zones(id)
+) STRICT;
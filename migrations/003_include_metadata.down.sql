@@ -0,0 +1,5 @@
+ALTER TABLE includes DROP COLUMN ctime_ns;
+ALTER TABLE includes DROP COLUMN mtime_ns;
+ALTER TABLE includes DROP COLUMN size;
+ALTER TABLE includes DROP COLUMN ino;
+ALTER TABLE includes DROP COLUMN dev;
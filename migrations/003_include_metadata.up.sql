@@ -0,0 +1,6 @@
+-- The metadata of readable included files when they were last hashed, to skip hashing them again if it didn't change
+ALTER TABLE includes ADD COLUMN dev INTEGER;
+ALTER TABLE includes ADD COLUMN ino INTEGER;
+ALTER TABLE includes ADD COLUMN size INTEGER;
+ALTER TABLE includes ADD COLUMN mtime_ns INTEGER;
+ALTER TABLE includes ADD COLUMN ctime_ns INTEGER;
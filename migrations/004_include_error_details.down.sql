@@ -0,0 +1,7 @@
+-- Older versions only know the error codes 0 to 2, the other errors become generic errors
+UPDATE includes SET error = 0 WHERE error > 2;
+UPDATE archived_includes SET error = 0 WHERE error > 2;
+ALTER TABLE archived_includes DROP COLUMN error_message;
+ALTER TABLE archived_includes DROP COLUMN errno;
+ALTER TABLE includes DROP COLUMN error_message;
+ALTER TABLE includes DROP COLUMN errno;
@@ -0,0 +1,10 @@
+-- Besides the codes 0 (other error), 1 (not found) and 2 (permission denied) the `error` column
+-- now also uses 3 (timed out), 4 (is a directory), 5 (too many symlinks) and 6 (invalid data).
+-- The details of errors which have no code of their own in the `error` column
+ALTER TABLE includes ADD COLUMN errno INTEGER;
+ALTER TABLE includes ADD COLUMN error_message TEXT;
+ALTER TABLE archived_includes ADD COLUMN errno INTEGER;
+ALTER TABLE archived_includes ADD COLUMN error_message TEXT;
+-- Older versions didn't record what went wrong
+UPDATE includes SET error_message = 'unknown error' WHERE error = 0;
+UPDATE archived_includes SET error_message = 'unknown error' WHERE error = 0;
@@ -0,0 +1,93 @@
+-- Store the paths as raw bytes since they don't need to be valid UTF-8.
+-- STRICT tables can't change the type of a column, so the tables are rebuilt.
+-- Foreign keys can't be disabled within the transaction of the migration,
+-- so the includes are moved out of the way while the zones are rebuilt.
+CREATE TEMPORARY TABLE old_includes AS SELECT * FROM includes;
+DROP TABLE includes;
+CREATE TEMPORARY TABLE old_archived_includes AS SELECT * FROM archived_includes;
+DROP TABLE archived_includes;
+
+CREATE TABLE new_zones (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL UNIQUE,
+	dir BLOB NOT NULL,
+	ttl TEXT NOT NULL,
+	soa_ttl TEXT NOT NULL,
+	soa_mname TEXT NOT NULL,
+	soa_rname TEXT NOT NULL,
+	soa_serial INTEGER NOT NULL,
+	soa_refresh TEXT NOT NULL,
+	soa_retry TEXT NOT NULL,
+	soa_expire TEXT NOT NULL,
+	soa_minimum TEXT NOT NULL,
+	last_reloaded_serial INTEGER
+) STRICT;
+INSERT INTO new_zones
+SELECT id, name, CAST(dir AS BLOB), ttl, soa_ttl, soa_mname, soa_rname, soa_serial,
+	soa_refresh, soa_retry, soa_expire, soa_minimum, last_reloaded_serial
+FROM zones;
+DROP TABLE zones;
+ALTER TABLE new_zones RENAME TO zones;
+CREATE INDEX zones_index ON zones(name);
+
+CREATE TABLE includes (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	zoneid INTEGER NOT NULL,
+	path BLOB NOT NULL,
+	hash BLOB,
+	error INTEGER,
+	dev INTEGER,
+	ino INTEGER,
+	size INTEGER,
+	mtime_ns INTEGER,
+	ctime_ns INTEGER,
+	errno INTEGER,
+	error_message TEXT,
+	FOREIGN KEY(zoneid) REFERENCES zones(id),
+	UNIQUE (zoneid, path),
+	CHECK ((hash IS NULL) <> (error IS NULL))
+) STRICT;
+INSERT INTO includes
+SELECT id, zoneid, CAST(path AS BLOB), hash, error, dev, ino, size, mtime_ns, ctime_ns,
+	errno, error_message
+FROM old_includes;
+DROP TABLE old_includes;
+CREATE INDEX includes_index ON includes(zoneid, path);
+
+CREATE TABLE new_archived_zones (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL,
+	archived_at INTEGER NOT NULL,
+	dir BLOB NOT NULL,
+	ttl TEXT NOT NULL,
+	soa_ttl TEXT NOT NULL,
+	soa_mname TEXT NOT NULL,
+	soa_rname TEXT NOT NULL,
+	soa_serial INTEGER NOT NULL,
+	soa_refresh TEXT NOT NULL,
+	soa_retry TEXT NOT NULL,
+	soa_expire TEXT NOT NULL,
+	soa_minimum TEXT NOT NULL,
+	last_reloaded_serial INTEGER
+) STRICT;
+INSERT INTO new_archived_zones
+SELECT id, name, archived_at, CAST(dir AS BLOB), ttl, soa_ttl, soa_mname, soa_rname, soa_serial,
+	soa_refresh, soa_retry, soa_expire, soa_minimum, last_reloaded_serial
+FROM archived_zones;
+DROP TABLE archived_zones;
+ALTER TABLE new_archived_zones RENAME TO archived_zones;
+
+CREATE TABLE archived_includes (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	zoneid INTEGER NOT NULL,
+	path BLOB NOT NULL,
+	hash BLOB,
+	error INTEGER,
+	errno INTEGER,
+	error_message TEXT,
+	FOREIGN KEY(zoneid) REFERENCES archived_zones(id)
+) STRICT;
+INSERT INTO archived_includes
+SELECT id, zoneid, CAST(path AS BLOB), hash, error, errno, error_message
+FROM old_archived_includes;
+DROP TABLE old_archived_includes;
@@ -17,10 +17,88 @@ let
         '';
       };
       reload_program_args = lib.mkOption {
-        type = lib.types.listOf lib.types.str;
+        type = lib.types.nullOr (lib.types.listOf lib.types.str);
+        default = null;
         example = [ "reload" "example.org" ];
         description = ''
           Command line arguments to be passed to the reload command.
+          The placeholders `{zone}`, `{serial}`, `{old_serial}` and `{zone_file}` are replaced with the respective values.
+          The program also receives the environment variables `ZONEWATCH_ZONE`, `ZONEWATCH_SERIAL`, `ZONEWATCH_OLD_SERIAL`, `ZONEWATCH_ZONE_FILE` and `ZONEWATCH_CHANGED_INCLUDES`.
+          The latter contains one line per changed include with its new state (`readable`, `not_found`, `permission_denied`, `is_a_directory`, `too_many_symlinks`, `invalid_data`, `timeout`, `error` or `removed`) followed by its path.
+          Exactly one of this and `reload` must be set.
+        '';
+      };
+      reload_program_bin = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        description = ''
+          Overrides the global `reload_program_bin` for this zone.
+          Only allowed together with `reload_program_args`.
+        '';
+      };
+      reload = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule reloadOpts);
+        default = null;
+        example = {
+          rndc = {
+            address = "127.0.0.1:953";
+            key_file = "/etc/bind/rndc.key";
+          };
+        };
+        description = ''
+          Reload the zone without calling an external program.
+          Exactly one of this and `reload_program_args` must be set.
+        '';
+      };
+      reload_timeout = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "30s";
+        description = ''
+          How long a single attempt to reload the zone may take before it is aborted. Defaults to one minute.
+        '';
+      };
+      reload_retry = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule retryOpts);
+        default = null;
+        description = ''
+          How often and how quickly to retry reloading the zone when it fails.
+          Once all attempts failed, the reload is retried every `max_delay`, also after a restart.
+        '';
+      };
+      hooks = lib.mkOption {
+        type = lib.types.submodule hooksOpts;
+        default = {};
+        description = ''
+          Programs which run at certain points while publishing the zone.
+          They get the same placeholders and environment variables as the reload program.
+          Additionally `ZONEWATCH_HOOK` contains the name of the hook and `ZONEWATCH_ERROR` the error for `on_failure` hooks.
+        '';
+      };
+      stale_file = lib.mkOption {
+        type = lib.types.enum [ "keep" "remove" "symlink" ];
+        default = "keep";
+        description = ''
+          What to do with the previously generated zone file when `dir` changes.
+          `keep` leaves it behind, `remove` deletes it and `symlink` replaces it with a symlink to the new zone file.
+        '';
+      };
+      always_hash = lib.mkOption {
+        type = lib.types.bool;
+        default = false;
+        description = ''
+          Hash the included files every time they might have changed.
+          By default a file is only hashed again if its device, inode, size, modification time or change time differ from when it was last hashed.
+          Files which were modified less than 2 seconds before they were hashed are always hashed again, since they may have changed again without changing these.
+        '';
+      };
+      hash_timeout = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "1m";
+        description = ''
+          How long hashing a single included file may take before it is commented out of the zone file. Defaults to 30 seconds.
+          The file is not read again until the read which timed out has finished.
         '';
       };
       ttl = lib.mkOption {
@@ -28,6 +106,8 @@ let
         default = "1d";
         description = ''
           The default TTL for the generated zone file.
+          Like all durations of the zone, it is either a number of seconds or uses units like `1d` or `2h30m`.
+          It is written to the zone file as a number of seconds.
         '';
       };
       includes = lib.mkOption {
@@ -35,7 +115,7 @@ let
         example = [ "/path/to/file 1.zone" "/path/to/file 2.zone" ];
         description = ''
           Absolute paths or paths relative to the corresponding zone file.
-          These are included in the generated zone file with the $INCLUDE directive.
+          These are included in the generated zone file with the $INCLUDE directive, so they must not contain control characters.
         '';
       };
       soa = lib.mkOption {
@@ -61,6 +141,269 @@ let
     };
   };
 
+  retryOpts = { lib, name, ... }: {
+    options = {
+      attempts = lib.mkOption {
+        type = lib.types.nullOr lib.types.ints.positive;
+        default = null;
+        example = 5;
+        description = ''
+          How often to try reloading the zone before giving up. Defaults to 3.
+        '';
+      };
+      initial_delay = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "5s";
+        description = ''
+          How long to wait after the first failed attempt. The delay doubles after every further failure. Defaults to one second.
+        '';
+      };
+      max_delay = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "10m";
+        description = ''
+          The upper limit for the delay between attempts. Defaults to one minute.
+        '';
+      };
+    };
+  };
+
+  hooksOpts = { lib, name, ... }: {
+    options = {
+      pre_publish = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule hookOpts);
+        default = [ ];
+        description = ''
+          Run before the new zone file is written. An aborting hook skips publishing this change.
+        '';
+      };
+      post_commit = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule hookOpts);
+        default = [ ];
+        description = ''
+          Run after the new zone file was written. An aborting hook skips reloading the zone.
+        '';
+      };
+      post_reload = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule hookOpts);
+        default = [ ];
+        description = ''
+          Run after the zone was reloaded successfully. An aborting hook causes the reload to be retried later.
+        '';
+      };
+      on_failure = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule hookOpts);
+        default = [ ];
+        description = ''
+          Run when publishing or reloading the zone failed. These hooks cannot abort.
+        '';
+      };
+    };
+  };
+
+  hookOpts = { lib, name, ... }: {
+    options = {
+      program = lib.mkOption {
+        type = lib.types.path;
+        description = ''
+          The program to run.
+        '';
+      };
+      args = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "{zone}" "{serial}" ];
+        description = ''
+          Command line arguments, supporting the same placeholders as `reload_program_args`.
+        '';
+      };
+      failure_policy = lib.mkOption {
+        type = lib.types.enum [ "ignore" "warn" "abort" ];
+        default = "warn";
+        description = ''
+          What to do when the hook fails.
+        '';
+      };
+      timeout = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = ''
+          How long the hook may take before it is aborted. Defaults to one minute.
+        '';
+      };
+    };
+  };
+
+  webhookOpts = { lib, name, ... }: {
+    options = {
+      url = lib.mkOption {
+        type = lib.types.str;
+        example = "https://chat.example.org/hooks/zonewatch";
+        description = ''
+          The URL to POST the notifications to.
+        '';
+      };
+      secret_file = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "/run/secrets/zonewatch-webhook";
+        description = ''
+          Absolute path to a file containing a secret.
+          If set, the header `X-Zonewatch-Signature` contains `sha256=` followed by the hex encoded HMAC-SHA256 of the body.
+        '';
+      };
+      events = lib.mkOption {
+        type = lib.types.nullOr (lib.types.listOf (lib.types.enum [ "serial_change" "include_state_change" "reload_failure" ]));
+        default = null;
+        description = ''
+          Which events to send. Defaults to all of them.
+        '';
+      };
+      timeout = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = ''
+          How long a single delivery attempt may take. Defaults to one minute.
+        '';
+      };
+      retry = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule retryOpts);
+        default = null;
+        description = ''
+          How often and how quickly to retry delivering a notification.
+        '';
+      };
+    };
+  };
+
+  reloadBatchOpts = { lib, name, ... }: {
+    options = {
+      window = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "2s";
+        description = ''
+          How long to collect zones before reloading them. Defaults to one second.
+        '';
+      };
+      program_bin = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        description = ''
+          The program to run for the whole batch. Defaults to `reload_program_bin`.
+        '';
+      };
+      program_args = lib.mkOption {
+        type = lib.types.nullOr (lib.types.listOf lib.types.str);
+        default = null;
+        example = [ "reload" ];
+        description = ''
+          Command line arguments of the batch command.
+          The names of the zones are passed in the environment variable `ZONEWATCH_ZONES`, one per line.
+          Exactly one of this and `concurrency` must be set.
+        '';
+      };
+      concurrency = lib.mkOption {
+        type = lib.types.nullOr lib.types.ints.positive;
+        default = null;
+        example = 4;
+        description = ''
+          Reload every zone on its own, at most this many at the same time.
+        '';
+      };
+      timeout = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = ''
+          How long a single attempt of the batch command may take. Defaults to one minute.
+        '';
+      };
+      retry = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule retryOpts);
+        default = null;
+        description = ''
+          How often and how quickly to retry the batch command when it fails.
+        '';
+      };
+    };
+  };
+
+  reloadOpts = { lib, name, ... }: {
+    options = {
+      rndc = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule rndcOpts);
+        default = null;
+        description = ''
+          Send the reload command directly to the control channel of BIND, like `rndc` would.
+        '';
+      };
+      powerdns = lib.mkOption {
+        type = lib.types.nullOr (lib.types.submodule powerdnsOpts);
+        default = null;
+        description = ''
+          Publish the records of the included files and the SOA record through the HTTP API of PowerDNS Authoritative.
+          Only one of `rndc` and `powerdns` may be set.
+        '';
+      };
+    };
+  };
+
+  powerdnsOpts = { lib, name, ... }: {
+    options = {
+      url = lib.mkOption {
+        type = lib.types.str;
+        default = "http://127.0.0.1:8081";
+        description = ''
+          Base URL of the PowerDNS HTTP API.
+        '';
+      };
+      server_id = lib.mkOption {
+        type = lib.types.str;
+        default = "localhost";
+        description = ''
+          ID of the server in the PowerDNS API.
+        '';
+      };
+      api_key_file = lib.mkOption {
+        type = lib.types.str;
+        example = "/run/secrets/powerdns-api-key";
+        description = ''
+          Absolute path to a file containing the API key.
+        '';
+      };
+    };
+  };
+
+  rndcOpts = { lib, name, ... }: {
+    options = {
+      address = lib.mkOption {
+        type = lib.types.str;
+        default = "127.0.0.1:953";
+        example = "/run/named/control.sock";
+        description = ''
+          IP address or host name of the control channel with an optional port (default 953) or the absolute path to a Unix socket.
+        '';
+      };
+      key_file = lib.mkOption {
+        type = lib.types.str;
+        default = "/etc/bind/rndc.key";
+        description = ''
+          Absolute path to a file in the format of `rndc.key` or `rndc.conf` containing the key shared with the DNS server.
+        '';
+      };
+      key_name = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "rndc-key";
+        description = ''
+          Name of the key to use if the key file contains more than one key. By default the first key is used.
+        '';
+      };
+    };
+  };
+
   soaOpts = { lib, name, ... }: {
     options = {
       ttl = lib.mkOption {
@@ -82,7 +425,9 @@ let
         example = "john\\.doe.example.org.";
         description = ''
           Email address of the administrator responsible for this zone.
-          Not that there is no @ symbol. Instead a different syntax is used.
+          Either a regular address like `john.doe@example.org` or the form used in the zone file,
+          where the @ symbol is replaced by a dot and dots in the local part are escaped.
+          In that form, the characters `;`, `(`, `)`, `"`, `$` and whitespace must be escaped as well and an escaped `\@` is part of the local part.
         '';
       };
       initial_serial = lib.mkOption {
@@ -107,7 +452,7 @@ let
         default = "2h";
         description = ''
           Number of seconds after which secondary name servers should retry to request the serial number from the master if the master does not respond.
-          It must be less than refresh.
+          It should be less than refresh, otherwise a warning is logged.
         '';
       };
       expire = lib.mkOption {
@@ -115,7 +460,7 @@ let
         default = "1000h";
         description = ''
           Number of seconds after which secondary name servers should stop answering requests for this zone if the master does not respond.
-          This value must be bigger than the sum of refresh and retry.
+          This value should be bigger than the sum of refresh and retry, otherwise a warning is logged.
         '';
       };
       minimum = lib.mkOption {
@@ -137,27 +482,79 @@ in
 
       settings = {
         db = lib.mkOption {
-          type = lib.types.str;
+          type = lib.types.nullOr lib.types.str;
           default = "/var/lib/zonewatch/db.sqlite";
           example = "/some/other/dir/db.sqlite";
           description = ''
             Where the SQLite database should be stored.
+            Set to `null` to run without any state besides the zone files.
+            The state of the included files is then written into the zone files in comments and read back from them after a restart.
           '';
         };
         reload_program_bin = lib.mkOption {
-          type = lib.types.path;
+          type = lib.types.nullOr lib.types.path;
           default = lib.getExe' pkgs.coreutils "false";
           example = lib.literalExpression ''lib.getExe' pkgs.dig.out "rndc"'';
           description = ''
             Path to a program which is used to tell the DNS server to reload the zone file.
             This program is called after every time the zone file is rewritten.
+            It is only used for zones which set `reload_program_args`.
+          '';
+        };
+        reload_batch = lib.mkOption {
+          type = lib.types.nullOr (lib.types.submodule reloadBatchOpts);
+          default = null;
+          example = {
+            window = "2s";
+            program_args = [ "reload" ];
+          };
+          description = ''
+            Collect the zones which need reloading within a short window and reload them together.
+            Either run a single command for all zones reloaded using a program (`program_args`)
+            or reload every zone on its own with bounded concurrency (`concurrency`).
+          '';
+        };
+
+        include_dir = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          example = "/etc/zonewatch/conf.d";
+          description = ''
+            Directory with more config files defining `zones` and `profiles`.
+            Its `*.toml` files are merged in the order of their names. A zone must only be defined once across all files.
+          '';
+        };
+
+        orphan_policy = lib.mkOption {
+          type = lib.types.enum [ "keep" "archive" "delete" ];
+          default = "keep";
+          description = ''
+            What to do at startup with zones in the database which are no longer configured.
+            `keep` leaves them alone, so a zone continues with its old serial when it is added again.
+            `archive` moves them into the archive tables of the database and renames their zone file to `<file>.archived`.
+            `delete` removes them from the database and deletes their zone file.
+            Orphaned zones can also be listed and removed manually with `zonewatch orphans list` and `zonewatch orphans purge`.
+          '';
+        };
+
+        webhooks = lib.mkOption {
+          type = lib.types.listOf (lib.types.submodule webhookOpts);
+          default = [ ];
+          description = ''
+            URLs to which a JSON payload is POSTed when the serial of a zone changes,
+            the state of an included file changes or reloading a zone fails.
           '';
         };
 
         zones = lib.mkOption {
           type = lib.types.attrsOf (lib.types.submodule zoneOpts);
           default = {};
-          description = "Attribute set of zones.";
+          description = ''
+            Attribute set of zones.
+            The names are case-insensitive and may end in a dot. Internationalized names are converted to punycode.
+            Zones stored in the database under a name which was written differently, e.g. in upper case, are renamed at startup.
+            The `/` in the names of classless reverse zones like `0/25.2.0.192.in-addr.arpa` is written as `%2F` in the name of the zone file.
+          '';
           example = {
             "example.org" = {
               path = "/var/lib/zonewatch/example.org.zone";
@@ -188,7 +585,8 @@ in
       wantedBy = [ "multi-user.target" ];
       confinement.enable = true;
       serviceConfig = let
-        settingsFile = settingsFormat.generate "zonewatch.toml" cfg.settings;
+        # TOML has no null value, so leave out options which are not set
+        settingsFile = settingsFormat.generate "zonewatch.toml" (lib.filterAttrsRecursive (_: v: v != null) cfg.settings);
       in {
         ExecStart = [ "" "${lib.getExe pkgs.zonewatch} --config '${settingsFile}'" ];
       };
@@ -46,21 +46,33 @@ let
 
   config-file = (formats.toml { }).generate "config.toml" config;
 
+  # zonewatch writes durations like `1d` or `2h30m` as a number of seconds
+  to-seconds = duration: let
+    factors = { w = 604800; d = 86400; h = 3600; m = 60; s = 1; };
+    parts = builtins.filter builtins.isList (builtins.split "([0-9]+)([wdhmsWDHMS])" duration);
+    part-seconds = part: lib.toInt (builtins.elemAt part 0) * factors.${lib.toLower (builtins.elemAt part 1)};
+  in if builtins.match "[0-9]+" duration != null
+    then lib.toInt duration
+    else lib.foldl' (total: part: total + part-seconds part) 0 parts;
+
   generate-zone-string = zone-name: zone: serial: let
-    includes = lib.concatMapStrings (include: "$INCLUDE ${include}\n") zone.includes;
+    # Backslashes and quotes in the path are escaped with a backslash
+    quote = path: "\"${lib.replaceStrings [ "\\" "\"" ] [ "\\\\" "\\\"" ] path}\"";
+    includes = lib.concatMapStrings (include: "$INCLUDE ${quote include}\n") zone.includes;
     pad = string: lib.fixedWidthString 10 " " string;
+    seconds = duration: toString (to-seconds duration);
   in ''
     ; This file was automatically generated by zonewatch.
     ; Do not edit or your changes will be overwritten!
 
     $ORIGIN ${zone-name}.
-    $TTL ${zone.ttl}
-    @ ${zone.soa.ttl} IN SOA ${zone.soa.mname} ${zone.soa.rname} (
+    $TTL ${seconds zone.ttl}
+    @ ${seconds zone.soa.ttl} IN SOA ${zone.soa.mname} ${zone.soa.rname} (
     ${pad (toString serial)} ; serial
-    ${pad zone.soa.refresh} ; refresh
-    ${pad zone.soa.retry} ; retry
-    ${pad zone.soa.expire} ; expire
-    ${pad zone.soa.minimum} ; negative
+    ${pad (seconds zone.soa.refresh)} ; refresh
+    ${pad (seconds zone.soa.retry)} ; retry
+    ${pad (seconds zone.soa.expire)} ; expire
+    ${pad (seconds zone.soa.minimum)} ; negative
     )
 
   '' + includes;
@@ -1,7 +1,7 @@
 # SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
 # SPDX-License-Identifier: GPL-3.0-only
 
-# Test that a failing reload command will cause zonewatch to exit with a non-zero exit code.
+# Test that a failing reload command will cause zonewatch to exit with a non-zero exit code when using --only-init.
 # Also check that the serial number is never decremented, even if the reload command fails.
 
 {
@@ -18,6 +18,7 @@ let
     reload_program_bin = "false";
     zones."example.org" = {
       reload_program_args = [ ];
+      reload_retry.attempts = 1;
       soa.expire = "1001h";
     };
   };
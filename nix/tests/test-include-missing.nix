@@ -29,7 +29,7 @@ let
   expected-zone-include-missing = let
     zone-string-no-includes = base.generate-zone-string "example.org" config-no-includes.zones."example.org" 1;
     zone-string = zone-string-no-includes + ''
-      ; $INCLUDE /path/does/not/exist ; Commented out because the file was not found
+      ; $INCLUDE "/path/does/not/exist" ; Commented out because the file was not found
     '';
   in writeText "expected.zone" zone-string;
 in
@@ -29,7 +29,7 @@ let
   expected-zone-include-no-permission = let
     zone-string-no-includes = base.generate-zone-string "example.org" config-no-includes.zones."example.org" 1;
     zone-string = zone-string-no-includes + ''
-      ; $INCLUDE /tmp/no-permission ; Commented out because we didn't have permission to read the file
+      ; $INCLUDE "/tmp/no-permission" ; Commented out because we didn't have permission to read the file
     '';
   in writeText "expected.zone" zone-string;
 in
@@ -0,0 +1,100 @@
+# SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+# SPDX-License-Identifier: GPL-3.0-only
+
+# Test that BIND loads includes whose path contains characters with a special meaning in zone files
+
+{
+  lib,
+  callPackage,
+  formats,
+  runCommand,
+  bind,
+  zonewatch-minimal,
+}:
+let
+  base = callPackage ./base.nix { };
+
+  special-dir = "/tmp/dir with space; and \"quotes\"";
+
+  config = lib.recursiveUpdate base.config {
+    zones."example.org".includes = [
+      "${special-dir}/ns-record.zone"
+      "${special-dir}/ns-ip.zone"
+    ];
+  };
+
+  config-file = (formats.toml { }).generate "config-special-characters.toml" config;
+
+  expected-zone = base.generate-zone "example.org" config.zones."example.org" 1;
+in
+  runCommand "zonewatch-test-special-characters-with-bind" { } ''
+    mkdir --verbose db
+    mkdir --verbose ${lib.escapeShellArg special-dir}
+    cp --verbose --no-preserve=mode '${base.ns-record}' ${lib.escapeShellArg "${special-dir}/ns-record.zone"}
+    cp --verbose --no-preserve=mode '${base.ns-ip}' ${lib.escapeShellArg "${special-dir}/ns-ip.zone"}
+
+    export RUST_LOG=zonewatch=trace
+    '${lib.getExe zonewatch-minimal}' --only-init --config '${config-file}'
+
+    if ! diff '${expected-zone}' 'zones/example.org.zone'; then
+      echo 'The zone file is different from what was expected!'
+      exit 1
+    fi
+    echo 'The zone file is exactly what we expected 🎉'
+
+    cat >named.conf <<EOF
+    options {
+      pid-file "/tmp/named.pid";
+    };
+    zone "example.org" {
+      type master;
+      file "$PWD/zones/example.org.zone";
+    };
+    EOF
+
+    stop () {
+      echo 'Stopping BIND...'
+      kill "$(<"/tmp/named.pid")"
+    }
+
+    echo 'Running BIND'
+    '${lib.getExe' bind "named"}' -g -c named.conf 2>&1 | while read -r line ; do
+      echo "$line"
+
+      if [[ "$line" == *'zone example.org/IN: loaded serial 1'* ]]; then
+        touch loaded
+      fi
+
+      if [[ "$line" == *'resolver priming query complete: failure'* ]]; then
+        echo 'BIND seems to have finished starting'
+        stop
+      fi
+
+      if [[ "$line" == *'dns_master_load:'* ]]; then
+        echo 'Error detected'
+        stop
+        exit 1
+      fi
+
+      if [[ "$line" == *'.zone:'* ]]; then
+        echo 'Warning detected?'
+        stop
+        exit 1
+      fi
+    done
+
+    if [ ! -e loaded ]; then
+      echo 'BIND did not load the zone!'
+      exit 1
+    fi
+
+    # The records from the includes are part of the zone
+    '${lib.getExe' bind "named-checkzone"}' -D -o zone.dump example.org zones/example.org.zone
+    if ! grep --quiet 'ns\.example\.org\..*A.*127\.0\.0\.1' zone.dump; then
+      echo 'The records of the includes are missing from the zone!'
+      cat zone.dump
+      exit 1
+    fi
+
+    touch "$out"
+  ''
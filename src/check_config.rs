@@ -0,0 +1,469 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Validate a config file without starting the daemon, e.g. before deploying it.
+// Unlike at startup, all problems are reported together with their location in the file
+// and the paths used by the zones are checked as well, with the permissions of the user running the check.
+
+use crate::config::{self, Config, ConvertError, Raw, Reload, Source, Sources};
+use crate::domain_name::normalize_origin;
+use color_eyre::eyre::{Report, Result, WrapErr, eyre};
+use log::{error, info, warn};
+use nix::unistd::{AccessFlags, User, access, getuid};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use toml::Spanned;
+use toml::de::{DeTable, DeValue};
+
+#[derive(Debug, PartialEq, Eq)]
+struct Problem {
+	// Keys leading to the value the problem is about, as far as they exist in the file
+	keys: Vec<String>,
+	message: String,
+}
+
+struct File<'a> {
+	path: &'a Path,
+	contents: &'a str,
+	root: Spanned<DeValue<'a>>,
+}
+
+impl<'a> File<'a> {
+	fn parse(source: &'a Source) -> Result<Self> {
+		let table = DeTable::parse(&source.contents)
+			.wrap_err_with(|| format!("Cannot parse config file `{}`", source.path.display()))?;
+		let span = table.span();
+		let root = Spanned::new(span, DeValue::Table(table.into_inner()));
+		Ok(Self {
+			path: &source.path,
+			contents: &source.contents,
+			root,
+		})
+	}
+
+	// The value of the deepest of the keys which exists in the file and how many keys were found
+	fn lookup<S: AsRef<str>>(&self, keys: &[S]) -> (&Spanned<DeValue<'a>>, usize) {
+		let mut value = &self.root;
+		let mut found = 0;
+		for key in keys {
+			let key = key.as_ref();
+			let next = match key.parse::<usize>() {
+				Ok(index) if value.get_ref().is_array() => value.get_ref().get(index),
+				_ => value.get_ref().get(key),
+			};
+			let Some(next) = next else {
+				break;
+			};
+			value = next;
+			found += 1;
+		}
+		(value, found)
+	}
+}
+
+// The config file and the files in its `include_dir`.
+// Zones and profiles are only defined in one of them, so the keys are unique across all files.
+struct Document<'a> {
+	files: Vec<File<'a>>,
+}
+
+impl<'a> Document<'a> {
+	fn parse(sources: &'a Sources) -> Result<Self> {
+		let files = sources
+			.files
+			.iter()
+			.map(File::parse)
+			.collect::<Result<_>>()?;
+		Ok(Self { files })
+	}
+
+	fn main(&self) -> &File<'a> {
+		&self.files[0]
+	}
+
+	// The file containing most of the keys, the value of the deepest of them and how many were found
+	fn lookup<S: AsRef<str>>(&self, keys: &[S]) -> (&File<'a>, &Spanned<DeValue<'a>>, usize) {
+		self.files
+			.iter()
+			.map(|file| {
+				let (value, found) = file.lookup(keys);
+				(file, value, found)
+			})
+			.rev()
+			.max_by_key(|(_, _, found)| *found)
+			.expect("there is always the main config file")
+	}
+
+	// The keys leading to a setting of a zone, which may be inherited from its profile or the defaults
+	fn zone_keys(&self, origin: &str, field: &[&str]) -> Vec<String> {
+		let zone = ["zones", origin];
+		let profile = self
+			.lookup(&["zones", origin, "profile"])
+			.1
+			.get_ref()
+			.as_str()
+			.map(|profile| vec!["profiles", profile]);
+		let candidates = [Some(zone.to_vec()), profile, Some(vec!["defaults"])];
+		candidates
+			.into_iter()
+			.flatten()
+			.map(|prefix| {
+				prefix
+					.into_iter()
+					.chain(field.iter().copied())
+					.map(ToString::to_string)
+					.collect::<Vec<String>>()
+			})
+			.find(|keys| self.lookup(keys).2 == keys.len())
+			.unwrap_or_else(|| zone.iter().map(ToString::to_string).collect())
+	}
+
+	// The position of the deepest of the keys which exists in the files
+	fn location(&self, keys: &[String]) -> String {
+		let (file, value, _) = self.lookup(keys);
+		let before = &file.contents[..value.span().start];
+		let line = before.matches('\n').count() + 1;
+		let column = before
+			.rsplit('\n')
+			.next()
+			.unwrap_or_default()
+			.chars()
+			.count() + 1;
+		format!("{}:{line}:{column}", file.path.display())
+	}
+
+	// The name of the zone as it is written in the files
+	fn written_origin(&self, origin: &str) -> String {
+		self.files
+			.iter()
+			.filter_map(|file| file.root.get_ref().get("zones"))
+			.filter_map(|zones| zones.get_ref().as_table())
+			.flat_map(|zones| zones.keys())
+			.map(|key| key.get_ref().to_string())
+			.find(|key| normalize_origin(key).is_ok_and(|normalized| normalized == origin))
+			.unwrap_or_else(|| origin.to_string())
+	}
+
+	// The index of the webhook with this URL
+	fn webhook_index(&self, url: &str) -> Option<usize> {
+		self.main()
+			.root
+			.get_ref()
+			.get("webhooks")
+			.and_then(|webhooks| webhooks.get_ref().as_array())
+			.and_then(|webhooks| {
+				webhooks.iter().position(|webhook| {
+					webhook
+						.get_ref()
+						.get("url")
+						.and_then(|value| value.get_ref().as_str())
+						== Some(url)
+				})
+			})
+	}
+}
+
+fn convert_problem(document: &Document, error: ConvertError) -> Problem {
+	let keys = match &error {
+		ConvertError::ZoneConvert { origin, source } => {
+			let field: Vec<&str> = source
+				.field()
+				.map(|field| field.split('.').collect())
+				.unwrap_or_default();
+			document.zone_keys(origin, &field)
+		}
+		ConvertError::ZoneDeserialize { origin, .. }
+		| ConvertError::InvalidOrigin { origin, .. }
+		| ConvertError::DuplicateOrigin { second: origin, .. } => {
+			vec!["zones".to_string(), origin.clone()]
+		}
+		ConvertError::InvalidProfile { origin } | ConvertError::UnknownProfile { origin, .. } => {
+			vec!["zones".to_string(), origin.clone(), "profile".to_string()]
+		}
+		ConvertError::MissingReloadProgramBin { origin } => {
+			document.zone_keys(origin, &["reload_program_args"])
+		}
+		ConvertError::ReloadBatch { .. } => vec!["reload_batch".to_string()],
+		ConvertError::Webhook { url, .. } => {
+			let mut keys = vec!["webhooks".to_string()];
+			keys.extend(document.webhook_index(url).map(|index| index.to_string()));
+			keys
+		}
+	};
+	Problem {
+		keys,
+		message: format!("{:#}", Report::new(error)),
+	}
+}
+
+fn check_readable(path: &Path) -> Result<()> {
+	fs::File::open(path).wrap_err_with(|| format!("Cannot read `{}`", path.display()))?;
+	Ok(())
+}
+
+// The zone file is written to the directory, which is created if it does not exist yet
+fn check_writable(dir: &Path) -> Result<()> {
+	let existing = dir
+		.ancestors()
+		.find(|ancestor| ancestor.exists())
+		.ok_or_else(|| {
+			eyre!(
+				"None of the parent directories of `{}` exist",
+				dir.display()
+			)
+		})?;
+	if !existing.is_dir() {
+		return Err(eyre!("`{}` is not a directory", existing.display()));
+	}
+	// Like access(2), this does not create anything
+	access(existing, AccessFlags::W_OK | AccessFlags::X_OK)
+		.map_err(std::io::Error::from)
+		.wrap_err_with(|| {
+			format!(
+				"User {} cannot create files in directory `{}`",
+				current_user(),
+				existing.display()
+			)
+		})
+}
+
+// The user whose permissions access(2) checks
+fn current_user() -> String {
+	let uid = getuid();
+	match User::from_uid(uid) {
+		Ok(Some(user)) => format!("`{}` (uid {uid})", user.name),
+		_ => format!("with uid {uid}"),
+	}
+}
+
+fn is_executable(path: &Path) -> bool {
+	fs::metadata(path)
+		.is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Like the shell, programs without a slash in their name are looked up in PATH
+fn check_executable(program: &Path) -> Result<()> {
+	if program.components().count() > 1 {
+		if is_executable(program) {
+			return Ok(());
+		}
+		return Err(eyre!("`{}` is not an executable file", program.display()));
+	}
+	let path = std::env::var_os("PATH").unwrap_or_default();
+	if std::env::split_paths(&path).any(|dir| is_executable(&dir.join(program))) {
+		return Ok(());
+	}
+	Err(eyre!("`{}` cannot be found in PATH", program.display()))
+}
+
+fn push_error(problems: &mut Vec<Problem>, keys: Vec<String>, result: Result<()>) {
+	if let Err(e) = result {
+		problems.push(Problem {
+			keys,
+			message: format!("{e:#}"),
+		});
+	}
+}
+
+fn check_zone(
+	document: &Document,
+	config: &Config,
+	written: &str,
+	zone: &config::Zone,
+	errors: &mut Vec<Problem>,
+	warnings: &mut Vec<Problem>,
+) {
+	for (index, include) in zone.includes.iter().enumerate() {
+		let index = index.to_string();
+		push_error(
+			errors,
+			document.zone_keys(written, &["includes", &index]),
+			check_readable(include),
+		);
+	}
+	push_error(
+		errors,
+		document.zone_keys(written, &["dir"]),
+		check_writable(&zone.dir),
+	);
+
+	if let Reload::Program { bin, .. } = &zone.reload {
+		let keys = if bin.is_some() {
+			document.zone_keys(written, &["reload_program_bin"])
+		} else {
+			vec!["reload_program_bin".to_string()]
+		};
+		if let Some(bin) = bin.as_ref().or(config.reload_program_bin.as_ref()) {
+			push_error(errors, keys, check_executable(bin));
+		}
+	}
+
+	let hooks = [
+		("pre_publish", &zone.hooks.pre_publish),
+		("post_commit", &zone.hooks.post_commit),
+		("post_reload", &zone.hooks.post_reload),
+		("on_failure", &zone.hooks.on_failure),
+	];
+	for (event, hooks) in hooks {
+		for (index, hook) in hooks.iter().enumerate() {
+			let index = index.to_string();
+			push_error(
+				errors,
+				document.zone_keys(written, &["hooks", event, &index, "program"]),
+				check_executable(&hook.program),
+			);
+		}
+	}
+
+	for warning in zone.soa.warnings() {
+		warnings.push(Problem {
+			keys: document.zone_keys(written, &["soa"]),
+			message: warning,
+		});
+	}
+}
+
+// Returns the errors and the warnings
+fn check(document: &Document, raw_config: Raw) -> (Vec<Problem>, Vec<Problem>) {
+	let (config, convert_errors) = Config::convert(raw_config);
+	let mut errors: Vec<Problem> = convert_errors
+		.into_iter()
+		.map(|error| convert_problem(document, error))
+		.collect();
+	let mut warnings = Vec::new();
+
+	let mut origins: Vec<&String> = config.zones.keys().collect();
+	origins.sort_unstable();
+	for origin in origins {
+		let written = document.written_origin(origin);
+		check_zone(
+			document,
+			&config,
+			&written,
+			&config.zones[origin],
+			&mut errors,
+			&mut warnings,
+		);
+	}
+
+	if let Some(config::ReloadBatch {
+		mode: config::BatchMode::Program { bin, .. },
+		..
+	}) = &config.reload_batch
+	{
+		push_error(
+			&mut errors,
+			vec!["reload_batch".to_string(), "program_bin".to_string()],
+			check_executable(bin),
+		);
+	}
+
+	for webhook in &config.webhooks {
+		if let Some(secret_file) = &webhook.secret_file {
+			let index = document
+				.webhook_index(&webhook.url)
+				.unwrap_or_default()
+				.to_string();
+			push_error(
+				&mut errors,
+				vec!["webhooks".to_string(), index, "secret_file".to_string()],
+				check_readable(secret_file),
+			);
+		}
+	}
+
+	(errors, warnings)
+}
+
+pub fn run(config_path: &Path) -> Result<()> {
+	info!("Checking config file {}", config_path.display());
+	let (raw_config, sources) = config::read_raw(config_path)?;
+	let document = Document::parse(&sources)?;
+
+	let (errors, warnings) = check(&document, raw_config);
+	for warning in &warnings {
+		warn!("{}: {}", document.location(&warning.keys), warning.message);
+	}
+	for error in &errors {
+		error!("{}: {}", document.location(&error.keys), error.message);
+	}
+
+	if !errors.is_empty() {
+		return Err(eyre!(
+			"Found {} problem(s) in config file `{}`",
+			errors.len(),
+			config_path.display()
+		));
+	}
+	info!(
+		"The config file {} is valid ({} warning(s))",
+		config_path.display(),
+		warnings.len()
+	);
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use crate::check_config::{Document, check};
+	use crate::config::read_raw;
+	use crate::test_util::TempDir;
+	use indoc::formatdoc;
+
+	#[test]
+	fn check_problem_locations() {
+		let temp_dir = TempDir::new("check-config");
+		let dir = temp_dir.path();
+		std::fs::create_dir(dir.join("conf.d")).expect("can create dir");
+		let soa = r#"{ mname = "ns1.example.org.", rname = "john.doe@example.org", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2d", expire = "1000h", minimum = "1h" }"#;
+		let config = formatdoc! {r#"
+			db = "/var/lib/zonewatch/db.sqlite"
+			reload_program_bin = "sh"
+			include_dir = "conf.d"
+
+			[zones."example.org"]
+			dir = "{dir}"
+			reload_program_args = []
+			ttl = "1d"
+			includes = ["{dir}/missing.zone"]
+			soa = {soa}
+			"#,
+			dir = dir.display()
+		};
+		let drop_in = formatdoc! {r#"
+			[zones."example.com"]
+			dir = "{dir}"
+			reload_program_args = []
+			ttl = "1x"
+			includes = []
+			soa = {soa}
+			"#,
+			dir = dir.display()
+		};
+		std::fs::write(dir.join("config.toml"), config).expect("can write");
+		std::fs::write(dir.join("conf.d/com.toml"), drop_in).expect("can write");
+
+		let (raw_config, sources) =
+			read_raw(&dir.join("config.toml")).expect("the files are valid");
+		let document = Document::parse(&sources).expect("the TOML is valid");
+		let (errors, warnings) = check(&document, raw_config);
+		let locations: Vec<String> = errors
+			.iter()
+			.map(|error| document.location(&error.keys))
+			.collect();
+		// The problems are reported in the file which defines the zone
+		assert_eq!(
+			locations,
+			vec![
+				format!("{}/conf.d/com.toml:4:7", dir.display()),
+				format!("{}/config.toml:9:13", dir.display()),
+			]
+		);
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(
+			document.location(&warnings[0].keys),
+			format!("{}/config.toml:10:7", dir.display())
+		);
+	}
+}
@@ -1,12 +1,15 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
 // SPDX-License-Identifier: GPL-3.0-only
 
-use color_eyre::eyre::{Result, WrapErr};
-use log::info;
-use serde_derive::Deserialize;
+use crate::domain_name::{NameError, RnameError, normalize_origin, normalize_rname};
+use crate::master_file::parse_ttl;
+use color_eyre::eyre::{Report, Result, WrapErr, eyre};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn default_nix_dir() -> PathBuf {
 	Path::new("/nix").to_path_buf()
@@ -14,22 +17,191 @@ fn default_nix_dir() -> PathBuf {
 
 #[derive(Debug, Deserialize)]
 pub struct Raw {
-	pub db: PathBuf,
+	// Without a database the state of each zone is recovered from its zone file
+	pub db: Option<PathBuf>,
 	#[serde(default = "default_nix_dir")]
 	pub nix_dir: PathBuf,
-	pub reload_program_bin: PathBuf,
-	pub zones: HashMap<String, ZoneRaw>,
+	pub reload_program_bin: Option<PathBuf>,
+	pub reload_batch: Option<ReloadBatchRaw>,
+	#[serde(default)]
+	pub webhooks: Vec<WebhookRaw>,
+	// Directory with more config files defining zones and profiles, relative to the config file
+	pub include_dir: Option<PathBuf>,
+	#[serde(default)]
+	pub orphan_policy: OrphanPolicy,
+	// Settings of zones which every zone inherits unless it overrides them
+	#[serde(default)]
+	pub defaults: toml::Table,
+	// Named sets of settings on top of the defaults, selected by a zone with `profile = "name"`
+	#[serde(default)]
+	pub profiles: HashMap<String, toml::Table>,
+	// Merged with the defaults and the profile before being deserialized into a `ZoneRaw`
+	pub zones: HashMap<String, toml::Table>,
+}
+
+// What to do at startup with zones in the database which are no longer in the config
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanPolicy {
+	// Leave them alone, the zone resumes with its old serial when it is added again
+	#[default]
+	Keep,
+	// Move them into the archive tables and rename their zone file to `<file>.archived`
+	Archive,
+	// Remove them from the database and delete their zone file
+	Delete,
+}
+
+// A file in the `include_dir`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DropInRaw {
+	#[serde(default)]
+	profiles: HashMap<String, toml::Table>,
+	#[serde(default)]
+	zones: HashMap<String, toml::Table>,
+}
+
+pub struct Source {
+	pub path: PathBuf,
+	pub contents: String,
+}
+
+// The config file and the files in its `include_dir`, in the order they were merged
+pub struct Sources {
+	pub files: Vec<Source>,
+	// Index of the file defining each zone, by the name as it is written
+	zones: HashMap<String, usize>,
+}
+
+impl Sources {
+	// The file which caused the error
+	pub fn file_of(&self, error: &ConvertError) -> &Path {
+		let index = error
+			.origin()
+			.and_then(|origin| self.zones.get(origin))
+			.copied()
+			.unwrap_or_default();
+		&self.files[index].path
+	}
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct WebhookRaw {
+	pub url: String,
+	// File containing the secret used to sign the payload
+	pub secret_file: Option<PathBuf>,
+	// Which events to send, all by default
+	pub events: Option<Vec<WebhookEvent>>,
+	pub timeout: Option<String>,
+	pub retry: Option<RetryRaw>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+	SerialChange,
+	IncludeStateChange,
+	ReloadFailure,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct ReloadBatchRaw {
+	// How long to collect zones which need reloading before reloading them together
+	pub window: Option<String>,
+	// Run this command once for all collected zones, overriding the global `reload_program_bin`
+	pub program_bin: Option<PathBuf>,
+	pub program_args: Option<Vec<String>>,
+	// Or reload the collected zones individually, at most this many at the same time
+	pub concurrency: Option<usize>,
+	// Timeout and retries of the batch command
+	pub timeout: Option<String>,
+	pub retry: Option<RetryRaw>,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct ZoneRaw {
 	pub dir: PathBuf,
-	pub reload_program_args: Vec<String>,
+	// Overrides the global `reload_program_bin` for this zone
+	pub reload_program_bin: Option<PathBuf>,
+	pub reload_program_args: Option<Vec<String>>,
+	pub reload: Option<ReloadRaw>,
+	// How long a single reload may take before it's aborted
+	pub reload_timeout: Option<String>,
+	pub reload_retry: Option<RetryRaw>,
+	#[serde(default)]
+	pub hooks: HooksRaw,
+	// What to do with the old zone file when `dir` changes
+	#[serde(default)]
+	pub stale_file: StaleFilePolicy,
+	// Hash included files even if their metadata shows that they didn't change
+	#[serde(default)]
+	pub always_hash: bool,
+	// How long hashing a single included file may take
+	pub hash_timeout: Option<String>,
 	pub ttl: String,
 	pub includes: Vec<PathBuf>,
 	pub soa: Soa,
 }
 
+// Programs which run at certain points while publishing a zone
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct HooksRaw {
+	// Before the new zone file is written
+	#[serde(default)]
+	pub pre_publish: Vec<HookRaw>,
+	// After the new zone file was written and the database transaction was committed
+	#[serde(default)]
+	pub post_commit: Vec<HookRaw>,
+	// After the DNS server was told about the new zone file
+	#[serde(default)]
+	pub post_reload: Vec<HookRaw>,
+	// After something went wrong
+	#[serde(default)]
+	pub on_failure: Vec<HookRaw>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct HookRaw {
+	pub program: PathBuf,
+	#[serde(default)]
+	pub args: Vec<String>,
+	#[serde(default)]
+	pub failure_policy: FailurePolicy,
+	pub timeout: Option<String>,
+}
+
+// What to do with the previously generated zone file after the zone file moved
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StaleFilePolicy {
+	#[default]
+	Keep,
+	Remove,
+	// Replace it with a symlink to the new zone file
+	Symlink,
+}
+
+// What to do when a hook fails
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+	Ignore,
+	#[default]
+	Warn,
+	// Stop publishing the zone
+	Abort,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct RetryRaw {
+	// How often to try reloading before giving up
+	pub attempts: Option<u32>,
+	// Delay before the first retry, doubled after every failed attempt
+	pub initial_delay: Option<String>,
+	pub max_delay: Option<String>,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
 pub struct Soa {
 	pub ttl: String,
@@ -42,34 +214,246 @@ pub struct Soa {
 	pub minimum: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadRaw {
+	Rndc(Rndc),
+	#[serde(rename = "powerdns")]
+	PowerDns(PowerDns),
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct Rndc {
+	// IP address or host name with an optional port or the absolute path to a Unix socket
+	pub address: String,
+	// File in the format of `rndc.key` or `rndc.conf`
+	pub key_file: PathBuf,
+	// Which key to use if the file contains multiple keys
+	pub key_name: Option<String>,
+}
+
+fn default_powerdns_server_id() -> String {
+	"localhost".to_string()
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct PowerDns {
+	// Base URL of the HTTP API like `http://127.0.0.1:8081`
+	pub url: String,
+	#[serde(default = "default_powerdns_server_id")]
+	pub server_id: String,
+	pub api_key_file: PathBuf,
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
-	pub db: PathBuf,
+	pub db: Option<PathBuf>,
 	pub nix_dir: PathBuf,
-	pub reload_program_bin: PathBuf,
+	pub reload_program_bin: Option<PathBuf>,
+	pub reload_batch: Option<ReloadBatch>,
+	pub webhooks: Vec<Webhook>,
+	pub include_dir: Option<PathBuf>,
+	pub orphan_policy: OrphanPolicy,
 	pub zones: HashMap<String, Zone>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Webhook {
+	pub url: String,
+	pub secret_file: Option<PathBuf>,
+	pub events: Vec<WebhookEvent>,
+	pub timeout: Duration,
+	pub retry: RetryPolicy,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BatchMode {
+	// Only zones reloaded using a program are reloaded by this command, the others are reloaded individually
+	Program {
+		bin: PathBuf,
+		args: Vec<String>,
+		timeout: Duration,
+		retry: RetryPolicy,
+	},
+	PerZone {
+		concurrency: usize,
+	},
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReloadBatch {
+	pub window: Duration,
+	pub mode: BatchMode,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Reload {
+	// Without `bin`, the global `reload_program_bin` is used
+	Program {
+		bin: Option<PathBuf>,
+		args: Vec<String>,
+	},
+	Rndc(Rndc),
+	PowerDns(PowerDns),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RetryPolicy {
+	pub attempts: u32,
+	pub initial_delay: Duration,
+	pub max_delay: Duration,
+}
+
+const DEFAULT_RELOAD_TIMEOUT: Duration = Duration::from_mins(1);
+
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_mins(1);
+
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_mins(1);
+
+const DEFAULT_HASH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_secs(1);
+
+const DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+	attempts: 3,
+	initial_delay: Duration::from_secs(1),
+	max_delay: Duration::from_mins(1),
+};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hook {
+	pub program: PathBuf,
+	pub args: Vec<String>,
+	pub failure_policy: FailurePolicy,
+	pub timeout: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Hooks {
+	pub pre_publish: Vec<Hook>,
+	pub post_commit: Vec<Hook>,
+	pub post_reload: Vec<Hook>,
+	pub on_failure: Vec<Hook>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Zone {
 	pub dir: PathBuf,
-	pub reload_program_args: Vec<String>,
+	pub reload: Reload,
+	pub reload_timeout: Duration,
+	pub reload_retry: RetryPolicy,
+	pub hooks: Hooks,
+	pub stale_file: StaleFilePolicy,
+	pub always_hash: bool,
+	pub hash_timeout: Duration,
+	// Write the state of the includes into the zone file, which replaces the database
+	pub embed_state: bool,
 	pub ttl: String,
 	pub includes: Vec<PathBuf>,
 	pub includes_set: HashSet<PathBuf>,
 	pub soa: Soa,
 }
 
+// The `*.toml` files in the directory, sorted by their name
+fn drop_in_files(dir: &Path) -> Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	for entry in fs::read_dir(dir)
+		.wrap_err_with(|| format!("Cannot read include_dir `{}`", dir.display()))?
+	{
+		let path = entry
+			.wrap_err_with(|| format!("Cannot read include_dir `{}`", dir.display()))?
+			.path();
+		let hidden = path
+			.file_name()
+			.is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+		if !hidden
+			&& path
+				.extension()
+				.is_some_and(|extension| extension == "toml")
+		{
+			files.push(path);
+		}
+	}
+	files.sort_unstable();
+	Ok(files)
+}
+
+// Read the config file and merge the files in its `include_dir` into it
+pub fn read_raw(filename: &Path) -> Result<(Raw, Sources)> {
+	info!("Reading config file {}", filename.display());
+	let contents = fs::read_to_string(filename)
+		.wrap_err_with(|| format!("Cannot read config file `{}`", filename.display()))?;
+	let mut raw_config: Raw = toml::from_str(&contents)
+		.wrap_err_with(|| format!("Cannot parse config file `{}`", filename.display()))?;
+	let mut sources = Sources {
+		zones: raw_config
+			.zones
+			.keys()
+			.map(|name| (name.clone(), 0))
+			.collect(),
+		files: vec![Source {
+			path: filename.to_path_buf(),
+			contents,
+		}],
+	};
+
+	let Some(include_dir) = &raw_config.include_dir else {
+		return Ok((raw_config, sources));
+	};
+	let include_dir = filename
+		.parent()
+		.unwrap_or_else(|| Path::new(""))
+		.join(include_dir);
+	let mut profiles: HashMap<String, usize> = raw_config
+		.profiles
+		.keys()
+		.map(|name| (name.clone(), 0))
+		.collect();
+	for path in drop_in_files(&include_dir)? {
+		info!("Reading config file {}", path.display());
+		let contents = fs::read_to_string(&path)
+			.wrap_err_with(|| format!("Cannot read config file `{}`", path.display()))?;
+		let drop_in: DropInRaw = toml::from_str(&contents)
+			.wrap_err_with(|| format!("Cannot parse config file `{}`", path.display()))?;
+		let index = sources.files.len();
+		let defined_twice = |kind: &str, name: &str, first: usize| {
+			eyre!(
+				"The {kind} `{name}` is defined in both `{}` and `{}`",
+				sources.files[first].path.display(),
+				path.display()
+			)
+		};
+		for (name, zone) in drop_in.zones {
+			if let Some(&first) = sources.zones.get(&name) {
+				return Err(defined_twice("zone", &name, first));
+			}
+			sources.zones.insert(name.clone(), index);
+			raw_config.zones.insert(name, zone);
+		}
+		for (name, profile) in drop_in.profiles {
+			if let Some(&first) = profiles.get(&name) {
+				return Err(defined_twice("profile", &name, first));
+			}
+			profiles.insert(name.clone(), index);
+			raw_config.profiles.insert(name, profile);
+		}
+		sources.files.push(Source { path, contents });
+	}
+	raw_config.include_dir = Some(include_dir);
+	Ok((raw_config, sources))
+}
+
 impl Config {
 	pub fn read(filename: &Path) -> Result<Self> {
-		info!("Reading config file {}", filename.display());
-		let contents = fs::read_to_string(filename)
-			.wrap_err_with(|| format!("Cannot read config file `{}`", filename.display()))?;
-		Self::parse(&contents)
-			.wrap_err_with(|| format!("Cannot parse config file `{}`", filename.display()))
+		let (raw_config, sources) = read_raw(filename)?;
+		raw_config.try_into().map_err(|e: ConvertError| {
+			let file = sources.file_of(&e).display().to_string();
+			Report::new(e).wrap_err(format!("Cannot parse config file `{file}`"))
+		})
 	}
 
-	fn parse(contents: &str) -> Result<Self> {
+	#[cfg(test)]
+	pub fn parse(contents: &str) -> Result<Self> {
 		let raw_config: Raw = toml::from_str(contents)?;
 		Ok(raw_config.try_into()?)
 	}
@@ -82,25 +466,301 @@ pub enum ConvertError {
 		origin: String,
 		source: ZoneConvertError,
 	},
+
+	#[error("Cannot deserialize zone `{origin}`")]
+	ZoneDeserialize {
+		origin: String,
+		source: toml::de::Error,
+	},
+
+	#[error("The `profile` of zone `{origin}` must be a string")]
+	InvalidProfile { origin: String },
+
+	#[error("Zone `{origin}` uses the profile `{profile}`, which does not exist")]
+	UnknownProfile { origin: String, profile: String },
+
+	#[error("The name of zone `{origin}` is invalid")]
+	InvalidOrigin { origin: String, source: NameError },
+
+	#[error("The zones `{first}` and `{second}` both have the name {origin}")]
+	DuplicateOrigin {
+		origin: String,
+		first: String,
+		second: String,
+	},
+
+	#[error("Zone `{origin}` is reloaded using a program but `reload_program_bin` is not set")]
+	MissingReloadProgramBin { origin: String },
+
+	#[error("Cannot validate `reload_batch`")]
+	ReloadBatch { source: ReloadBatchConvertError },
+
+	#[error("Cannot validate webhook `{url}`")]
+	Webhook {
+		url: String,
+		source: WebhookConvertError,
+	},
 }
 
-impl TryFrom<Raw> for Config {
-	type Error = ConvertError;
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum WebhookConvertError {
+	#[error("The URL is invalid (must start with http:// or https://)")]
+	InvalidUrl,
 
-	fn try_from(raw_config: Raw) -> std::result::Result<Self, Self::Error> {
-		let zones: std::result::Result<HashMap<String, Zone>, Self::Error> = raw_config
-			.zones
-			.into_iter()
-			.map(|(origin, raw_zone)| {
-				let origin_copy = origin.clone();
-				let zone: Zone =
-					raw_zone
-						.try_into()
-						.map_err(|source| ConvertError::ZoneConvert {
-							origin: origin_copy,
+	#[error("Path `{path}` of the secret file is relative")]
+	RelativeSecretFilePath { path: String },
+
+	#[error(transparent)]
+	Invalid(#[from] ZoneConvertError),
+}
+
+impl TryFrom<WebhookRaw> for Webhook {
+	type Error = WebhookConvertError;
+
+	fn try_from(raw: WebhookRaw) -> std::result::Result<Self, Self::Error> {
+		if !raw.url.starts_with("http://") && !raw.url.starts_with("https://") {
+			return Err(WebhookConvertError::InvalidUrl);
+		}
+		if let Some(secret_file) = &raw.secret_file
+			&& secret_file.is_relative()
+		{
+			return Err(WebhookConvertError::RelativeSecretFilePath {
+				path: secret_file.display().to_string(),
+			});
+		}
+		Ok(Self {
+			url: raw.url,
+			secret_file: raw.secret_file,
+			events: raw.events.unwrap_or_else(|| {
+				vec![
+					WebhookEvent::SerialChange,
+					WebhookEvent::IncludeStateChange,
+					WebhookEvent::ReloadFailure,
+				]
+			}),
+			timeout: parse_duration("timeout", raw.timeout, DEFAULT_WEBHOOK_TIMEOUT)?,
+			retry: convert_retry("retry", raw.retry)?,
+		})
+	}
+}
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum ReloadBatchConvertError {
+	#[error("Both `program_args` and `concurrency` are set, only one is allowed")]
+	ConflictingModes,
+
+	#[error("Neither `program_args` nor `concurrency` are set")]
+	MissingMode,
+
+	#[error("`program_bin` is set but `program_args` is not")]
+	UnusedProgramBin,
+
+	#[error(
+		"`program_args` is set but neither `program_bin` nor the global `reload_program_bin` are set"
+	)]
+	MissingProgramBin,
+
+	#[error("`concurrency` must be at least 1")]
+	ZeroConcurrency,
+
+	#[error("`timeout` and `retry` are only used together with `program_args`")]
+	UnusedRetry,
+
+	#[error(transparent)]
+	Invalid(#[from] ZoneConvertError),
+}
+
+impl ReloadBatch {
+	fn try_from_raw(
+		raw: ReloadBatchRaw,
+		reload_program_bin: Option<&Path>,
+	) -> std::result::Result<Self, ReloadBatchConvertError> {
+		let window = parse_duration("reload_batch.window", raw.window, DEFAULT_BATCH_WINDOW)?;
+		if raw.program_bin.is_some() && raw.program_args.is_none() {
+			return Err(ReloadBatchConvertError::UnusedProgramBin);
+		}
+		let mode = match (raw.program_args, raw.concurrency) {
+			(Some(args), None) => {
+				let bin = raw
+					.program_bin
+					.or_else(|| reload_program_bin.map(Path::to_path_buf))
+					.ok_or(ReloadBatchConvertError::MissingProgramBin)?;
+				let timeout =
+					parse_duration("reload_batch.timeout", raw.timeout, DEFAULT_RELOAD_TIMEOUT)?;
+				BatchMode::Program {
+					bin,
+					args,
+					timeout,
+					retry: convert_retry("reload_batch.retry", raw.retry)?,
+				}
+			}
+			(None, Some(concurrency)) => {
+				if concurrency == 0 {
+					return Err(ReloadBatchConvertError::ZeroConcurrency);
+				}
+				if raw.timeout.is_some() || raw.retry.is_some() {
+					return Err(ReloadBatchConvertError::UnusedRetry);
+				}
+				BatchMode::PerZone { concurrency }
+			}
+			(Some(_), Some(_)) => return Err(ReloadBatchConvertError::ConflictingModes),
+			(None, None) => return Err(ReloadBatchConvertError::MissingMode),
+		};
+		Ok(Self { window, mode })
+	}
+}
+
+// Override the values of `base` with those of `overrides`, merging tables key by key
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+	for (key, value) in overrides {
+		match (base.get_mut(&key), value) {
+			(Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+				merge(base, overrides);
+			}
+			(_, value) => {
+				base.insert(key, value);
+			}
+		}
+	}
+}
+
+// The way a zone is reloaded is replaced as a whole instead of being merged,
+// since a zone can only be reloaded in one way
+fn override_reload_method(base: &mut toml::Table, overrides: &toml::Table) {
+	if overrides.contains_key("reload") {
+		base.remove("reload");
+		base.remove("reload_program_args");
+		base.remove("reload_program_bin");
+	}
+	if overrides.contains_key("reload_program_args") {
+		base.remove("reload");
+	}
+}
+
+// Apply the defaults and the profile of the zone
+fn inherit(
+	origin: &str,
+	mut zone: toml::Table,
+	defaults: &toml::Table,
+	profiles: &HashMap<String, toml::Table>,
+) -> std::result::Result<ZoneRaw, ConvertError> {
+	let mut effective = defaults.clone();
+	let profile = zone.remove("profile");
+	if let Some(profile) = &profile {
+		let profile = profile
+			.as_str()
+			.ok_or_else(|| ConvertError::InvalidProfile {
+				origin: origin.to_string(),
+			})?;
+		let settings = profiles
+			.get(profile)
+			.ok_or_else(|| ConvertError::UnknownProfile {
+				origin: origin.to_string(),
+				profile: profile.to_string(),
+			})?;
+		override_reload_method(&mut effective, settings);
+		merge(&mut effective, settings.clone());
+	}
+	override_reload_method(&mut effective, &zone);
+	merge(&mut effective, zone);
+
+	if !defaults.is_empty() || profile.is_some() {
+		let settings = toml::to_string(&effective).unwrap_or_default();
+		info!(
+			"Effective settings of zone `{origin}`:\n{}",
+			settings.trim_end()
+		);
+	}
+	effective
+		.try_into()
+		.map_err(|source| ConvertError::ZoneDeserialize {
+			origin: origin.to_string(),
+			source,
+		})
+}
+
+impl Config {
+	// Convert the raw config, collecting every error instead of stopping at the first one.
+	// The returned config only contains the zones without errors.
+	pub fn convert(raw_config: Raw) -> (Self, Vec<ConvertError>) {
+		let mut errors = Vec::new();
+
+		// Sorted so that the error about duplicates does not depend on the order of the HashMap
+		let mut raw_zones: Vec<(String, toml::Table)> = raw_config.zones.into_iter().collect();
+		raw_zones.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+		let mut zones = HashMap::new();
+		let mut written_as: HashMap<String, String> = HashMap::new();
+		for (written, raw_zone) in raw_zones {
+			let origin = match normalize_origin(&written) {
+				Ok(origin) => origin,
+				Err(source) => {
+					errors.push(ConvertError::InvalidOrigin {
+						origin: written,
+						source,
+					});
+					continue;
+				}
+			};
+			if origin != written {
+				info!("Using the canonical name {origin} for zone `{written}`");
+			}
+			if let Some(first) = written_as.insert(origin.clone(), written.clone()) {
+				errors.push(ConvertError::DuplicateOrigin {
+					origin,
+					first,
+					second: written,
+				});
+				continue;
+			}
+			let raw_zone = match inherit(
+				&written,
+				raw_zone,
+				&raw_config.defaults,
+				&raw_config.profiles,
+			) {
+				Ok(raw_zone) => raw_zone,
+				Err(e) => {
+					errors.push(e);
+					continue;
+				}
+			};
+			let mut zone: Zone = match raw_zone.try_into() {
+				Ok(zone) => zone,
+				Err(zone_errors) => {
+					errors.extend(zone_errors.into_iter().map(|source| {
+						ConvertError::ZoneConvert {
+							origin: written.clone(),
 							source,
-						})?;
-				Ok((origin, zone))
+						}
+					}));
+					continue;
+				}
+			};
+			if raw_config.reload_program_bin.is_none()
+				&& let Reload::Program { bin: None, .. } = zone.reload
+			{
+				errors.push(ConvertError::MissingReloadProgramBin { origin: written });
+				continue;
+			}
+			zone.embed_state = raw_config.db.is_none();
+			zones.insert(origin, zone);
+		}
+
+		let reload_batch = raw_config.reload_batch.and_then(|raw| {
+			ReloadBatch::try_from_raw(raw, raw_config.reload_program_bin.as_deref())
+				.map_err(|source| errors.push(ConvertError::ReloadBatch { source }))
+				.ok()
+		});
+
+		let webhooks = raw_config
+			.webhooks
+			.into_iter()
+			.filter_map(|raw| {
+				let url = raw.url.clone();
+				raw.try_into()
+					.map_err(|source| errors.push(ConvertError::Webhook { url, source }))
+					.ok()
 			})
 			.collect();
 
@@ -108,8 +768,29 @@ impl TryFrom<Raw> for Config {
 			db: raw_config.db,
 			nix_dir: raw_config.nix_dir,
 			reload_program_bin: raw_config.reload_program_bin,
-			zones: zones?,
+			reload_batch,
+			webhooks,
+			include_dir: raw_config.include_dir,
+			orphan_policy: raw_config.orphan_policy,
+			zones,
 		};
+		(config, errors)
+	}
+}
+
+impl TryFrom<Raw> for Config {
+	type Error = ConvertError;
+
+	fn try_from(raw_config: Raw) -> std::result::Result<Self, Self::Error> {
+		let (config, errors) = Self::convert(raw_config);
+		if let Some(error) = errors.into_iter().next() {
+			return Err(error);
+		}
+		for (origin, zone) in &config.zones {
+			for warning in zone.soa.warnings() {
+				warn!("Zone {origin}: {warning}");
+			}
+		}
 		Ok(config)
 	}
 }
@@ -122,52 +803,415 @@ pub enum ZoneConvertError {
 	#[error("Path {path} is included multiple times in this zone")]
 	DuplicateIncludePath { path: String },
 
+	#[error(
+		"Included path {path:?} contains a control character, which cannot be written into a zone file"
+	)]
+	UnrepresentableIncludePath { path: String },
+
 	#[error("MNAME `{mname}` is invalid (must end in a dot)")]
 	InvalidMname { mname: String },
 
-	#[error("RNAME `{rname}` is invalid (must end in a dot)")]
-	InvalidRname { rname: String },
+	#[error("RNAME `{rname}` is invalid")]
+	InvalidRname { rname: String, source: RnameError },
+
+	#[error("Both `reload_program_args` and `reload` are set, only one is allowed")]
+	ConflictingReloadMethods,
+
+	#[error("Neither `reload_program_args` nor `reload` are set")]
+	MissingReloadMethod,
+
+	#[error("`reload_program_bin` is set but the zone is not reloaded using a program")]
+	UnusedReloadProgramBin,
+
+	#[error("Path `{path}` of the rndc key file is relative")]
+	RelativeKeyFilePath { path: String },
+
+	#[error("Path `{path}` of the PowerDNS API key file is relative")]
+	RelativeApiKeyFilePath { path: String },
+
+	#[error("PowerDNS API URL `{url}` is invalid (must start with http:// or https://)")]
+	InvalidApiUrl { url: String },
+
+	#[error("`{field}` must be at least 1")]
+	ZeroAttempts { field: String },
+
+	#[error("`{field}` has the invalid duration `{value}`")]
+	InvalidDuration { field: String, value: String },
+
+	#[error("The hook `{program}` cannot abort since it only runs after a failure")]
+	AbortingFailureHook { program: String },
+
+	#[error("`{field}` is `{value}`, which is more than the maximum of {MAX_TTL} seconds")]
+	DurationTooLarge { field: String, value: String },
 }
 
-impl TryFrom<ZoneRaw> for Zone {
+impl ConvertError {
+	// The name of the zone the error is about, as it is written
+	pub fn origin(&self) -> Option<&str> {
+		match self {
+			Self::ZoneConvert { origin, .. }
+			| Self::ZoneDeserialize { origin, .. }
+			| Self::InvalidProfile { origin }
+			| Self::UnknownProfile { origin, .. }
+			| Self::InvalidOrigin { origin, .. }
+			| Self::DuplicateOrigin { second: origin, .. }
+			| Self::MissingReloadProgramBin { origin } => Some(origin),
+			Self::ReloadBatch { .. } | Self::Webhook { .. } => None,
+		}
+	}
+}
+
+impl ZoneConvertError {
+	// The key of the zone table the error is about, separated by dots
+	pub fn field(&self) -> Option<&str> {
+		match self {
+			Self::RelativeIncludePath { .. }
+			| Self::DuplicateIncludePath { .. }
+			| Self::UnrepresentableIncludePath { .. } => Some("includes"),
+			Self::InvalidMname { .. } => Some("soa.mname"),
+			Self::InvalidRname { .. } => Some("soa.rname"),
+			Self::ConflictingReloadMethods | Self::MissingReloadMethod => None,
+			Self::UnusedReloadProgramBin => Some("reload_program_bin"),
+			Self::RelativeKeyFilePath { .. } => Some("reload.rndc.key_file"),
+			Self::RelativeApiKeyFilePath { .. } => Some("reload.powerdns.api_key_file"),
+			Self::InvalidApiUrl { .. } => Some("reload.powerdns.url"),
+			Self::ZeroAttempts { field }
+			| Self::InvalidDuration { field, .. }
+			| Self::DurationTooLarge { field, .. } => Some(field),
+			Self::AbortingFailureHook { .. } => Some("hooks.on_failure"),
+		}
+	}
+}
+
+// RFC 2181 section 8: TTLs and the SOA timers only use the lower 31 bits
+const MAX_TTL: u32 = (1 << 31) - 1;
+
+// Parse a BIND-style duration (`1d`, `2h30m` or plain seconds) and return it as plain seconds
+fn normalize_ttl(field: &str, value: &str) -> std::result::Result<String, ZoneConvertError> {
+	let seconds = parse_ttl(value).ok_or_else(|| ZoneConvertError::InvalidDuration {
+		field: field.to_string(),
+		value: value.to_string(),
+	})?;
+	if seconds > MAX_TTL {
+		return Err(ZoneConvertError::DurationTooLarge {
+			field: field.to_string(),
+			value: value.to_string(),
+		});
+	}
+	Ok(seconds.to_string())
+}
+
+impl Soa {
+	fn normalize(self) -> std::result::Result<Self, Vec<ZoneConvertError>> {
+		let mut errors = Vec::new();
+		let mut normalize = |field, value: &str| collect(&mut errors, normalize_ttl(field, value));
+		let ttl = normalize("soa.ttl", &self.ttl);
+		let refresh = normalize("soa.refresh", &self.refresh);
+		let retry = normalize("soa.retry", &self.retry);
+		let expire = normalize("soa.expire", &self.expire);
+		let minimum = normalize("soa.minimum", &self.minimum);
+		let (Some(ttl), Some(refresh), Some(retry), Some(expire), Some(minimum)) =
+			(ttl, refresh, retry, expire, minimum)
+		else {
+			return Err(errors);
+		};
+		Ok(Self {
+			ttl,
+			refresh,
+			retry,
+			expire,
+			minimum,
+			..self
+		})
+	}
+
+	// Relations between the timers which are allowed but most likely a mistake.
+	// Expects the timers to be normalized.
+	pub fn warnings(&self) -> Vec<String> {
+		let seconds = |value: &str| parse_ttl(value).map_or(0, u64::from);
+		let (refresh, retry, expire) = (
+			seconds(&self.refresh),
+			seconds(&self.retry),
+			seconds(&self.expire),
+		);
+		let mut warnings = Vec::new();
+		if retry >= refresh {
+			warnings.push(format!(
+				"The SOA retry ({retry}s) should be less than the refresh ({refresh}s)"
+			));
+		}
+		if expire <= refresh + retry {
+			warnings.push(format!(
+				"The SOA expire ({expire}s) should be bigger than the sum of refresh and retry ({}s)",
+				refresh + retry
+			));
+		}
+		warnings
+	}
+}
+
+// Remember the error, so that all problems of a zone are reported at once
+fn collect<T>(
+	errors: &mut Vec<ZoneConvertError>,
+	result: std::result::Result<T, ZoneConvertError>,
+) -> Option<T> {
+	result.map_err(|e| errors.push(e)).ok()
+}
+
+fn parse_duration(
+	field: &str,
+	value: Option<String>,
+	default: Duration,
+) -> std::result::Result<Duration, ZoneConvertError> {
+	value.map_or(Ok(default), |value| {
+		parse_ttl(&value)
+			.map(|seconds| Duration::from_secs(seconds.into()))
+			.ok_or_else(|| ZoneConvertError::InvalidDuration {
+				field: field.to_string(),
+				value,
+			})
+	})
+}
+
+// The same retry table is used in several places, `field` is its key for error messages
+fn convert_retry(
+	field: &str,
+	raw: Option<RetryRaw>,
+) -> std::result::Result<RetryPolicy, ZoneConvertError> {
+	let Some(raw) = raw else {
+		return Ok(DEFAULT_RETRY_POLICY);
+	};
+	let attempts = raw.attempts.unwrap_or(DEFAULT_RETRY_POLICY.attempts);
+	if attempts == 0 {
+		return Err(ZoneConvertError::ZeroAttempts {
+			field: format!("{field}.attempts"),
+		});
+	}
+	Ok(RetryPolicy {
+		attempts,
+		initial_delay: parse_duration(
+			&format!("{field}.initial_delay"),
+			raw.initial_delay,
+			DEFAULT_RETRY_POLICY.initial_delay,
+		)?,
+		max_delay: parse_duration(
+			&format!("{field}.max_delay"),
+			raw.max_delay,
+			DEFAULT_RETRY_POLICY.max_delay,
+		)?,
+	})
+}
+
+fn convert_hooks(
+	event: &str,
+	raw_hooks: Vec<HookRaw>,
+) -> std::result::Result<Vec<Hook>, ZoneConvertError> {
+	raw_hooks
+		.into_iter()
+		.map(|raw| {
+			Ok(Hook {
+				timeout: parse_duration(
+					&format!("hooks.{event}.timeout"),
+					raw.timeout,
+					DEFAULT_HOOK_TIMEOUT,
+				)?,
+				program: raw.program,
+				args: raw.args,
+				failure_policy: raw.failure_policy,
+			})
+		})
+		.collect()
+}
+
+impl TryFrom<HooksRaw> for Hooks {
 	type Error = ZoneConvertError;
 
-	fn try_from(raw_zone: ZoneRaw) -> std::result::Result<Self, Self::Error> {
-		let mut includes_set: HashSet<PathBuf> = HashSet::new();
-		for include in &raw_zone.includes {
-			if include.is_relative() {
-				return Err(ZoneConvertError::RelativeIncludePath {
-					path: include.display().to_string(),
+	fn try_from(raw: HooksRaw) -> std::result::Result<Self, Self::Error> {
+		if let Some(hook) = raw
+			.on_failure
+			.iter()
+			.find(|hook| hook.failure_policy == FailurePolicy::Abort)
+		{
+			return Err(ZoneConvertError::AbortingFailureHook {
+				program: hook.program.display().to_string(),
+			});
+		}
+		Ok(Self {
+			pre_publish: convert_hooks("pre_publish", raw.pre_publish)?,
+			post_commit: convert_hooks("post_commit", raw.post_commit)?,
+			post_reload: convert_hooks("post_reload", raw.post_reload)?,
+			on_failure: convert_hooks("on_failure", raw.on_failure)?,
+		})
+	}
+}
+
+// Returns the includes as a set, for looking them up quickly
+fn check_includes(includes: &[PathBuf], errors: &mut Vec<ZoneConvertError>) -> HashSet<PathBuf> {
+	let mut includes_set: HashSet<PathBuf> = HashSet::new();
+	for include in includes {
+		if include.is_relative() {
+			errors.push(ZoneConvertError::RelativeIncludePath {
+				path: include.display().to_string(),
+			});
+		}
+		// Everything else can be quoted or escaped in the $INCLUDE line
+		if include.to_string_lossy().chars().any(char::is_control) {
+			errors.push(ZoneConvertError::UnrepresentableIncludePath {
+				path: include.display().to_string(),
+			});
+		}
+
+		let was_newly_inserted = includes_set.insert(include.clone());
+		if !was_newly_inserted {
+			errors.push(ZoneConvertError::DuplicateIncludePath {
+				path: include.display().to_string(),
+			});
+		}
+	}
+	includes_set
+}
+
+// Only one of `reload_program_args` and `reload` may be set
+fn convert_reload(
+	reload_program_bin: Option<PathBuf>,
+	reload_program_args: Option<Vec<String>>,
+	reload: Option<ReloadRaw>,
+	errors: &mut Vec<ZoneConvertError>,
+) -> Option<Reload> {
+	match (reload_program_args, reload) {
+		(Some(args), None) => Some(Reload::Program {
+			bin: reload_program_bin,
+			args,
+		}),
+		(None, Some(ReloadRaw::Rndc(rndc))) => {
+			if rndc.key_file.is_relative() {
+				errors.push(ZoneConvertError::RelativeKeyFilePath {
+					path: rndc.key_file.display().to_string(),
 				});
 			}
-
-			let was_newly_inserted = includes_set.insert(include.clone());
-			if !was_newly_inserted {
-				return Err(ZoneConvertError::DuplicateIncludePath {
-					path: include.display().to_string(),
+			Some(Reload::Rndc(rndc))
+		}
+		(None, Some(ReloadRaw::PowerDns(powerdns))) => {
+			if powerdns.api_key_file.is_relative() {
+				errors.push(ZoneConvertError::RelativeApiKeyFilePath {
+					path: powerdns.api_key_file.display().to_string(),
+				});
+			}
+			if !powerdns.url.starts_with("http://") && !powerdns.url.starts_with("https://") {
+				errors.push(ZoneConvertError::InvalidApiUrl {
+					url: powerdns.url.clone(),
 				});
 			}
+			Some(Reload::PowerDns(powerdns))
+		}
+		(Some(_), Some(_)) => {
+			errors.push(ZoneConvertError::ConflictingReloadMethods);
+			None
+		}
+		(None, None) => {
+			errors.push(ZoneConvertError::MissingReloadMethod);
+			None
 		}
+	}
+}
+
+impl TryFrom<ZoneRaw> for Zone {
+	type Error = Vec<ZoneConvertError>;
+
+	fn try_from(raw_zone: ZoneRaw) -> std::result::Result<Self, Self::Error> {
+		let mut errors = Vec::new();
+
+		let includes_set = check_includes(&raw_zone.includes, &mut errors);
 
 		if !raw_zone.soa.mname.ends_with('.') {
-			return Err(ZoneConvertError::InvalidMname {
-				mname: raw_zone.soa.mname,
+			errors.push(ZoneConvertError::InvalidMname {
+				mname: raw_zone.soa.mname.clone(),
 			});
 		}
 
-		if !raw_zone.soa.rname.ends_with('.') {
-			return Err(ZoneConvertError::InvalidRname {
-				rname: raw_zone.soa.rname,
-			});
+		let rname = collect(
+			&mut errors,
+			normalize_rname(&raw_zone.soa.rname).map_err(|source| ZoneConvertError::InvalidRname {
+				rname: raw_zone.soa.rname.clone(),
+				source,
+			}),
+		);
+
+		if raw_zone.reload_program_bin.is_some() && raw_zone.reload_program_args.is_none() {
+			errors.push(ZoneConvertError::UnusedReloadProgramBin);
+		}
+
+		let reload = convert_reload(
+			raw_zone.reload_program_bin,
+			raw_zone.reload_program_args,
+			raw_zone.reload,
+			&mut errors,
+		);
+
+		let reload_timeout = collect(
+			&mut errors,
+			parse_duration(
+				"reload_timeout",
+				raw_zone.reload_timeout,
+				DEFAULT_RELOAD_TIMEOUT,
+			),
+		);
+		let reload_retry = collect(
+			&mut errors,
+			convert_retry("reload_retry", raw_zone.reload_retry),
+		);
+		let hooks = collect(&mut errors, raw_zone.hooks.try_into());
+		let hash_timeout = collect(
+			&mut errors,
+			parse_duration("hash_timeout", raw_zone.hash_timeout, DEFAULT_HASH_TIMEOUT),
+		);
+		let ttl = collect(&mut errors, normalize_ttl("ttl", &raw_zone.ttl));
+		let soa = raw_zone
+			.soa
+			.normalize()
+			.map_err(|soa_errors| errors.extend(soa_errors))
+			.ok();
+
+		let (
+			Some(rname),
+			Some(reload),
+			Some(reload_timeout),
+			Some(reload_retry),
+			Some(hooks),
+			Some(hash_timeout),
+			Some(ttl),
+			Some(soa),
+		) = (
+			rname,
+			reload,
+			reload_timeout,
+			reload_retry,
+			hooks,
+			hash_timeout,
+			ttl,
+			soa,
+		)
+		else {
+			return Err(errors);
+		};
+		if !errors.is_empty() {
+			return Err(errors);
 		}
 
 		let zone = Self {
 			dir: raw_zone.dir,
-			reload_program_args: raw_zone.reload_program_args,
-			ttl: raw_zone.ttl,
+			reload,
+			reload_timeout,
+			reload_retry,
+			hooks,
+			stale_file: raw_zone.stale_file,
+			always_hash: raw_zone.always_hash,
+			hash_timeout,
+			embed_state: false,
+			ttl,
 			includes: raw_zone.includes,
 			includes_set,
-			soa: raw_zone.soa,
+			soa: Soa { rname, ..soa },
 		};
 
 		Ok(zone)
@@ -176,9 +1220,32 @@ impl TryFrom<ZoneRaw> for Zone {
 
 #[cfg(test)]
 mod test {
+	use crate::config::{Soa, ZoneRaw};
+
+	// A zone with only the required fields
+	fn zone_raw(ttl: &str, includes: &[&str], soa: Soa) -> ZoneRaw {
+		use crate::config::{HooksRaw, PathBuf, StaleFilePolicy};
+
+		ZoneRaw {
+			dir: PathBuf::from("/some/dir"),
+			reload_program_bin: None,
+			reload_program_args: Some(Vec::new()),
+			reload: None,
+			reload_timeout: None,
+			reload_retry: None,
+			hooks: HooksRaw::default(),
+			stale_file: StaleFilePolicy::default(),
+			always_hash: false,
+			hash_timeout: None,
+			ttl: ttl.to_string(),
+			includes: includes.iter().map(PathBuf::from).collect(),
+			soa,
+		}
+	}
+
 	#[test]
 	fn check_from_raw_zone_to_zone() {
-		use crate::config::{PathBuf, Soa, Zone, ZoneConvertError, ZoneRaw};
+		use crate::config::{Zone, ZoneConvertError};
 
 		let soa = Soa {
 			ttl: "1d".to_string(),
@@ -191,34 +1258,284 @@ mod test {
 			minimum: "1h".to_string(),
 		};
 
-		let zone_raw_include_relative = ZoneRaw {
-			dir: PathBuf::from("/some/dir"),
-			reload_program_args: Vec::new(),
-			ttl: "1d".to_string(),
-			includes: Vec::from([PathBuf::from("path")]),
-			soa: soa.clone(),
-		};
+		let zone_raw_include_relative = zone_raw("1d", &["path"], soa.clone());
 
 		assert_eq!(
 			zone_raw_include_relative.try_into(),
-			Err::<Zone, _>(ZoneConvertError::RelativeIncludePath {
+			Err::<Zone, _>(vec![ZoneConvertError::RelativeIncludePath {
 				path: "path".to_string()
-			})
+			}])
 		);
 
-		let zone_raw_include_duplicate = ZoneRaw {
-			dir: PathBuf::from("/some/dir"),
-			reload_program_args: Vec::new(),
-			ttl: "1h".to_string(),
-			includes: Vec::from([PathBuf::from("/path"), PathBuf::from("/path")]),
-			soa,
-		};
+		let zone_raw_include_duplicate = zone_raw("1h", &["/path", "/path"], soa.clone());
 
 		assert_eq!(
 			zone_raw_include_duplicate.try_into(),
-			Err::<Zone, _>(ZoneConvertError::DuplicateIncludePath {
+			Err::<Zone, _>(vec![ZoneConvertError::DuplicateIncludePath {
 				path: "/path".to_string()
+			}])
+		);
+
+		let zone_raw_include_newline = zone_raw("1h", &["/path\nwith newline"], soa);
+
+		assert_eq!(
+			zone_raw_include_newline.try_into(),
+			Err::<Zone, _>(vec![ZoneConvertError::UnrepresentableIncludePath {
+				path: "/path\nwith newline".to_string()
+			}])
+		);
+	}
+
+	#[test]
+	fn check_soa_normalization() {
+		use crate::config::{Zone, ZoneConvertError};
+
+		let soa = Soa {
+			ttl: "1d".to_string(),
+			mname: "ns1.example.org.".to_string(),
+			rname: "john\\.doe.example.org.".to_string(),
+			initial_serial: 1,
+			refresh: "2h30m".to_string(),
+			retry: "3h".to_string(),
+			expire: "600".to_string(),
+			minimum: "1H".to_string(),
+		};
+		let zone: Zone = zone_raw("1w", &[], soa.clone())
+			.try_into()
+			.expect("the durations are valid");
+		assert_eq!(zone.ttl, "604800");
+		assert_eq!(
+			zone.soa,
+			Soa {
+				ttl: "86400".to_string(),
+				refresh: "9000".to_string(),
+				retry: "10800".to_string(),
+				expire: "600".to_string(),
+				minimum: "3600".to_string(),
+				..soa.clone()
+			}
+		);
+		assert_eq!(zone.soa.warnings().len(), 2);
+
+		assert_eq!(
+			Zone::try_from(zone_raw(
+				"1d",
+				&[],
+				Soa {
+					retry: "2x".to_string(),
+					..soa.clone()
+				}
+			)),
+			Err(vec![ZoneConvertError::InvalidDuration {
+				field: "soa.retry".to_string(),
+				value: "2x".to_string(),
+			}])
+		);
+		// All problems are reported, not just the first one
+		assert_eq!(
+			Zone::try_from(zone_raw(
+				"2147483648",
+				&[],
+				Soa {
+					mname: "ns1".to_string(),
+					expire: "2y".to_string(),
+					..soa
+				}
+			)),
+			Err(vec![
+				ZoneConvertError::InvalidMname {
+					mname: "ns1".to_string()
+				},
+				ZoneConvertError::DurationTooLarge {
+					field: "ttl".to_string(),
+					value: "2147483648".to_string(),
+				},
+				ZoneConvertError::InvalidDuration {
+					field: "soa.expire".to_string(),
+					value: "2y".to_string(),
+				},
+			])
+		);
+	}
+
+	#[test]
+	fn check_duplicate_origin() {
+		use crate::config::{Config, ConvertError};
+		use indoc::indoc;
+
+		let zone = indoc! {r#"
+			dir = "/var/lib/zonewatch"
+			reload_program_args = []
+			ttl = "1d"
+			includes = []
+			soa = { mname = "ns1.example.org.", rname = "john\\.doe.example.org.", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2h", expire = "1000h", minimum = "1h" }
+		"#};
+		let config = format!(
+			"db = \"/var/lib/zonewatch/db.sqlite\"\nreload_program_bin = \"true\"\n[zones.\"example.org\"]\n{zone}[zones.\"Example.org.\"]\n{zone}"
+		);
+		let error = Config::parse(&config).expect_err("the names are the same after normalization");
+		assert!(matches!(
+			error.downcast_ref::<ConvertError>(),
+			Some(ConvertError::DuplicateOrigin { origin, .. }) if origin == "example.org"
+		));
+	}
+
+	#[test]
+	fn check_inheritance() {
+		use crate::config::{Config, ConvertError, Reload};
+		use indoc::indoc;
+		use std::path::PathBuf;
+
+		let config = Config::parse(indoc! {r#"
+			db = "/var/lib/zonewatch/db.sqlite"
+			reload_program_bin = "true"
+
+			[defaults]
+			dir = "/var/lib/zonewatch"
+			reload_program_args = []
+			ttl = "1d"
+			includes = []
+			soa = { mname = "ns1.example.org.", rname = "john.doe@example.org", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2h", expire = "1000h", minimum = "1h" }
+
+			[profiles.short]
+			ttl = "5m"
+			soa = { minimum = "5m" }
+
+			[zones."example.org"]
+
+			[zones."example.com"]
+			profile = "short"
+			soa = { mname = "ns1.example.com." }
+		"#})
+		.expect("the config is valid");
+
+		let org = &config.zones["example.org"];
+		assert_eq!(org.dir, PathBuf::from("/var/lib/zonewatch"));
+		assert_eq!(org.ttl, "86400");
+		assert!(matches!(org.reload, Reload::Program { .. }));
+
+		// Tables are merged key by key
+		let com = &config.zones["example.com"];
+		assert_eq!(com.ttl, "300");
+		assert_eq!(com.soa.minimum, "300");
+		assert_eq!(com.soa.mname, "ns1.example.com.");
+		assert_eq!(com.soa.refresh, "86400");
+
+		let error = Config::parse(indoc! {r#"
+			db = "/var/lib/zonewatch/db.sqlite"
+			[zones."example.org"]
+			profile = "missing"
+		"#})
+		.expect_err("the profile does not exist");
+		assert!(matches!(
+			error.downcast_ref::<ConvertError>(),
+			Some(ConvertError::UnknownProfile { profile, .. }) if profile == "missing"
+		));
+	}
+
+	#[test]
+	fn check_inherit_reload_method() {
+		use crate::config::{Config, Reload};
+		use indoc::indoc;
+
+		let config = Config::parse(indoc! {r#"
+			db = "/var/lib/zonewatch/db.sqlite"
+			reload_program_bin = "rndc"
+
+			[defaults]
+			dir = "/var/lib/zonewatch"
+			reload_program_bin = "/usr/local/bin/reload"
+			reload_program_args = ["reload", "{zone}"]
+			ttl = "1d"
+			includes = []
+			soa = { mname = "ns1.example.org.", rname = "john.doe@example.org", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2h", expire = "1000h", minimum = "1h" }
+
+			[profiles.rndc]
+			reload = { rndc = { address = "127.0.0.1", key_file = "/etc/bind/rndc.key" } }
+
+			[zones."example.org"]
+			reload = { rndc = { address = "127.0.0.1", key_file = "/etc/bind/rndc.key" } }
+
+			[zones."example.com"]
+			profile = "rndc"
+			reload = { powerdns = { url = "http://127.0.0.1:8081", api_key_file = "/etc/powerdns/api-key" } }
+
+			[zones."example.net"]
+			profile = "rndc"
+			reload_program_args = ["reload"]
+		"#})
+		.expect("the reload method of a zone replaces the inherited one");
+
+		assert!(matches!(
+			config.zones["example.org"].reload,
+			Reload::Rndc(_)
+		));
+		assert!(matches!(
+			config.zones["example.com"].reload,
+			Reload::PowerDns(_)
+		));
+		assert!(matches!(
+			&config.zones["example.net"].reload,
+			Reload::Program { bin: None, args } if args == &["reload"]
+		));
+	}
+
+	#[test]
+	fn check_retry_field() {
+		use crate::config::{
+			Path, ReloadBatch, ReloadBatchConvertError, ReloadBatchRaw, Webhook,
+			WebhookConvertError, WebhookRaw, Zone, ZoneConvertError, ZoneRaw,
+		};
+
+		// Each retry table is named after where it is in the config file
+		let webhook: WebhookRaw = toml::from_str(indoc::indoc! {r#"
+			url = "https://example.org/hook"
+			retry = { attempts = 0 }
+		"#})
+		.expect("can parse");
+		assert_eq!(
+			Webhook::try_from(webhook),
+			Err(WebhookConvertError::Invalid(
+				ZoneConvertError::ZeroAttempts {
+					field: "retry.attempts".to_string()
+				}
+			))
+		);
+
+		let reload_batch: ReloadBatchRaw = toml::from_str(indoc::indoc! {r#"
+			program_args = ["reload"]
+			retry = { max_delay = "soon" }
+		"#})
+		.expect("can parse");
+		let error = ReloadBatch::try_from_raw(reload_batch, Some(Path::new("/bin/true")))
+			.expect_err("the delay is invalid");
+		assert_eq!(
+			error,
+			ReloadBatchConvertError::Invalid(ZoneConvertError::InvalidDuration {
+				field: "reload_batch.retry.max_delay".to_string(),
+				value: "soon".to_string(),
 			})
 		);
+
+		let zone = zone_raw(
+			"1d",
+			&[],
+			Soa {
+				ttl: "1d".to_string(),
+				mname: "ns1.example.org.".to_string(),
+				rname: "john\\.doe.example.org.".to_string(),
+				initial_serial: 1,
+				refresh: "1d".to_string(),
+				retry: "2h".to_string(),
+				expire: "1000h".to_string(),
+				minimum: "1h".to_string(),
+			},
+		);
+		let zone = ZoneRaw {
+			reload_retry: toml::from_str("attempts = 0").expect("can parse"),
+			..zone
+		};
+		let errors = Zone::try_from(zone).expect_err("no attempts");
+		assert_eq!(errors[0].field(), Some("reload_retry.attempts"));
 	}
 }
@@ -0,0 +1,200 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Collects the zones which need reloading within a short window and reloads them together.
+// This avoids calling the reload program hundreds of times in a row when a file included
+// in many zones changes.
+
+use crate::config;
+use crate::reloader::{self, Method, Reloader};
+use crate::zone_file;
+use color_eyre::eyre::{Result, eyre};
+use futures::StreamExt;
+use log::{debug, error, info};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, timeout_at};
+
+struct Job {
+	reloader: Reloader,
+	zone: zone_file::Zone,
+	old_zone: Option<zone_file::Zone>,
+	done: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+	sender: mpsc::UnboundedSender<Job>,
+}
+
+impl Handle {
+	// Wait until the coordinator reloaded the zone, possibly together with other zones
+	pub async fn reload(
+		&self,
+		reloader: &Reloader,
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+	) -> Result<()> {
+		let (done, result) = oneshot::channel();
+		let job = Job {
+			reloader: reloader.clone(),
+			zone: zone.clone(),
+			old_zone: old_zone.cloned(),
+			done,
+		};
+		self.sender
+			.send(job)
+			.map_err(|_| eyre!("The reload coordinator has stopped"))?;
+		result.await.map_err(|_| {
+			eyre!(
+				"The reload coordinator dropped the reload of zone {}",
+				zone.name
+			)
+		})?
+	}
+}
+
+async fn reload_individually(jobs: Vec<Job>, concurrency: usize) {
+	futures::stream::iter(jobs)
+		.for_each_concurrent(concurrency, |job| async move {
+			let result = job.reloader.execute(&job.zone, job.old_zone.as_ref()).await;
+			// The zone task may have stopped waiting, in which case nobody is interested in the result
+			let _ = job.done.send(result);
+		})
+		.await;
+}
+
+async fn reload_with_program(
+	jobs: Vec<Job>,
+	bin: &std::path::Path,
+	args: &[String],
+	timeout: tokio::time::Duration,
+	retry: config::RetryPolicy,
+) {
+	let (batched, individual): (Vec<Job>, Vec<Job>) = jobs
+		.into_iter()
+		.partition(|job| matches!(job.reloader.method, Method::Program { .. }));
+
+	if !batched.is_empty() {
+		let zones: Vec<&str> = batched.iter().map(|job| job.zone.name.as_str()).collect();
+		info!(
+			"Reloading {} zone(s) with command `{} {}`",
+			zones.len(),
+			bin.display(),
+			args.join(" ")
+		);
+		let zones = zones.join("\n");
+		let result = reloader::with_retries("reload the batch of zones", timeout, retry, || {
+			let mut command = Command::new(bin);
+			command.args(args).env("ZONEWATCH_ZONES", &zones);
+			reloader::run_program(command)
+		})
+		.await;
+		let succeeded = result.is_ok();
+		if let Err(e) = result {
+			error!("{e:?}");
+		}
+		for job in batched {
+			let result = if succeeded {
+				Ok(())
+			} else {
+				Err(eyre!(
+					"Reloading zone {} as part of a batch failed",
+					job.zone.name
+				))
+			};
+			let _ = job.done.send(result);
+		}
+	}
+
+	// Zones which are not reloaded using a program cannot be part of the batch
+	reload_individually(individual, 1).await;
+}
+
+pub fn spawn(batch: config::ReloadBatch) -> (Handle, JoinHandle<()>) {
+	let (sender, mut receiver) = mpsc::unbounded_channel();
+	let task = tokio::spawn(async move {
+		// Stops once every zone task dropped its handle
+		while let Some(first) = receiver.recv().await {
+			let mut jobs = vec![first];
+			let end_time = Instant::now() + batch.window;
+			while let Ok(Some(job)) = timeout_at(end_time, receiver.recv()).await {
+				jobs.push(job);
+			}
+			debug!("Collected {} zone(s) to reload", jobs.len());
+			match &batch.mode {
+				config::BatchMode::Program {
+					bin,
+					args,
+					timeout,
+					retry,
+				} => reload_with_program(jobs, bin, args, *timeout, *retry).await,
+				config::BatchMode::PerZone { concurrency } => {
+					reload_individually(jobs, *concurrency).await;
+				}
+			}
+		}
+	});
+	(Handle { sender }, task)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::{BatchMode, ReloadBatch, RetryPolicy};
+	use crate::coordinator::spawn;
+	use crate::reloader::{Method, Reloader};
+	use crate::test_util::{TempDir, test_zone};
+	use std::path::PathBuf;
+	use tokio::time::Duration;
+
+	#[tokio::test]
+	async fn check_batch_program() {
+		let temp_dir = TempDir::new("batch");
+		let dir = temp_dir.path();
+		let output = dir.join("output");
+		let retry = RetryPolicy {
+			attempts: 1,
+			initial_delay: Duration::from_millis(1),
+			max_delay: Duration::from_millis(1),
+		};
+		let (handle, task) = spawn(ReloadBatch {
+			window: Duration::from_millis(100),
+			mode: BatchMode::Program {
+				bin: PathBuf::from("sh"),
+				args: vec![
+					"-c".to_string(),
+					format!("echo \"$ZONEWATCH_ZONES\" >> '{}'", output.display()),
+				],
+				timeout: Duration::from_secs(10),
+				retry,
+			},
+		});
+
+		let reloader = |name: &str| Reloader {
+			zone_name: name.to_string(),
+			method: Method::Program {
+				bin: PathBuf::from("false"),
+				args: Vec::new(),
+			},
+			timeout: Duration::from_secs(10),
+			retry,
+		};
+		let (org, com) = (test_zone("example.org", dir), test_zone("example.com", dir));
+		let (org_reloader, com_reloader) = (reloader("example.org"), reloader("example.com"));
+		let (org_result, com_result) = tokio::join!(
+			handle.reload(&org_reloader, &org, None),
+			handle.reload(&com_reloader, &com, None),
+		);
+		org_result.expect("the batch command should succeed");
+		com_result.expect("the batch command should succeed");
+		drop(handle);
+		task.await.expect("the coordinator should not panic");
+
+		// Both zones were reloaded by a single execution of the batch command
+		let contents = std::fs::read_to_string(&output).expect("can read");
+		let mut zones: Vec<&str> = contents.lines().collect();
+		zones.sort_unstable();
+		assert_eq!(zones, vec!["example.com", "example.org"]);
+	}
+}
@@ -6,19 +6,24 @@ use blake3::Hash;
 use color_eyre::eyre::{Result, WrapErr, eyre};
 use futures::StreamExt;
 use indoc::indoc;
-use log::debug;
+use log::{debug, info};
 use sqlx::{
 	Pool, Row, Sqlite, Transaction,
+	migrate::Migrator,
 	sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
 };
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::{error::Error, fmt::Debug};
 
 #[derive(sqlx::FromRow)]
 struct Zone {
 	name: String,
-	dir: String,
+	dir: Vec<u8>,
 	ttl: String,
 	soa_ttl: String,
 	soa_mname: String,
@@ -32,14 +37,18 @@ struct Zone {
 
 #[derive(sqlx::FromRow)]
 struct Include {
-	path: String,
+	path: Vec<u8>,
 	hash: Option<Vec<u8>>,
 	error: Option<i64>,
+	errno: Option<i64>,
+	error_message: Option<String>,
 }
 
 struct PartialInclude {
 	hash: Option<Vec<u8>>,
 	error: Option<i64>,
+	errno: Option<i64>,
+	error_message: Option<String>,
 }
 
 impl TryFrom<Zone> for zone_file::Soa {
@@ -68,6 +77,12 @@ pub enum IncludeConvertError {
 	#[error("failed to convert the file read error")]
 	FileReadConvert,
 
+	#[error("the file read error has no message")]
+	MissingErrorMessage,
+
+	#[error("errno {0} is out of range")]
+	InvalidErrno(i64),
+
 	#[error("neither hash nor error were set")]
 	HashAndErrorNotSet,
 
@@ -97,7 +112,7 @@ impl TryFrom<Include> for zone_file::Include {
 					.map_err(IncludeConvertError::InvalidHashLength)?;
 				Ok(Readable(Hash::from_bytes(hash)))
 			}
-			(None, Some(error)) => error.try_into(),
+			(None, Some(error)) => include_from_error(error, include.errno, include.error_message),
 			(Some(_), Some(_)) => Err(IncludeConvertError::HashAndErrorBothSet),
 			(None, None) => Err(IncludeConvertError::HashAndErrorNotSet),
 		}
@@ -108,43 +123,73 @@ impl TryFrom<Include> for zone_file::Include {
 enum IncludeError {
 	NotFound = 1,
 	PermissionDenied = 2,
+	Timeout = 3,
+	IsADirectory = 4,
+	TooManySymlinks = 5,
+	InvalidData = 6,
 	OtherError = 0,
 }
 
-impl TryFrom<i64> for zone_file::Include {
-	type Error = IncludeConvertError;
-
-	fn try_from(e: i64) -> Result<Self, Self::Error> {
-		use zone_file::Include::{NotFound, OtherError, PermissionDenied};
-		match e {
-			x if x == IncludeError::OtherError as i64 => Ok(OtherError),
-			x if x == IncludeError::NotFound as i64 => Ok(NotFound),
-			x if x == IncludeError::PermissionDenied as i64 => Ok(PermissionDenied),
-			_ => Err(IncludeConvertError::FileReadConvert),
+fn include_from_error(
+	error: i64,
+	errno: Option<i64>,
+	error_message: Option<String>,
+) -> Result<zone_file::Include, IncludeConvertError> {
+	use zone_file::Include::{
+		InvalidData, IsADirectory, NotFound, OtherError, PermissionDenied, Timeout, TooManySymlinks,
+	};
+	match error {
+		x if x == IncludeError::OtherError as i64 => {
+			let errno = errno
+				.map(|errno| {
+					errno
+						.try_into()
+						.map_err(|_| IncludeConvertError::InvalidErrno(errno))
+				})
+				.transpose()?;
+			let message = error_message.ok_or(IncludeConvertError::MissingErrorMessage)?;
+			Ok(OtherError(zone_file::IoError { errno, message }))
 		}
+		x if x == IncludeError::NotFound as i64 => Ok(NotFound),
+		x if x == IncludeError::PermissionDenied as i64 => Ok(PermissionDenied),
+		x if x == IncludeError::Timeout as i64 => Ok(Timeout),
+		x if x == IncludeError::IsADirectory as i64 => Ok(IsADirectory),
+		x if x == IncludeError::TooManySymlinks as i64 => Ok(TooManySymlinks),
+		x if x == IncludeError::InvalidData as i64 => Ok(InvalidData),
+		_ => Err(IncludeConvertError::FileReadConvert),
 	}
 }
 
 impl From<&zone_file::Include> for PartialInclude {
 	fn from(include: &zone_file::Include) -> Self {
-		use zone_file::Include::{NotFound, OtherError, PermissionDenied, Readable};
+		use zone_file::Include::{
+			InvalidData, IsADirectory, NotFound, OtherError, PermissionDenied, Readable, Timeout,
+			TooManySymlinks,
+		};
+		let error = |error: IncludeError| Self {
+			hash: None,
+			error: Some(error as i64),
+			errno: None,
+			error_message: None,
+		};
 		match include {
 			Readable(hash) => Self {
 				hash: Some(hash.as_bytes().to_vec()),
 				error: None,
+				errno: None,
+				error_message: None,
 			},
-			OtherError => Self {
-				hash: None,
-				error: Some(IncludeError::OtherError as i64),
-			},
-			NotFound => Self {
-				hash: None,
-				error: Some(IncludeError::NotFound as i64),
-			},
-			PermissionDenied => Self {
-				hash: None,
-				error: Some(IncludeError::PermissionDenied as i64),
+			OtherError(e) => Self {
+				errno: e.errno.map(i64::from),
+				error_message: Some(e.message.clone()),
+				..error(IncludeError::OtherError)
 			},
+			NotFound => error(IncludeError::NotFound),
+			PermissionDenied => error(IncludeError::PermissionDenied),
+			Timeout => error(IncludeError::Timeout),
+			IsADirectory => error(IncludeError::IsADirectory),
+			TooManySymlinks => error(IncludeError::TooManySymlinks),
+			InvalidData => error(IncludeError::InvalidData),
 		}
 	}
 }
@@ -154,7 +199,7 @@ fn db_to_zone(
 	(includes, includes_ordered): (HashMap<PathBuf, zone_file::Include>, Vec<PathBuf>),
 ) -> Result<zone_file::Zone> {
 	let name = db_zone.name.clone();
-	let dir = PathBuf::from(&db_zone.dir);
+	let dir = bytes_to_path(db_zone.dir.clone());
 	let ttl = db_zone.ttl.clone();
 	let soa: zone_file::Soa = db_zone
 		.try_into()
@@ -171,27 +216,181 @@ fn db_to_zone(
 	Ok(zone)
 }
 
-pub async fn init(path: &Path) -> Result<Pool<Sqlite>> {
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+#[derive(thiserror::Error, Debug)]
+pub enum VersionError {
+	#[error(
+		"The database is at schema version {applied}, but this version of zonewatch only knows up to version {latest}. \
+		It was probably migrated by a newer version of zonewatch. \
+		Run `zonewatch db migrate --to {latest}` with that newer version before downgrading or upgrade zonewatch again"
+	)]
+	DatabaseTooNew { applied: i64, latest: i64 },
+
+	#[error(
+		"Unknown schema version {target}, this version of zonewatch knows the versions 0 to {latest}"
+	)]
+	UnknownVersion { target: i64, latest: i64 },
+
+	#[error(
+		"Schema versions before {BINARY_PATHS_VERSION} can only store paths which are valid UTF-8, but the database contains {}. \
+		Older versions of zonewatch could not read them after migrating",
+		paths.iter().map(|path| format!("`{}`", path.display())).collect::<Vec<_>>().join(", ")
+	)]
+	NonUtf8Paths { paths: Vec<PathBuf> },
+}
+
+// The first schema version which stores paths as raw bytes
+const BINARY_PATHS_VERSION: i64 = 5;
+
+// The paths in the database which are not valid UTF-8
+async fn non_utf8_paths(pool: &Pool<Sqlite>) -> Result<Vec<PathBuf>> {
+	let paths: Vec<Vec<u8>> = sqlx::query_scalar(indoc! {"
+		SELECT dir FROM zones
+		UNION SELECT path FROM includes
+		UNION SELECT dir FROM archived_zones
+		UNION SELECT path FROM archived_includes;
+	"})
+	.fetch_all(pool)
+	.await
+	.wrap_err("Cannot SELECT paths from the database")?;
+	Ok(paths
+		.into_iter()
+		.filter(|path| std::str::from_utf8(path).is_err())
+		.map(bytes_to_path)
+		.collect())
+}
+
+// The newest schema version this binary can migrate to
+pub fn latest_version() -> i64 {
+	MIGRATOR
+		.iter()
+		.map(|migration| migration.version)
+		.max()
+		.unwrap_or(-1)
+}
+
+// The newest migration which was applied to the database, `None` for a new database
+async fn applied_version(pool: &Pool<Sqlite>) -> Result<Option<i64>> {
+	let has_migrations_table = sqlx::query(indoc! {"
+		SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations';
+	"})
+	.fetch_optional(pool)
+	.await
+	.wrap_err("Cannot check for the migrations table")?
+	.is_some();
+	if !has_migrations_table {
+		return Ok(None);
+	}
+
+	sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success;")
+		.fetch_one(pool)
+		.await
+		.wrap_err("Cannot SELECT version from migrations table")
+}
+
+async fn open(path: &Path) -> Result<Pool<Sqlite>> {
 	let connection_options = SqliteConnectOptions::new()
 		.filename(path)
 		.create_if_missing(true)
 		.journal_mode(SqliteJournalMode::Wal)
 		.optimize_on_close(true, None);
 
-	let pool = SqlitePoolOptions::new()
+	SqlitePoolOptions::new()
 		.max_connections(1)
 		.connect_with(connection_options)
 		.await
-		.wrap_err_with(|| format!("Cannot open database file `{}`", path.display()))?;
+		.wrap_err_with(|| format!("Cannot open database file `{}`", path.display()))
+}
+
+pub async fn init(path: &Path) -> Result<Pool<Sqlite>> {
+	let pool = open(path).await?;
+
+	migrate(&pool).await?;
+
+	Ok(pool)
+}
+
+// Migrate the database up or down to the given schema version, e.g. before downgrading zonewatch
+pub async fn migrate_to(path: &Path, target: i64) -> Result<()> {
+	let latest = latest_version();
+	if !(0..=latest).contains(&target) {
+		return Err(VersionError::UnknownVersion { target, latest }.into());
+	}
+
+	let pool = open(path).await?;
+	let applied = applied_version(&pool).await?.unwrap_or(-1);
+	if applied > latest {
+		return Err(VersionError::DatabaseTooNew { applied, latest }.into());
+	}
+
+	match target.cmp(&applied) {
+		Ordering::Less => {
+			// Reverting the migration would turn these paths into invalid TEXT,
+			// which the older version of zonewatch cannot read
+			if target < BINARY_PATHS_VERSION && applied >= BINARY_PATHS_VERSION {
+				let paths = non_utf8_paths(&pool).await?;
+				if !paths.is_empty() {
+					pool.close().await;
+					return Err(VersionError::NonUtf8Paths { paths }.into());
+				}
+			}
+			MIGRATOR
+				.undo(&pool, target)
+				.await
+				.wrap_err("Cannot revert database migrations")?;
+			info!("Reverted the database from schema version {applied} to {target}");
+		}
+		Ordering::Greater => {
+			MIGRATOR
+				.run_to(target, &pool)
+				.await
+				.wrap_err("Cannot run database migrations")?;
+			info!("Migrated the database from schema version {applied} to {target}");
+		}
+		Ordering::Equal => info!("The database is already at schema version {target}"),
+	}
+
+	pool.close().await;
+	Ok(())
+}
+
+// A database which only lives as long as the process, for running without any state on disk.
+// The state of each zone is then recovered from its zone file.
+pub async fn init_in_memory() -> Result<Pool<Sqlite>> {
+	let connection_options =
+		SqliteConnectOptions::from_str("sqlite::memory:").wrap_err("Invalid database URL")?;
 
-	sqlx::migrate!("./migrations")
-		.run(&pool)
+	// The database disappears when its last connection is closed, so keep the only one forever
+	let pool = SqlitePoolOptions::new()
+		.max_connections(1)
+		.min_connections(1)
+		.idle_timeout(None)
+		.max_lifetime(None)
+		.connect_with(connection_options)
 		.await
-		.wrap_err("Cannot run database migrations")?;
+		.wrap_err("Cannot create in-memory database")?;
+
+	migrate(&pool).await?;
 
 	Ok(pool)
 }
 
+async fn migrate(pool: &Pool<Sqlite>) -> Result<()> {
+	// sqlx only reports the unknown migration, which doesn't tell the user what to do
+	let latest = latest_version();
+	if let Some(applied) = applied_version(pool).await?
+		&& applied > latest
+	{
+		return Err(VersionError::DatabaseTooNew { applied, latest }.into());
+	}
+
+	MIGRATOR
+		.run(pool)
+		.await
+		.wrap_err("Cannot run database migrations")
+}
+
 async fn read_includes(
 	zoneid: i64,
 	tx: &mut Transaction<'_, Sqlite>,
@@ -202,7 +401,9 @@ async fn read_includes(
 			zoneid,
 			path,
 			hash,
-			error
+			error,
+			errno,
+			error_message
 		FROM includes
 		WHERE zoneid = ?1
 		ORDER BY id;
@@ -214,7 +415,7 @@ async fn read_includes(
 	let mut includes_ordered: Vec<PathBuf> = Vec::new();
 	while let Some(maybe_include_row) = includes_rows.next().await {
 		let include_row = maybe_include_row.wrap_err("Cannot get row from includes table")?;
-		let path = PathBuf::from(&include_row.path);
+		let path = bytes_to_path(include_row.path.clone());
 		let include: zone_file::Include = include_row
 			.try_into()
 			.wrap_err("Cannot convert database information to Include struct")?;
@@ -246,15 +447,17 @@ async fn get_zone_id(zone_name: &str, tx: &mut Transaction<'_, Sqlite>) -> Resul
 	}
 }
 
-fn path_buf_to_string(path: &Path) -> Result<String> {
-	path.to_str().map_or_else(
-		|| Err(eyre!("path is not valid unicode: {}", path.display())),
-		|path| Ok(path.to_string()),
-	)
+// Paths are stored as their raw bytes since they don't need to be valid UTF-8
+fn path_to_bytes(path: &Path) -> &[u8] {
+	path.as_os_str().as_bytes()
+}
+
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+	PathBuf::from(OsString::from_vec(bytes))
 }
 
 async fn insert_zone(zone: &zone_file::Zone, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
-	let zone_dir = path_buf_to_string(&zone.dir).wrap_err("Cannot convert zone dir to string")?;
+	let zone_dir = path_to_bytes(&zone.dir);
 
 	sqlx::query(indoc! {"
 		INSERT INTO zones (
@@ -295,7 +498,7 @@ async fn update_zone(
 	zone: &zone_file::Zone,
 	tx: &mut Transaction<'_, Sqlite>,
 ) -> Result<()> {
-	let zone_dir = path_buf_to_string(&zone.dir).wrap_err("Cannot convert zone dir to string")?;
+	let zone_dir = path_to_bytes(&zone.dir);
 
 	// TODO: only update what actually changed?
 	sqlx::query(indoc! {"
@@ -427,8 +630,6 @@ pub async fn write_state(zone: &zone_file::Zone, tx: &mut Transaction<'_, Sqlite
 			let include = new_includes
 				.get(path)
 				.expect("new_includes should contain the same keys as new_includes_ordered");
-			let path_str = path_buf_to_string(path)
-				.wrap_err("Cannot convert path of added include to string")?;
 
 			let partial_include: PartialInclude = include.into();
 
@@ -437,14 +638,18 @@ pub async fn write_state(zone: &zone_file::Zone, tx: &mut Transaction<'_, Sqlite
 					zoneid,
 					path,
 					hash,
-					error
+					error,
+					errno,
+					error_message
 				)
-				VALUES (?1, ?2, ?3, ?4);
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6);
 			"})
 			.bind(zoneid)
-			.bind(path_str)
+			.bind(path_to_bytes(path))
 			.bind(partial_include.hash)
 			.bind(partial_include.error)
+			.bind(partial_include.errno)
+			.bind(partial_include.error_message)
 			.execute(&mut **tx)
 			.await
 			.wrap_err("Cannot INSERT into includes table")?;
@@ -453,3 +658,468 @@ pub async fn write_state(zone: &zone_file::Zone, tx: &mut Transaction<'_, Sqlite
 
 	Ok(())
 }
+
+// The metadata of the readable includes of the zone when they were last hashed
+pub async fn read_metadata(
+	zone_name: &str,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<zone_file::Metadata> {
+	let rows = sqlx::query(indoc! {"
+		SELECT path, dev, ino, size, mtime_ns, ctime_ns
+		FROM includes
+		WHERE zoneid IN (SELECT id FROM zones WHERE name = ?1) AND dev IS NOT NULL;
+	"})
+	.bind(zone_name)
+	.fetch_all(&mut **tx)
+	.await
+	.wrap_err("Cannot SELECT metadata from includes table")?;
+
+	rows.into_iter()
+		.map(|row| {
+			let path: Vec<u8> = row.try_get("path")?;
+			let dev: i64 = row.try_get("dev")?;
+			let ino: i64 = row.try_get("ino")?;
+			let size: i64 = row.try_get("size")?;
+			let metadata = zone_file::FileMetadata {
+				dev: dev.cast_unsigned(),
+				ino: ino.cast_unsigned(),
+				size: size.cast_unsigned(),
+				mtime_ns: row.try_get("mtime_ns")?,
+				ctime_ns: row.try_get("ctime_ns")?,
+			};
+			Ok((bytes_to_path(path), metadata))
+		})
+		.collect::<std::result::Result<_, sqlx::Error>>()
+		.wrap_err("Cannot get metadata from includes table")
+}
+
+// Must be called after `write_state`, which may replace the rows of the includes
+pub async fn write_metadata(
+	zone_name: &str,
+	metadata: &zone_file::Metadata,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+	for (path, file_metadata) in metadata {
+		sqlx::query(indoc! {"
+			UPDATE includes SET
+				dev = ?3,
+				ino = ?4,
+				size = ?5,
+				mtime_ns = ?6,
+				ctime_ns = ?7
+			WHERE zoneid IN (SELECT id FROM zones WHERE name = ?1) AND path = ?2;
+		"})
+		.bind(zone_name)
+		.bind(path_to_bytes(path))
+		// SQLite only has signed integers, the bits are what matters
+		.bind(file_metadata.dev.cast_signed())
+		.bind(file_metadata.ino.cast_signed())
+		.bind(file_metadata.size.cast_signed())
+		.bind(file_metadata.mtime_ns)
+		.bind(file_metadata.ctime_ns)
+		.execute(&mut **tx)
+		.await
+		.wrap_err("Cannot UPDATE metadata in includes table")?;
+	}
+
+	Ok(())
+}
+
+// Whether the current serial of the zone was never successfully reloaded
+pub async fn needs_reload(zone_name: &str, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+	let maybe_row = sqlx::query(indoc! {"
+		SELECT last_reloaded_serial IS NOT soa_serial AS needs_reload
+		FROM zones
+		WHERE name = ?1;
+	"})
+	.bind(zone_name)
+	.fetch_optional(&mut **tx)
+	.await
+	.wrap_err("Cannot SELECT reload state from zones table")?;
+
+	maybe_row.map_or(Ok(false), |row| {
+		row.try_get("needs_reload")
+			.wrap_err("Cannot get reload state from zones table")
+	})
+}
+
+pub async fn mark_reloaded(
+	zone_name: &str,
+	serial: u32,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+	set_last_reloaded_serial(zone_name, Some(serial), tx).await
+}
+
+pub async fn set_last_reloaded_serial(
+	zone_name: &str,
+	serial: Option<u32>,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+	sqlx::query(indoc! {"
+		UPDATE zones SET
+			last_reloaded_serial = ?2
+		WHERE name = ?1;
+	"})
+	.bind(zone_name)
+	.bind(serial)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot UPDATE reload state in zones table")?;
+
+	Ok(())
+}
+
+// The last serial of the zone which was reloaded successfully
+pub async fn last_reloaded_serial(
+	zone_name: &str,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<Option<u32>> {
+	let serial: Option<i64> = sqlx::query_scalar(indoc! {"
+		SELECT last_reloaded_serial FROM zones WHERE name = ?1;
+	"})
+	.bind(zone_name)
+	.fetch_optional(&mut **tx)
+	.await
+	.wrap_err("Cannot SELECT reload state from zones table")?
+	.flatten();
+
+	serial
+		.map(u32::try_from)
+		.transpose()
+		.wrap_err("Invalid last reloaded serial in zones table")
+}
+
+pub struct StoredZone {
+	pub name: String,
+	pub dir: PathBuf,
+	pub serial: u32,
+}
+
+// All zones in the database, sorted by name
+pub async fn list_zones(tx: &mut Transaction<'_, Sqlite>) -> Result<Vec<StoredZone>> {
+	let rows = sqlx::query(indoc! {"
+		SELECT name, dir, soa_serial FROM zones ORDER BY name;
+	"})
+	.fetch_all(&mut **tx)
+	.await
+	.wrap_err("Cannot SELECT zones from zones table")?;
+
+	rows.into_iter()
+		.map(|row| {
+			let dir: Vec<u8> = row.try_get("dir")?;
+			let serial: i64 = row.try_get("soa_serial")?;
+			Ok(StoredZone {
+				name: row.try_get("name")?,
+				dir: bytes_to_path(dir),
+				serial: serial.try_into()?,
+			})
+		})
+		.collect::<std::result::Result<_, Box<dyn Error + Send + Sync>>>()
+		.map_err(|e| eyre!(e))
+		.wrap_err("Cannot get zone from zones table")
+}
+
+pub async fn rename_zone(
+	old_name: &str,
+	new_name: &str,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+	sqlx::query(indoc! {"
+		UPDATE zones SET
+			name = ?2
+		WHERE name = ?1;
+	"})
+	.bind(old_name)
+	.bind(new_name)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot UPDATE name in zones table")?;
+
+	Ok(())
+}
+
+pub async fn delete_zone(zone_name: &str, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+	sqlx::query(indoc! {"
+		DELETE FROM includes
+		WHERE zoneid IN (SELECT id FROM zones WHERE name = ?1);
+	"})
+	.bind(zone_name)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot DELETE from includes table")?;
+
+	sqlx::query(indoc! {"
+		DELETE FROM zones
+		WHERE name = ?1;
+	"})
+	.bind(zone_name)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot DELETE from zones table")?;
+
+	Ok(())
+}
+
+// Move the zone into the archive tables, so that it starts over if it is added again
+pub async fn archive_zone(zone_name: &str, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+	let archived_id = sqlx::query(indoc! {"
+		INSERT INTO archived_zones (
+			name,
+			archived_at,
+			dir,
+			ttl,
+			soa_ttl,
+			soa_mname,
+			soa_rname,
+			soa_serial,
+			soa_refresh,
+			soa_retry,
+			soa_expire,
+			soa_minimum,
+			last_reloaded_serial
+		)
+		SELECT
+			name,
+			CAST(strftime('%s', 'now') AS INTEGER),
+			dir,
+			ttl,
+			soa_ttl,
+			soa_mname,
+			soa_rname,
+			soa_serial,
+			soa_refresh,
+			soa_retry,
+			soa_expire,
+			soa_minimum,
+			last_reloaded_serial
+		FROM zones
+		WHERE name = ?1;
+	"})
+	.bind(zone_name)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot INSERT into archived_zones table")?
+	.last_insert_rowid();
+
+	sqlx::query(indoc! {"
+		INSERT INTO archived_includes (
+			zoneid,
+			path,
+			hash,
+			error,
+			errno,
+			error_message
+		)
+		SELECT ?1, path, hash, error, errno, error_message
+		FROM includes
+		WHERE zoneid IN (SELECT id FROM zones WHERE name = ?2)
+		ORDER BY id;
+	"})
+	.bind(archived_id)
+	.bind(zone_name)
+	.execute(&mut **tx)
+	.await
+	.wrap_err("Cannot INSERT into archived_includes table")?;
+
+	delete_zone(zone_name, tx).await
+}
+
+#[cfg(test)]
+mod test {
+	use crate::db::{
+		VersionError, applied_version, archive_zone, delete_zone, init, latest_version, list_zones,
+		mark_reloaded, migrate_to, needs_reload, open, read_zone, write_state,
+	};
+	use crate::test_util::{TempDir, test_zone};
+	use crate::zone_file;
+
+	#[tokio::test]
+	async fn check_reload_state() {
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+		use std::path::PathBuf;
+
+		let temp_dir = TempDir::new("db");
+		let dir = temp_dir.path();
+		let pool = init(&dir.join("db.sqlite")).await.expect("can open db");
+		// Paths don't need to be valid UTF-8
+		let path = PathBuf::from(OsStr::from_bytes(b"/caf\xe9.zone"));
+		let mut zone = test_zone("example.org", dir);
+		zone.includes
+			.insert(path.clone(), zone_file::Include::NotFound);
+		zone.includes_ordered.push(path);
+
+		let mut tx = pool.begin().await.expect("can begin");
+		assert!(!needs_reload(&zone.name, &mut tx).await.expect("can read"));
+		write_state(&zone, &mut tx).await.expect("can write");
+		assert_eq!(
+			read_zone(&zone.name, &mut tx).await.expect("can read"),
+			Some(zone.clone())
+		);
+		assert!(needs_reload(&zone.name, &mut tx).await.expect("can read"));
+		mark_reloaded(&zone.name, 1, &mut tx)
+			.await
+			.expect("can write");
+		assert!(!needs_reload(&zone.name, &mut tx).await.expect("can read"));
+		zone.soa.serial = 2;
+		write_state(&zone, &mut tx).await.expect("can write");
+		assert!(needs_reload(&zone.name, &mut tx).await.expect("can read"));
+		tx.commit().await.expect("can commit");
+
+		pool.close().await;
+	}
+
+	#[tokio::test]
+	async fn check_remove_zones() {
+		let temp_dir = TempDir::new("db-remove");
+		let dir = temp_dir.path();
+		let pool = init(&dir.join("db.sqlite")).await.expect("can open db");
+		let mut zone = test_zone("example.org", dir);
+		zone.soa.serial = 5;
+
+		let mut tx = pool.begin().await.expect("can begin");
+		write_state(&zone, &mut tx).await.expect("can write");
+		zone.name = "example.com".to_string();
+		write_state(&zone, &mut tx).await.expect("can write");
+		let names = |zones: Vec<crate::db::StoredZone>| -> Vec<String> {
+			zones.into_iter().map(|zone| zone.name).collect()
+		};
+		assert_eq!(
+			names(list_zones(&mut tx).await.expect("can read")),
+			["example.com", "example.org"]
+		);
+
+		archive_zone("example.org", &mut tx)
+			.await
+			.expect("can archive");
+		delete_zone("example.com", &mut tx)
+			.await
+			.expect("can delete");
+		assert!(list_zones(&mut tx).await.expect("can read").is_empty());
+		let archived: (String, i64) =
+			sqlx::query_as("SELECT name, soa_serial FROM archived_zones;")
+				.fetch_one(&mut *tx)
+				.await
+				.expect("can read archive");
+		assert_eq!(archived, ("example.org".to_string(), 5));
+		tx.commit().await.expect("can commit");
+
+		pool.close().await;
+	}
+
+	#[tokio::test]
+	async fn check_migrate_to() {
+		let temp_dir = TempDir::new("db-migrate");
+		let dir = temp_dir.path();
+		let path = dir.join("db.sqlite");
+		init(&path).await.expect("can open db").close().await;
+
+		migrate_to(&path, 0).await.expect("can migrate down");
+		let pool = open(&path).await.expect("can open db");
+		assert_eq!(applied_version(&pool).await.expect("can read"), Some(0));
+		pool.close().await;
+
+		// Starting normally migrates it up again
+		let pool = init(&path).await.expect("can open db");
+		assert_eq!(
+			applied_version(&pool).await.expect("can read"),
+			Some(latest_version())
+		);
+
+		// Pretend that a newer version of zonewatch migrated the database
+		let newer = latest_version() + 1;
+		sqlx::query(
+			"INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (?1, 'newer', TRUE, x'00', 0);",
+		)
+		.bind(newer)
+		.execute(&pool)
+		.await
+		.expect("can insert");
+		pool.close().await;
+		let error = init(&path).await.expect_err("database is too new");
+		assert!(matches!(
+			error.downcast_ref::<VersionError>(),
+			Some(VersionError::DatabaseTooNew { applied, .. }) if *applied == newer
+		));
+	}
+
+	#[tokio::test]
+	async fn check_binary_paths_migration() {
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+		use std::path::PathBuf;
+
+		let temp_dir = TempDir::new("db-binary-paths");
+		let dir = temp_dir.path();
+		let path = dir.join("db.sqlite");
+		let pool = init(&path).await.expect("can open db");
+		let mut zone = test_zone("example.org", dir);
+		let include = dir.join("file 1.zone");
+		zone.includes.insert(
+			include.clone(),
+			zone_file::Include::Readable(blake3::hash(b"a")),
+		);
+		zone.includes_ordered.push(include);
+		let mut tx = pool.begin().await.expect("can begin");
+		write_state(&zone, &mut tx).await.expect("can write");
+		archive_zone("example.org", &mut tx)
+			.await
+			.expect("can archive");
+		write_state(&zone, &mut tx).await.expect("can write");
+		mark_reloaded(&zone.name, 1, &mut tx)
+			.await
+			.expect("can write");
+		tx.commit().await.expect("can commit");
+		pool.close().await;
+
+		let types = async |expected: &str| {
+			let pool = open(&path).await.expect("can open db");
+			for query in [
+				"SELECT typeof(dir) FROM zones;",
+				"SELECT typeof(path) FROM includes;",
+				"SELECT typeof(dir) FROM archived_zones;",
+				"SELECT typeof(path) FROM archived_includes;",
+			] {
+				let (name,): (String,) = sqlx::query_as(query)
+					.fetch_one(&pool)
+					.await
+					.expect("can read");
+				assert_eq!(name, expected, "{query}");
+			}
+			pool.close().await;
+		};
+		types("blob").await;
+
+		// Reverting the migration keeps the rows, as text again
+		migrate_to(&path, 4).await.expect("can migrate down");
+		types("text").await;
+
+		migrate_to(&path, 5).await.expect("can migrate up");
+		types("blob").await;
+		let pool = open(&path).await.expect("can open db");
+		let mut tx = pool.begin().await.expect("can begin");
+		assert_eq!(
+			read_zone(&zone.name, &mut tx).await.expect("can read"),
+			Some(zone.clone())
+		);
+		assert!(!needs_reload(&zone.name, &mut tx).await.expect("can read"));
+
+		// Older versions cannot read paths which are not valid UTF-8
+		let invalid = PathBuf::from(OsStr::from_bytes(b"/caf\xe9.zone"));
+		zone.includes
+			.insert(invalid.clone(), zone_file::Include::NotFound);
+		zone.includes_ordered.push(invalid.clone());
+		write_state(&zone, &mut tx).await.expect("can write");
+		tx.commit().await.expect("can commit");
+		pool.close().await;
+		let error = migrate_to(&path, 4)
+			.await
+			.expect_err("the path cannot be converted");
+		assert!(matches!(
+			error.downcast_ref::<VersionError>(),
+			Some(VersionError::NonUtf8Paths { paths }) if paths == &[invalid]
+		));
+		types("blob").await;
+	}
+}
@@ -0,0 +1,345 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Validation and normalization of the domain names in the config file.
+// Names are compared and written in their canonical form: lower case, without the trailing dot
+// and with internationalized labels converted to their A-label (punycode) form.
+// The mailbox in the SOA record can also be given as an e-mail address.
+
+use idna::AsciiDenyList;
+
+// RFC 1035 section 2.3.4, not counting the length octets and the root label
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 253;
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum NameError {
+	#[error("The name is empty")]
+	Empty,
+
+	#[error("The name contains an empty label")]
+	EmptyLabel,
+
+	#[error("The name is not a valid internationalized domain name")]
+	InvalidIdn,
+
+	#[error("The label `{label}` is longer than {MAX_LABEL_LENGTH} characters")]
+	LabelTooLong { label: String },
+
+	#[error("The name is longer than {MAX_NAME_LENGTH} characters")]
+	NameTooLong,
+
+	#[error("The label `{label}` contains the invalid character `{character}`")]
+	InvalidCharacter { label: String, character: char },
+
+	#[error("The label `{label}` starts or ends with a hyphen")]
+	InvalidHyphen { label: String },
+}
+
+fn check_label(label: &str) -> Result<(), NameError> {
+	if label.is_empty() {
+		return Err(NameError::EmptyLabel);
+	}
+	if label.len() > MAX_LABEL_LENGTH {
+		return Err(NameError::LabelTooLong {
+			label: label.to_string(),
+		});
+	}
+	// Letters, digits and hyphens (RFC 1035 section 2.3.1), underscores for names like `_dmarc`
+	// and slashes for classless reverse zones like `0/25.2.0.192.in-addr.arpa` (RFC 2317)
+	if let Some(character) = label
+		.chars()
+		.find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '_' | '/'))
+	{
+		return Err(NameError::InvalidCharacter {
+			label: label.to_string(),
+			character,
+		});
+	}
+	if label.starts_with('-') || label.ends_with('-') {
+		return Err(NameError::InvalidHyphen {
+			label: label.to_string(),
+		});
+	}
+	Ok(())
+}
+
+// Returns the canonical form of the name of a zone
+pub fn normalize_origin(origin: &str) -> Result<String, NameError> {
+	let name = origin.strip_suffix('.').unwrap_or(origin);
+	if name.is_empty() {
+		return Err(NameError::Empty);
+	}
+	if name.split('.').any(str::is_empty) {
+		return Err(NameError::EmptyLabel);
+	}
+
+	// Lower-cases the name and converts Unicode labels to punycode
+	let name = idna::domain_to_ascii_cow(name.as_bytes(), AsciiDenyList::EMPTY)
+		.map_err(|_| NameError::InvalidIdn)?;
+	for label in name.split('.') {
+		check_label(label)?;
+	}
+	if name.len() > MAX_NAME_LENGTH {
+		return Err(NameError::NameTooLong);
+	}
+	Ok(name.into_owned())
+}
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum RnameError {
+	#[error("The name must end in a dot")]
+	MissingTrailingDot,
+
+	#[error("The local part of the e-mail address is empty")]
+	EmptyLocalPart,
+
+	#[error("The local part of the e-mail address contains a whitespace or control character")]
+	InvalidLocalPart,
+
+	#[error("The domain of the e-mail address is invalid")]
+	InvalidDomain(#[source] NameError),
+
+	#[error("The name contains an empty label, a dot in the local part must be escaped as `\\.`")]
+	EmptyLabel,
+
+	#[error("The name contains the invalid escape sequence `{sequence}`")]
+	InvalidEscape { sequence: String },
+
+	#[error("The name contains the character {character:?}, which must be escaped")]
+	UnescapedCharacter { character: char },
+
+	#[error("The label `{label}` is longer than {MAX_LABEL_LENGTH} bytes")]
+	LabelTooLong { label: String },
+}
+
+// Check the escaping of a name in the master file format (RFC 1035 section 5.1):
+// `\X` quotes the character X and `\DDD` is the octet with the decimal value DDD.
+// Also checks the length of the labels, which is counted in octets after unescaping.
+fn check_escaping(name: &str) -> Result<(), RnameError> {
+	let check_length = |label: &str, length: usize| {
+		if length > MAX_LABEL_LENGTH {
+			return Err(RnameError::LabelTooLong {
+				label: label.to_string(),
+			});
+		}
+		Ok(())
+	};
+
+	let mut chars = name.char_indices();
+	let mut label_start = 0;
+	let mut label_length = 0;
+	while let Some((index, c)) = chars.next() {
+		match c {
+			'\\' => {
+				let next = chars.next().map(|(_, c)| c);
+				if next.is_some_and(|c| c.is_ascii_digit()) {
+					let digits: String = next
+						.into_iter()
+						.chain(chars.by_ref().take(2).map(|(_, c)| c))
+						.collect();
+					let valid = digits.len() == 3
+						&& digits.chars().all(|c| c.is_ascii_digit())
+						&& digits.parse::<u16>().is_ok_and(|value| value <= 255);
+					if !valid {
+						return Err(RnameError::InvalidEscape {
+							sequence: format!("\\{digits}"),
+						});
+					}
+					label_length += 1;
+				} else if let Some(next) = next.filter(|c| !c.is_control()) {
+					label_length += next.len_utf8();
+				} else {
+					return Err(RnameError::InvalidEscape {
+						sequence: format!("\\{}", next.map(String::from).unwrap_or_default()),
+					});
+				}
+			}
+			'.' => {
+				if label_length == 0 {
+					return Err(RnameError::EmptyLabel);
+				}
+				check_length(&name[label_start..index], label_length)?;
+				label_start = index + 1;
+				label_length = 0;
+			}
+			// These have a special meaning in master files
+			c if c.is_whitespace()
+				|| c.is_control()
+				|| matches!(c, ';' | '(' | ')' | '"' | '$') =>
+			{
+				return Err(RnameError::UnescapedCharacter { character: c });
+			}
+			_ => label_length += c.len_utf8(),
+		}
+	}
+	check_length(&name[label_start..], label_length)
+}
+
+// The position of the last `@` which is not escaped with a backslash
+fn find_unescaped_at(name: &str) -> Option<usize> {
+	let mut chars = name.char_indices();
+	let mut position = None;
+	while let Some((index, c)) = chars.next() {
+		match c {
+			'\\' => {
+				chars.next();
+			}
+			'@' => position = Some(index),
+			_ => {}
+		}
+	}
+	position
+}
+
+// Returns the mailbox in the form used in the SOA record, e.g. `john\.doe.example.org.`.
+// An e-mail address like `john.doe@example.org` is converted to that form.
+pub fn normalize_rname(rname: &str) -> Result<String, RnameError> {
+	// `\@` is an escaped character in the mailbox, not the separator of an e-mail address
+	let Some((local_part, domain)) =
+		find_unescaped_at(rname).map(|position| (&rname[..position], &rname[position + 1..]))
+	else {
+		if !rname.ends_with('.') {
+			return Err(RnameError::MissingTrailingDot);
+		}
+		check_escaping(rname)?;
+		return Ok(rname.to_string());
+	};
+
+	if local_part.is_empty() {
+		return Err(RnameError::EmptyLocalPart);
+	}
+	if local_part
+		.chars()
+		.any(|c| c.is_whitespace() || c.is_control())
+	{
+		return Err(RnameError::InvalidLocalPart);
+	}
+	let domain = normalize_origin(domain).map_err(RnameError::InvalidDomain)?;
+	let local_part = escape_label(local_part);
+	let rname = format!("{local_part}.{domain}.");
+	// The local part may be too long for a label
+	check_escaping(&rname)?;
+	Ok(rname)
+}
+
+// Escape the characters of the local part of an e-mail address which have a special meaning
+// in master files (RFC 1035 section 5.1), so that it becomes the first label of the RNAME
+fn escape_label(label: &str) -> String {
+	label.chars().fold(String::new(), |mut escaped, c| {
+		if matches!(c, '.' | '\\' | '"' | ';' | '(' | ')' | '$' | '@') {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+		escaped
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use crate::domain_name::{NameError, RnameError, normalize_origin, normalize_rname};
+
+	#[test]
+	fn check_normalize_origin() {
+		assert_eq!(
+			normalize_origin("Example.ORG."),
+			Ok("example.org".to_string())
+		);
+		assert_eq!(
+			normalize_origin("Bücher.example"),
+			Ok("xn--bcher-kva.example".to_string())
+		);
+		assert_eq!(
+			normalize_origin("_dmarc.example.org"),
+			Ok("_dmarc.example.org".to_string())
+		);
+		assert_eq!(
+			normalize_origin("0/25.2.0.192.in-addr.arpa"),
+			Ok("0/25.2.0.192.in-addr.arpa".to_string())
+		);
+		assert_eq!(normalize_origin("."), Err(NameError::Empty));
+		assert_eq!(normalize_origin("example..org"), Err(NameError::EmptyLabel));
+		assert_eq!(
+			normalize_origin("exa mple.org"),
+			Err(NameError::InvalidCharacter {
+				label: "exa mple".to_string(),
+				character: ' '
+			})
+		);
+		assert_eq!(
+			normalize_origin("-example.org"),
+			Err(NameError::InvalidHyphen {
+				label: "-example".to_string()
+			})
+		);
+		let long_label = "a".repeat(64);
+		assert_eq!(
+			normalize_origin(&format!("{long_label}.org")),
+			Err(NameError::LabelTooLong { label: long_label })
+		);
+		let long_name = vec!["a".repeat(63); 4].join(".");
+		assert_eq!(normalize_origin(&long_name), Err(NameError::NameTooLong));
+	}
+
+	#[test]
+	fn check_normalize_rname() {
+		assert_eq!(
+			normalize_rname("john.doe@Example.org"),
+			Ok("john\\.doe.example.org.".to_string())
+		);
+		assert_eq!(
+			normalize_rname("john;doe(x)@example.org"),
+			Ok("john\\;doe\\(x\\).example.org.".to_string())
+		);
+		assert_eq!(
+			normalize_rname("john\\.doe.example.org."),
+			Ok("john\\.doe.example.org.".to_string())
+		);
+		assert_eq!(
+			normalize_rname("hostmaster\\064.example.org."),
+			Ok("hostmaster\\064.example.org.".to_string())
+		);
+		assert_eq!(
+			normalize_rname("john.doe.example.org"),
+			Err(RnameError::MissingTrailingDot)
+		);
+		assert_eq!(
+			normalize_rname("john..doe.example.org."),
+			Err(RnameError::EmptyLabel)
+		);
+		assert_eq!(
+			normalize_rname("john\\256.example.org."),
+			Err(RnameError::InvalidEscape {
+				sequence: "\\256".to_string()
+			})
+		);
+		assert_eq!(
+			normalize_rname("@example.org"),
+			Err(RnameError::EmptyLocalPart)
+		);
+		// An escaped `@` is part of the mailbox, not the separator of an e-mail address
+		assert_eq!(
+			normalize_rname("john\\@doe.example.org."),
+			Ok("john\\@doe.example.org.".to_string())
+		);
+		assert_eq!(
+			normalize_rname("john;doe.example.org."),
+			Err(RnameError::UnescapedCharacter { character: ';' })
+		);
+		assert_eq!(
+			normalize_rname("john\\;doe.example.org."),
+			Ok("john\\;doe.example.org.".to_string())
+		);
+		let long_label = format!("{}\\.", "a".repeat(63));
+		assert_eq!(
+			normalize_rname(&format!("{long_label}.example.org.")),
+			Err(RnameError::LabelTooLong { label: long_label })
+		);
+		assert_eq!(
+			normalize_rname(&format!("{}@example.org", "a".repeat(64))),
+			Err(RnameError::LabelTooLong {
+				label: "a".repeat(64)
+			})
+		);
+	}
+}
@@ -4,52 +4,77 @@
 use crate::config;
 use crate::db;
 use crate::event_analyzer::Changes;
-use crate::reloader::Reloader;
+use crate::hooks::{self, Event};
+use crate::reloader;
+use crate::webhook::{self, Notification};
 use crate::zone_file;
 use color_eyre::eyre::{Result, WrapErr};
-use log::{debug, info, trace};
-use sqlx::{Pool, Sqlite, Transaction};
+use log::{debug, error, info, trace, warn};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashSet;
+use std::path::PathBuf;
 
-fn update_zone(
+async fn update_zone(
 	zone_name: &str,
 	config_zone: &config::Zone,
 	changes: Changes,
 	maybe_old_zone: Option<&zone_file::Zone>,
-) -> zone_file::Zone {
-	let (serial, includes) = maybe_old_zone.as_ref().map_or_else(
-		|| {
+	old_metadata: &zone_file::Metadata,
+) -> (zone_file::Zone, zone_file::Metadata) {
+	let timeout = config_zone.hash_timeout;
+	let (serial, (includes, metadata)) = match maybe_old_zone {
+		None => {
 			let serial = config_zone.soa.initial_serial;
 			info!(
 				"Zone {zone_name} does not exist yet, generating new zone file with serial {serial}"
 			);
-			let includes =
-				zone_file::Include::files_from_paths(zone_name, config_zone.includes.iter());
+			let includes = zone_file::Include::files_from_paths(
+				zone_name,
+				config_zone.includes.iter(),
+				None,
+				timeout,
+			)
+			.await;
 			(serial, includes)
-		},
-		|old_zone| {
+		}
+		Some(old_zone) => {
+			// Files whose metadata didn't change since they were last hashed aren't hashed again
+			let previous = (!config_zone.always_hash).then_some((&old_zone.includes, old_metadata));
 			let includes = match changes {
 				Changes::All => {
 					debug!("Will rescan all the files included in zone {zone_name}");
-					zone_file::Include::files_from_paths(zone_name, config_zone.includes.iter())
+					zone_file::Include::files_from_paths(
+						zone_name,
+						config_zone.includes.iter(),
+						previous,
+						timeout,
+					)
+					.await
 				}
 				Changes::Some(changed_include_paths) => {
 					debug!(
 						"This is the set of changed files for zone {zone_name}: {changed_include_paths:?}"
 					);
-					let changed_files = zone_file::Include::files_from_paths(
+					let (changed_files, changed_metadata) = zone_file::Include::files_from_paths(
 						zone_name,
 						changed_include_paths.iter(),
-					);
+						previous,
+						timeout,
+					)
+					.await;
 					let mut includes = old_zone.includes.clone();
 					includes.extend(changed_files);
-					includes
+					let mut metadata = old_metadata.clone();
+					metadata.retain(|path, _| !changed_include_paths.contains(path));
+					metadata.extend(changed_metadata);
+					(includes, metadata)
 				}
-				Changes::None => old_zone.includes.clone(),
+				Changes::None => (old_zone.includes.clone(), old_metadata.clone()),
 			};
 
 			(old_zone.soa.serial, includes)
-		},
-	);
+		}
+	};
 
 	let soa = zone_file::Soa {
 		ttl: config_zone.soa.ttl.clone(),
@@ -61,101 +86,223 @@ fn update_zone(
 		expire: config_zone.soa.expire.clone(),
 		minimum: config_zone.soa.minimum.clone(),
 	};
-	zone_file::Zone {
+	let zone = zone_file::Zone {
 		name: zone_name.to_string(),
 		dir: config_zone.dir.clone(),
 		ttl: config_zone.ttl.clone(),
 		includes,
 		includes_ordered: config_zone.includes.clone(),
 		soa,
+	};
+	(zone, metadata)
+}
+
+// Without a database, or when it was lost, continue where the existing zone file left off
+// instead of starting over with the initial serial
+fn recover_zone(zone_name: &str, config_zone: &config::Zone) -> Option<zone_file::Zone> {
+	match zone_file::read_from_disc(zone_name, &config_zone.dir) {
+		Ok(Some(zone)) => {
+			info!(
+				"Recovered the state of zone {zone_name} with serial {} from its zone file",
+				zone.soa.serial
+			);
+			Some(zone)
+		}
+		Ok(None) => None,
+		Err(e) => {
+			warn!("Cannot recover the state of zone {zone_name} from its zone file: {e:?}");
+			None
+		}
 	}
 }
 
-// Returns true if the reload program needs to be executed
-async fn write_state(
-	// add needs_reloading to the name
+// Returns the zone if it needs to be written and the reload program needs to be executed
+fn zone_to_publish(
 	zone_name: &str,
 	force_write: bool,
 	mut new_zone: zone_file::Zone,
-	maybe_old_zone: Option<zone_file::Zone>,
-	tx: &mut Transaction<'_, Sqlite>,
-) -> Result<bool> {
-	let needs_reloading = match maybe_old_zone {
-		None => {
-			info!("Writing zone file {zone_name} for the first time");
-			zone_file::sync_state_to_disc(new_zone, tx).await?;
-			true
-		}
-		Some(old_zone) => {
-			trace!("old_zone: {old_zone:?} (zone {zone_name})");
-			trace!("new_zone: {new_zone:?} (zone {zone_name})");
-			if new_zone == old_zone {
-				if force_write {
-					// It may have happened that zonewatch was shut down after the
-					// database was written (with a new serial number) but before the zone file was written.
-					// If this happens, the serial number is inconsistent between the zone file and the database.
-					// To fix this, I could call fsync(). But since I can also just regenerate the file
-					// every time this program starts, I'll avoid learning the semantics
-					// of fsync() on every filesystem and just write the zone file again after each start.
-					info!("Writing zone file {zone_name} even though nothing changed");
-					zone_file::sync_state_to_disc(new_zone, tx).await?;
-					true
-				} else {
-					info!(
-						"No contents of any file actually changed for zone {zone_name}, ignoring"
-					);
-					false
-				}
-			} else {
-				let serial = new_zone.soa.serial.wrapping_add(1);
-				info!(
-					"Something changed, generating new zone file {zone_name} and incrementing serial to {serial}"
-				);
-				new_zone.soa.serial = serial;
-
-				zone_file::sync_state_to_disc(new_zone, tx).await?;
-				true
-			}
+	maybe_old_zone: Option<&zone_file::Zone>,
+) -> Option<zone_file::Zone> {
+	let Some(old_zone) = maybe_old_zone else {
+		info!("Writing zone file {zone_name} for the first time");
+		return Some(new_zone);
+	};
+	trace!("old_zone: {old_zone:?} (zone {zone_name})");
+	trace!("new_zone: {new_zone:?} (zone {zone_name})");
+	if new_zone == old_zone.with_durations_in_seconds() {
+		if force_write {
+			// It may have happened that zonewatch was shut down after the
+			// database was written (with a new serial number) but before the zone file was written.
+			// If this happens, the serial number is inconsistent between the zone file and the database.
+			// To fix this, I could call fsync(). But since I can also just regenerate the file
+			// every time this program starts, I'll avoid learning the semantics
+			// of fsync() on every filesystem and just write the zone file again after each start.
+			info!("Writing zone file {zone_name} even though nothing changed");
+			Some(new_zone)
+		} else {
+			info!("No contents of any file actually changed for zone {zone_name}, ignoring");
+			None
 		}
+	} else {
+		let serial = new_zone.soa.serial.wrapping_add(1);
+		info!(
+			"Something changed, generating new zone file {zone_name} and incrementing serial to {serial}"
+		);
+		new_zone.soa.serial = serial;
+		Some(new_zone)
+	}
+}
+
+// The includes which differ between the two zones, so they can be checked again later
+fn changed_includes(
+	new_zone: &zone_file::Zone,
+	maybe_old_zone: Option<&zone_file::Zone>,
+) -> Changes {
+	let Some(old_zone) = maybe_old_zone else {
+		return Changes::All;
 	};
-	Ok(needs_reloading)
+	let paths: HashSet<PathBuf> = new_zone
+		.includes
+		.iter()
+		.filter(|(path, include)| old_zone.includes.get(*path) != Some(include))
+		.map(|(path, _)| path.clone())
+		.collect();
+	if paths.is_empty() {
+		Changes::None
+	} else {
+		Changes::Some(paths)
+	}
 }
 
-pub async fn process_probably_changed_includes(
+// Returns the changes which were rejected by the pre-publish hook and still need to be published
+async fn publish(
 	zone_name: &str,
 	config_zone: &config::Zone,
 	changes: Changes,
-	reloader: &Reloader,
+	reload_queue: &reloader::Queue,
+	notifier: Option<&webhook::Handle>,
 	force_write: bool,
 	pool: &Pool<Sqlite>,
-) -> Result<()> {
-	trace!("Will begin transaction for zone {zone_name}");
+) -> Result<Changes> {
 	let mut tx = pool.begin().await.wrap_err("Cannot begin transaction")?;
-	trace!("Transaction began for zone {zone_name}");
-
 	let maybe_old_zone = db::read_zone(zone_name, &mut tx)
 		.await
-		.wrap_err("Cannot read zone info")?;
+		.wrap_err("Cannot read zone info")?
+		.or_else(|| recover_zone(zone_name, config_zone));
+	let old_metadata = db::read_metadata(zone_name, &mut tx)
+		.await
+		.wrap_err("Cannot read metadata of the includes")?;
+	// The pool has a single connection, so don't keep other zones from using the database while hashing.
+	// Only the watcher of this zone writes its state, so it cannot change until the next transaction.
+	tx.commit().await.wrap_err("Cannot commit transaction")?;
+
+	let (new_zone, metadata) = update_zone(
+		zone_name,
+		config_zone,
+		changes,
+		maybe_old_zone.as_ref(),
+		&old_metadata,
+	)
+	.await;
+	let Some(zone) = zone_to_publish(zone_name, force_write, new_zone, maybe_old_zone.as_ref())
+	else {
+		trace!("We don't need to call the reloading program for zone {zone_name}");
+		// Remember the metadata of files which were hashed again without changing
+		if metadata != old_metadata {
+			let mut tx = pool.begin().await.wrap_err("Cannot begin transaction")?;
+			db::write_metadata(zone_name, &metadata, &mut tx).await?;
+			tx.commit().await.wrap_err("Cannot commit transaction")?;
+		}
+		return Ok(Changes::None);
+	};
+
+	let context = reloader::ProgramContext::new(&zone, maybe_old_zone.as_ref());
+	if let Err(e) = hooks::run(&config_zone.hooks.pre_publish, Event::PrePublish, &context).await {
+		// Nothing was written yet, so the database still has the old state of the includes.
+		// The rejected includes are hashed again and passed to the hook with the next change to the zone,
+		// otherwise publishing an unrelated change would serve their unchecked contents.
+		error!("{e:?}");
+		hooks::run_on_failure(&config_zone.hooks.on_failure, &context, &e).await;
+		return Ok(changed_includes(&zone, maybe_old_zone.as_ref()));
+	}
 
-	let new_zone = update_zone(zone_name, config_zone, changes, maybe_old_zone.as_ref());
-	let needs_reloading =
-		write_state(zone_name, force_write, new_zone, maybe_old_zone, &mut tx).await?;
+	trace!("Will begin transaction for zone {zone_name}");
+	let mut tx = pool.begin().await.wrap_err("Cannot begin transaction")?;
+	trace!("Transaction began for zone {zone_name}");
+	zone_file::sync_state_to_disc(&zone, config_zone.embed_state, &mut tx).await?;
+	db::write_metadata(zone_name, &metadata, &mut tx).await?;
 
 	trace!("Will end transaction for zone {zone_name}");
 	tx.commit().await.wrap_err("Cannot commit transaction")?;
 	trace!("Transaction ended for zone {zone_name}");
 
+	if let Some(old_zone) = &maybe_old_zone {
+		let old_path = old_zone.file_path();
+		let new_path = zone.file_path();
+		if old_path != new_path {
+			// The new zone file is already written, so this is not worth failing the publishing for
+			if let Err(e) = zone_file::handle_stale_file(
+				zone_name,
+				&old_path,
+				&new_path,
+				config_zone.stale_file,
+			) {
+				error!("{e:?}");
+			}
+		}
+	}
+
+	if let Some(notifier) = notifier {
+		for notification in Notification::for_changes(&zone, maybe_old_zone.as_ref()) {
+			notifier.notify(notification);
+		}
+	}
+
+	if let Err(e) = hooks::run(&config_zone.hooks.post_commit, Event::PostCommit, &context).await {
+		// The zone file was already written, but the DNS server is not told about it yet.
+		// The database remembers that it still needs to be reloaded, which is retried later.
+		error!("{e:?}");
+		hooks::run_on_failure(&config_zone.hooks.on_failure, &context, &e).await;
+		return Ok(Changes::None);
+	}
+
 	// Reload only after committing the transaction.
 	// If the reload command fails, the updated zone file was already written to disk
 	// and the DNS server may have already seen the incremented serial number.
 	// For this reason we have to keep the new serial number.
-	if needs_reloading {
-		trace!("Will execute the reloading program for zone {zone_name}");
-		reloader.execute()?;
-		trace!("Done executing the reloading program for zone {zone_name}");
-	} else {
-		trace!("We don't need to call the reloading program for zone {zone_name}");
-	}
+	// The database remembers that this serial number still needs to be reloaded until the reload succeeds.
+	trace!("Requesting reload of zone {zone_name}");
+	reload_queue.request(reloader::Request {
+		zone,
+		old_zone: maybe_old_zone,
+	});
+
+	Ok(Changes::None)
+}
 
-	Ok(())
+// Returns the changes which still need to be published, see `publish()`
+pub async fn process_probably_changed_includes(
+	zone_name: &str,
+	config_zone: &config::Zone,
+	changes: Changes,
+	reload_queue: &reloader::Queue,
+	notifier: Option<&webhook::Handle>,
+	force_write: bool,
+	pool: &Pool<Sqlite>,
+) -> Result<Changes> {
+	let result = publish(
+		zone_name,
+		config_zone,
+		changes,
+		reload_queue,
+		notifier,
+		force_write,
+		pool,
+	)
+	.await;
+	if let Err(e) = &result {
+		let context = reloader::ProgramContext::from_zone_name(zone_name);
+		hooks::run_on_failure(&config_zone.hooks.on_failure, &context, e).await;
+	}
+	result
 }
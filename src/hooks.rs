@@ -0,0 +1,156 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Run the programs configured to be notified at certain points while publishing a zone.
+// They get the same placeholders and environment variables as the reload program.
+
+use crate::config::{FailurePolicy, Hook};
+use crate::reloader::{ProgramContext, run_program};
+use color_eyre::eyre::{Report, Result, eyre};
+use log::{debug, info, warn};
+use tokio::process::Command;
+use tokio::time::timeout;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+	PrePublish,
+	PostCommit,
+	PostReload,
+	OnFailure,
+}
+
+impl Event {
+	const fn name(self) -> &'static str {
+		match self {
+			Self::PrePublish => "pre_publish",
+			Self::PostCommit => "post_commit",
+			Self::PostReload => "post_reload",
+			Self::OnFailure => "on_failure",
+		}
+	}
+}
+
+async fn run_one(
+	hook: &Hook,
+	event: Event,
+	context: &ProgramContext,
+	error: Option<&str>,
+) -> Result<()> {
+	let args = context.expand_args(&hook.args);
+	info!(
+		"Running {} hook for zone {}: `{} {}`",
+		event.name(),
+		context.zone(),
+		hook.program.display(),
+		args.join(" ")
+	);
+
+	let mut command = Command::new(&hook.program);
+	command.args(&args).env("ZONEWATCH_HOOK", event.name());
+	context.set_env(&mut command);
+	if let Some(error) = error {
+		command.env("ZONEWATCH_ERROR", error);
+	}
+	// Dropping the future on timeout also kills the child process
+	timeout(hook.timeout, run_program(command))
+		.await
+		.unwrap_or_else(|_| Err(eyre!("Did not finish within {:?}", hook.timeout)))
+}
+
+async fn run_all(
+	hooks: &[Hook],
+	event: Event,
+	context: &ProgramContext,
+	error: Option<&str>,
+) -> Result<()> {
+	for hook in hooks {
+		let Err(e) = run_one(hook, event, context, error).await else {
+			continue;
+		};
+		let e = e.wrap_err(format!(
+			"The {} hook `{}` of zone {} failed",
+			event.name(),
+			hook.program.display(),
+			context.zone()
+		));
+		match hook.failure_policy {
+			FailurePolicy::Ignore => debug!("{e:#}"),
+			FailurePolicy::Warn => warn!("{e:?}"),
+			FailurePolicy::Abort => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+// Run the hooks one after the other.
+// If a hook with the abort policy fails, the remaining hooks are skipped and an error is returned.
+pub async fn run(hooks: &[Hook], event: Event, context: &ProgramContext) -> Result<()> {
+	run_all(hooks, event, context, None).await
+}
+
+// Run the hooks notifying about a failure, which is passed in `ZONEWATCH_ERROR`
+pub async fn run_on_failure(hooks: &[Hook], context: &ProgramContext, error: &Report) {
+	let error = format!("{error:#}");
+	// The configuration does not allow these hooks to abort
+	let _ = run_all(hooks, Event::OnFailure, context, Some(&error)).await;
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::{FailurePolicy, Hook};
+	use crate::hooks::{Event, run, run_on_failure};
+	use crate::reloader::ProgramContext;
+	use crate::test_util::TempDir;
+	use color_eyre::eyre::eyre;
+	use std::path::PathBuf;
+	use tokio::time::Duration;
+
+	fn hook(script: &str, failure_policy: FailurePolicy) -> Hook {
+		Hook {
+			program: PathBuf::from("sh"),
+			args: vec!["-c".to_string(), script.to_string()],
+			failure_policy,
+			timeout: Duration::from_secs(10),
+		}
+	}
+
+	#[tokio::test]
+	async fn check_failure_policy() {
+		let temp_dir = TempDir::new("hooks");
+		let dir = temp_dir.path();
+		let output = dir.join("output");
+		let record = format!(
+			"echo \"$ZONEWATCH_HOOK {{zone}} $ZONEWATCH_ERROR\" >> '{}'",
+			output.display()
+		);
+		let context = ProgramContext::from_zone_name("example.org");
+
+		let hooks = [
+			hook("false", FailurePolicy::Ignore),
+			hook("false", FailurePolicy::Warn),
+			hook(&record, FailurePolicy::Abort),
+		];
+		run(&hooks, Event::PostCommit, &context)
+			.await
+			.expect("only ignored and warned about failures");
+
+		let hooks = [
+			hook("false", FailurePolicy::Abort),
+			hook(&record, FailurePolicy::Warn),
+		];
+		assert!(run(&hooks, Event::PrePublish, &context).await.is_err());
+
+		run_on_failure(
+			&[hook(&record, FailurePolicy::Warn)],
+			&context,
+			&eyre!("oops"),
+		)
+		.await;
+
+		// The hook after the aborting one was never run
+		assert_eq!(
+			std::fs::read_to_string(&output).expect("can read"),
+			"post_commit example.org \non_failure example.org oops\n"
+		);
+	}
+}
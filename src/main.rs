@@ -1,79 +1,189 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
 // SPDX-License-Identifier: GPL-3.0-only
 
+mod check_config;
 mod config;
+mod coordinator;
 mod db;
+mod domain_name;
 mod event_analyzer;
 mod event_processor;
+mod hooks;
 mod logging;
+mod master_file;
+mod orphans;
+mod powerdns;
 mod reloader;
+mod rndc;
+mod state;
+mod supervisor;
+#[cfg(test)]
+mod test_util;
 mod watcher;
+mod webhook;
 mod zone_file;
 
 use crate::config::Config;
-use crate::reloader::Reloader;
-use crate::watcher::watch;
-use clap::Parser;
-use color_eyre::eyre::{Result, WrapErr};
+use crate::supervisor::Supervisor;
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{Result, WrapErr, eyre};
 use log::info;
-use tokio::task::JoinSet;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Args {
 	/// Path to the config file
 	#[arg(short, long, default_value = "config.toml")]
-	config: std::path::PathBuf,
+	config: PathBuf,
 
 	/// Whether to only generate the initial zone file and then exit, mainly used for testing
 	#[arg(long, action)]
 	only_init: bool,
+
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Check the config file and the paths it refers to, report all problems and exit
+	CheckConfig,
+
+	/// Manage the database
+	Db {
+		#[command(subcommand)]
+		command: DbCommand,
+	},
+
+	/// Export or import the state of the zones, e.g. to move zonewatch to another host
+	State {
+		#[command(subcommand)]
+		command: StateCommand,
+	},
+
+	/// List or remove zones which are in the database but no longer in the config file
+	Orphans {
+		#[command(subcommand)]
+		command: OrphansCommand,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+	/// Migrate the database to the given schema version, e.g. to the one an older version of zonewatch knows before downgrading to it.
+	/// Versions before 5 cannot store paths which are not valid UTF-8, migrating to them fails while the database contains such paths
+	Migrate {
+		/// The schema version, the version of the newest file in the `migrations` directory of the older zonewatch
+		#[arg(long)]
+		to: i64,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum StateCommand {
+	/// Write the state of all zones in the database as a JSON document
+	Export {
+		/// Write it to this file instead of stdout
+		#[arg(short, long)]
+		output: Option<PathBuf>,
+	},
+
+	/// Replace the state of the zones in a JSON document written by `state export`, while zonewatch is not running
+	Import {
+		/// Only check the document and log what would change
+		#[arg(long, action)]
+		dry_run: bool,
+
+		/// The JSON document, `-` to read it from stdin
+		file: PathBuf,
+	},
 }
 
-#[tokio::main()]
-async fn main() -> Result<()> {
+#[derive(Subcommand, Debug)]
+enum OrphansCommand {
+	/// List the orphaned zones
+	List,
+
+	/// Remove orphaned zones from the database and delete their zone files
+	Purge {
+		/// Move them into the archive tables and rename their zone files instead
+		#[arg(long, action)]
+		archive: bool,
+
+		/// The zones to remove, all orphaned zones if none are given
+		zones: Vec<String>,
+	},
+}
+
+fn main() -> Result<()> {
 	color_eyre::install()?;
 
 	logging::setup();
 
 	let args = Args::parse();
 
-	let config = Config::read(&args.config).wrap_err("Cannot read config file")?;
+	let runtime = tokio::runtime::Runtime::new().wrap_err("Cannot start the async runtime")?;
+	let result = runtime.block_on(run(args));
+	// Don't wait for threads which are stuck hashing a file on a hung file system
+	runtime.shutdown_background();
+	result
+}
 
-	let pool = db::init(&config.db).await?;
+async fn run(args: Args) -> Result<()> {
+	if matches!(args.command, Some(Command::CheckConfig)) {
+		return check_config::run(&args.config);
+	}
 
-	let mut set = JoinSet::new();
+	let config = Config::read(&args.config).wrap_err("Cannot read config file")?;
 
-	for (origin, zone) in config.zones {
-		info!("Starting task for zone {origin}");
-		// TODO: find a way to pass these variables without .clone()
-		let pool_for_thread = pool.clone();
-		let nix_dir = config.nix_dir.clone();
-		let reloader = Reloader {
-			zone_name: origin.clone(),
-			bin: config.reload_program_bin.clone(),
-			args: zone.reload_program_args.clone(),
+	if let Some(Command::Db {
+		command: DbCommand::Migrate { to },
+	}) = args.command
+	{
+		let Some(path) = &config.db else {
+			return Err(eyre!("`db` is not set, there is no database to migrate"));
 		};
-		let only_init = args.only_init;
-		set.spawn(async move {
-			info!("Task for zone {origin} started");
-			watch(
-				pool_for_thread,
-				&nix_dir,
-				&origin,
-				zone,
-				reloader,
-				only_init,
-			)
-			.await
-			.wrap_err_with(|| format!("While watching zone `{origin}`"))
-		});
+		return db::migrate_to(path, to).await;
 	}
 
-	while let Some(res) = set.join_next().await {
-		let inner_res = res.wrap_err("While joining all tasks")?;
-		inner_res?;
+	let pool = if let Some(path) = &config.db {
+		db::init(path).await?
+	} else {
+		if matches!(
+			args.command,
+			Some(Command::Orphans { .. } | Command::State { .. })
+		) {
+			return Err(eyre!("This command needs a database, but `db` is not set"));
+		}
+		info!("No database configured, recovering the state of the zones from their zone files");
+		db::init_in_memory().await?
+	};
+
+	match args.command {
+		Some(Command::Orphans {
+			command: OrphansCommand::List,
+		}) => return orphans::list(&pool, &config).await,
+		Some(Command::Orphans {
+			command: OrphansCommand::Purge { archive, zones },
+		}) => return orphans::purge(&pool, &config, &zones, archive).await,
+		Some(Command::State {
+			command: StateCommand::Export { output },
+		}) => return state::export(&pool, output.as_deref()).await,
+		Some(Command::State {
+			command: StateCommand::Import { dry_run, file },
+		}) => return state::import(&pool, &config, &file, dry_run).await,
+		_ => {}
 	}
 
-	Ok(())
+	orphans::apply_policy(&pool, &config)
+		.await
+		.wrap_err("Cannot clean up orphaned zones")?;
+
+	let supervisor = Supervisor::start(pool, config, args.only_init);
+	if args.only_init {
+		supervisor.wait().await
+	} else {
+		supervisor.run(&args.config).await
+	}
 }
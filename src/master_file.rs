@@ -0,0 +1,400 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// A small parser for the records in the included files.
+// It only understands as much of the master file format (RFC 1035 section 5) as is needed
+// to hand the records to a DNS server through an API. The record data is passed on as text.
+
+use std::collections::BTreeMap;
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum ParseError {
+	#[error("Line {line}: unbalanced parentheses")]
+	UnbalancedParentheses { line: usize },
+
+	#[error("Line {line}: unterminated quoted string")]
+	UnterminatedString { line: usize },
+
+	#[error("Line {line}: the directive `{directive}` is not supported")]
+	UnsupportedDirective { line: usize, directive: String },
+
+	#[error("Line {line}: missing argument for `{directive}`")]
+	MissingArgument { line: usize, directive: String },
+
+	#[error("Line {line}: invalid TTL `{ttl}`")]
+	InvalidTtl { line: usize, ttl: String },
+
+	#[error("Line {line}: the record has no owner name and there is no previous one")]
+	MissingOwner { line: usize },
+
+	#[error("Line {line}: the record has no type")]
+	MissingType { line: usize },
+
+	#[error("Line {line}: only the class IN is supported, not `{class}`")]
+	UnsupportedClass { line: usize, class: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+	pub name: String,
+	pub ttl: u32,
+	pub rtype: String,
+	pub rdata: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rrset {
+	pub ttl: u32,
+	pub records: Vec<String>,
+}
+
+// Parse a TTL like `3600`, `1d` or `1h30m` (units as understood by BIND)
+pub fn parse_ttl(s: &str) -> Option<u32> {
+	if s.is_empty() {
+		return None;
+	}
+	if s.bytes().all(|b| b.is_ascii_digit()) {
+		return s.parse().ok();
+	}
+
+	let mut total: u32 = 0;
+	let mut number: Option<u32> = None;
+	for c in s.chars() {
+		if let Some(digit) = c.to_digit(10) {
+			number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+			continue;
+		}
+		let factor = match c.to_ascii_lowercase() {
+			'w' => 7 * 24 * 60 * 60,
+			'd' => 24 * 60 * 60,
+			'h' => 60 * 60,
+			'm' => 60,
+			's' => 1,
+			_ => return None,
+		};
+		total = total.checked_add(number.take()?.checked_mul(factor)?)?;
+	}
+	if number.is_some() {
+		// Trailing digits without a unit
+		return None;
+	}
+	Some(total)
+}
+
+struct Line {
+	number: usize,
+	// Whether the line started with whitespace, meaning the owner is the same as in the previous record
+	inherits_owner: bool,
+	tokens: Vec<String>,
+}
+
+fn split_lines(contents: &str) -> Result<Vec<Line>, ParseError> {
+	let mut lines = Vec::new();
+	let mut tokens = Vec::new();
+	let mut token = String::new();
+	let mut depth: usize = 0;
+	let mut line_number = 1;
+	let mut start_line = 1;
+	let mut inherits_owner = false;
+	let mut at_line_start = true;
+
+	let mut chars = contents.chars().peekable();
+	while let Some(c) = chars.next() {
+		if at_line_start {
+			inherits_owner = c == ' ' || c == '\t';
+			start_line = line_number;
+			at_line_start = false;
+		}
+		match c {
+			'\\' => {
+				token.push(c);
+				if let Some(escaped) = chars.next() {
+					token.push(escaped);
+				}
+			}
+			'"' => {
+				token.push(c);
+				loop {
+					match chars.next() {
+						None => {
+							return Err(ParseError::UnterminatedString { line: line_number });
+						}
+						Some('\\') => {
+							token.push('\\');
+							if let Some(escaped) = chars.next() {
+								token.push(escaped);
+							}
+						}
+						Some('"') => {
+							token.push('"');
+							break;
+						}
+						Some(c) => {
+							if c == '\n' {
+								line_number += 1;
+							}
+							token.push(c);
+						}
+					}
+				}
+			}
+			';' => while chars.next_if(|&c| c != '\n').is_some() {},
+			'(' | ')' | '\n' | ' ' | '\t' | '\r' => {
+				if !token.is_empty() {
+					tokens.push(std::mem::take(&mut token));
+				}
+				match c {
+					'(' => depth += 1,
+					')' => {
+						depth = depth
+							.checked_sub(1)
+							.ok_or(ParseError::UnbalancedParentheses { line: line_number })?;
+					}
+					'\n' => {
+						line_number += 1;
+						if depth == 0 {
+							if !tokens.is_empty() {
+								lines.push(Line {
+									number: start_line,
+									inherits_owner,
+									tokens: std::mem::take(&mut tokens),
+								});
+							}
+							at_line_start = true;
+						}
+					}
+					_ => {}
+				}
+			}
+			c => token.push(c),
+		}
+	}
+	if depth != 0 {
+		return Err(ParseError::UnbalancedParentheses { line: line_number });
+	}
+	if !token.is_empty() {
+		tokens.push(token);
+	}
+	if !tokens.is_empty() {
+		lines.push(Line {
+			number: start_line,
+			inherits_owner,
+			tokens,
+		});
+	}
+	Ok(lines)
+}
+
+fn is_absolute(name: &str) -> bool {
+	// A trailing dot only makes the name absolute if it's not escaped
+	let trailing_backslashes = name
+		.trim_end_matches('.')
+		.chars()
+		.rev()
+		.take_while(|&c| c == '\\')
+		.count();
+	name.ends_with('.') && trailing_backslashes % 2 == 0
+}
+
+fn absolute_name(name: &str, origin: &str) -> String {
+	if name == "@" {
+		origin.to_string()
+	} else if is_absolute(name) {
+		name.to_string()
+	} else if origin == "." {
+		format!("{name}.")
+	} else {
+		format!("{name}.{origin}")
+	}
+}
+
+// The positions of domain names in the record data of common types,
+// which need to be made absolute before handing them to anyone else
+fn name_positions(rtype: &str) -> &'static [usize] {
+	match rtype {
+		"NS" | "CNAME" | "DNAME" | "PTR" => &[0],
+		"MX" | "AFSDB" | "RT" | "KX" => &[1],
+		"SRV" => &[3],
+		"RP" => &[0, 1],
+		_ => &[],
+	}
+}
+
+fn is_class(token: &str) -> bool {
+	let upper = token.to_ascii_uppercase();
+	matches!(upper.as_str(), "IN" | "CH" | "CS" | "HS")
+		|| upper
+			.strip_prefix("CLASS")
+			.is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Parse the records of a file which is included into the zone `origin` (given with a trailing dot)
+pub fn parse(contents: &str, origin: &str, default_ttl: u32) -> Result<Vec<Record>, ParseError> {
+	let mut origin = origin.to_string();
+	let mut default_ttl = default_ttl;
+	let mut previous_owner: Option<String> = None;
+	let mut records = Vec::new();
+
+	for line in split_lines(contents)? {
+		let number = line.number;
+		let mut tokens = line.tokens.into_iter().peekable();
+
+		if !line.inherits_owner
+			&& let Some(directive) = tokens.next_if(|token| token.starts_with('$'))
+		{
+			let directive = directive.to_ascii_uppercase();
+			let argument = tokens.next().ok_or_else(|| ParseError::MissingArgument {
+				line: number,
+				directive: directive.clone(),
+			})?;
+			match directive.as_str() {
+				"$ORIGIN" => origin = absolute_name(&argument, &origin),
+				"$TTL" => {
+					default_ttl = parse_ttl(&argument).ok_or(ParseError::InvalidTtl {
+						line: number,
+						ttl: argument,
+					})?;
+				}
+				_ => {
+					return Err(ParseError::UnsupportedDirective {
+						line: number,
+						directive,
+					});
+				}
+			}
+			continue;
+		}
+
+		let owner = if line.inherits_owner {
+			previous_owner
+				.clone()
+				.ok_or(ParseError::MissingOwner { line: number })?
+		} else {
+			let name = tokens
+				.next()
+				.ok_or(ParseError::MissingOwner { line: number })?;
+			absolute_name(&name, &origin).to_ascii_lowercase()
+		};
+		previous_owner = Some(owner.clone());
+
+		let mut ttl = default_ttl;
+		let rtype = loop {
+			let token = tokens
+				.next()
+				.ok_or(ParseError::MissingType { line: number })?;
+			if let Some(explicit_ttl) = parse_ttl(&token) {
+				ttl = explicit_ttl;
+			} else if is_class(&token) {
+				if !token.eq_ignore_ascii_case("IN") {
+					return Err(ParseError::UnsupportedClass {
+						line: number,
+						class: token,
+					});
+				}
+			} else {
+				break token.to_ascii_uppercase();
+			}
+		};
+
+		let positions = name_positions(&rtype);
+		let rdata: Vec<String> = tokens
+			.enumerate()
+			.map(|(i, token)| {
+				if positions.contains(&i) {
+					absolute_name(&token, &origin)
+				} else {
+					token
+				}
+			})
+			.collect();
+
+		records.push(Record {
+			name: owner,
+			ttl,
+			rtype,
+			rdata: rdata.join(" "),
+		});
+	}
+
+	Ok(records)
+}
+
+// Group records with the same name and type into sets.
+// The first TTL wins since all records in a set must have the same TTL.
+pub fn group(records: Vec<Record>) -> BTreeMap<(String, String), Rrset> {
+	let mut rrsets: BTreeMap<(String, String), Rrset> = BTreeMap::new();
+	for record in records {
+		let rrset = rrsets
+			.entry((record.name, record.rtype))
+			.or_insert_with(|| Rrset {
+				ttl: record.ttl,
+				records: Vec::new(),
+			});
+		if !rrset.records.contains(&record.rdata) {
+			rrset.records.push(record.rdata);
+		}
+	}
+	rrsets
+}
+
+#[cfg(test)]
+mod test {
+	use crate::master_file::{ParseError, Record, parse, parse_ttl};
+
+	#[test]
+	fn check_parse_ttl() {
+		assert_eq!(parse_ttl("3600"), Some(3600));
+		assert_eq!(parse_ttl("1d"), Some(86400));
+		assert_eq!(parse_ttl("1H30m"), Some(5400));
+		assert_eq!(parse_ttl("1w2d3h4m5s"), Some(788_645));
+		assert_eq!(parse_ttl(""), None);
+		assert_eq!(parse_ttl("1x"), None);
+		assert_eq!(parse_ttl("1h30"), None);
+		assert_eq!(parse_ttl("IN"), None);
+		assert_eq!(parse_ttl("99999999999"), None);
+	}
+
+	#[test]
+	fn check_parse() {
+		let contents = indoc::indoc! {r#"
+			@  IN NS   ns.example.org.
+			ns IN A    127.0.0.1 ; comment
+			   IN AAAA ::1
+			$TTL 1h
+			@  MX ( 10
+			        mail )
+			txt 300 IN TXT "a ; b" "c\"d"
+			$ORIGIN sub.example.org.
+			www CNAME @
+		"#};
+		let record = |name: &str, ttl, rtype: &str, rdata: &str| Record {
+			name: name.to_string(),
+			ttl,
+			rtype: rtype.to_string(),
+			rdata: rdata.to_string(),
+		};
+		assert_eq!(
+			parse(contents, "example.org.", 86400),
+			Ok(vec![
+				record("example.org.", 86400, "NS", "ns.example.org."),
+				record("ns.example.org.", 86400, "A", "127.0.0.1"),
+				record("ns.example.org.", 86400, "AAAA", "::1"),
+				record("example.org.", 3600, "MX", "10 mail.example.org."),
+				record("txt.example.org.", 300, "TXT", r#""a ; b" "c\"d""#),
+				record("www.sub.example.org.", 3600, "CNAME", "sub.example.org."),
+			])
+		);
+
+		assert_eq!(
+			parse("$INCLUDE other.zone", "example.org.", 86400),
+			Err(ParseError::UnsupportedDirective {
+				line: 1,
+				directive: "$INCLUDE".to_string()
+			})
+		);
+		assert_eq!(
+			parse("a IN A (\n1.2.3.4", "example.org.", 86400),
+			Err(ParseError::UnbalancedParentheses { line: 2 })
+		);
+	}
+}
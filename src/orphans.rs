@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Zones which are still in the database but no longer in the config file.
+// Depending on the `orphan_policy` they are kept, archived or deleted at startup
+// and they can be listed and purged manually.
+
+use crate::config::{Config, OrphanPolicy};
+use crate::db::{self, StoredZone};
+use crate::domain_name::normalize_origin;
+use crate::zone_file;
+use color_eyre::eyre::{Result, WrapErr, eyre};
+use log::{info, warn};
+use sqlx::{Pool, Sqlite};
+use std::{fs, io::ErrorKind, path::Path};
+
+pub async fn find(pool: &Pool<Sqlite>, config: &Config) -> Result<Vec<StoredZone>> {
+	let mut tx = pool.begin().await?;
+	let zones = db::list_zones(&mut tx).await?;
+	tx.commit().await?;
+	Ok(zones
+		.into_iter()
+		.filter(|zone| !config.zones.contains_key(&zone.name))
+		.collect())
+}
+
+// Treats a zone file which is already gone as success
+fn remove_file(path: &Path) -> Result<()> {
+	match fs::remove_file(path) {
+		Err(e) if e.kind() != ErrorKind::NotFound => {
+			Err(e).wrap_err_with(|| format!("Cannot delete zone file `{}`", path.display()))
+		}
+		_ => Ok(()),
+	}
+}
+
+fn archive_file(path: &Path) -> Result<()> {
+	let mut archived = path.as_os_str().to_owned();
+	archived.push(".archived");
+	match fs::rename(path, &archived) {
+		Err(e) if e.kind() != ErrorKind::NotFound => Err(e).wrap_err_with(|| {
+			format!(
+				"Cannot rename zone file `{}` to `{}`",
+				path.display(),
+				archived.display()
+			)
+		}),
+		_ => Ok(()),
+	}
+}
+
+// Remove the orphaned zone from the database, keeping a copy in the archive tables if `archive` is set,
+// and then delete or rename its zone file
+pub async fn remove(pool: &Pool<Sqlite>, zone: &StoredZone, archive: bool) -> Result<()> {
+	let mut tx = pool.begin().await?;
+	if archive {
+		db::archive_zone(&zone.name, &mut tx).await?;
+	} else {
+		db::delete_zone(&zone.name, &mut tx).await?;
+	}
+	tx.commit().await?;
+
+	let path = zone_file::file_path(&zone.dir, &zone.name);
+	if archive {
+		archive_file(&path)?;
+		info!(
+			"Archived orphaned zone {} (serial {})",
+			zone.name, zone.serial
+		);
+	} else {
+		remove_file(&path)?;
+		info!(
+			"Deleted orphaned zone {} (serial {})",
+			zone.name, zone.serial
+		);
+	}
+	Ok(())
+}
+
+// Zones stored before their names were normalized, e.g. in upper case, would otherwise be orphaned
+// and start over with the initial serial. They are renamed together with their zone file instead.
+async fn rename_unnormalized(pool: &Pool<Sqlite>, config: &Config) -> Result<()> {
+	let mut tx = pool.begin().await?;
+	let zones = db::list_zones(&mut tx).await?;
+	tx.commit().await?;
+	for zone in &zones {
+		let Ok(name) = normalize_origin(&zone.name) else {
+			continue;
+		};
+		if name == zone.name || !config.zones.contains_key(&name) {
+			continue;
+		}
+		if zones.iter().any(|other| other.name == name) {
+			return Err(eyre!(
+				"Zone {} in the database is called {name} after normalizing its name, but zone {name} is also in the database. \
+				Remove one of them with `zonewatch orphans purge` and start again",
+				zone.name
+			));
+		}
+
+		let mut tx = pool.begin().await?;
+		db::rename_zone(&zone.name, &name, &mut tx).await?;
+		tx.commit().await?;
+
+		let old_path = zone_file::file_path(&zone.dir, &zone.name);
+		let new_path = zone_file::file_path(&zone.dir, &name);
+		match fs::rename(&old_path, &new_path) {
+			Err(e) if e.kind() != ErrorKind::NotFound => {
+				return Err(e).wrap_err_with(|| {
+					format!(
+						"Cannot rename zone file `{}` to `{}`",
+						old_path.display(),
+						new_path.display()
+					)
+				});
+			}
+			_ => {}
+		}
+		info!("Renamed zone {} to its normalized name {name}", zone.name);
+	}
+	Ok(())
+}
+
+// Called at startup, before any zone is started
+pub async fn apply_policy(pool: &Pool<Sqlite>, config: &Config) -> Result<()> {
+	rename_unnormalized(pool, config).await?;
+	for zone in find(pool, config).await? {
+		match config.orphan_policy {
+			OrphanPolicy::Keep => warn!(
+				"Zone {} is no longer in the config file, it continues with serial {} if it is added again",
+				zone.name, zone.serial
+			),
+			OrphanPolicy::Archive => remove(pool, &zone, true).await?,
+			OrphanPolicy::Delete => remove(pool, &zone, false).await?,
+		}
+	}
+	Ok(())
+}
+
+pub async fn list(pool: &Pool<Sqlite>, config: &Config) -> Result<()> {
+	let orphans = find(pool, config).await?;
+	if orphans.is_empty() {
+		info!("There are no orphaned zones");
+	}
+	for zone in orphans {
+		info!(
+			"Orphaned zone {} (serial {}, zone file `{}`)",
+			zone.name,
+			zone.serial,
+			zone_file::file_path(&zone.dir, &zone.name).display()
+		);
+	}
+	Ok(())
+}
+
+// Purge the given orphaned zones or all of them if `names` is empty
+pub async fn purge(
+	pool: &Pool<Sqlite>,
+	config: &Config,
+	names: &[String],
+	archive: bool,
+) -> Result<()> {
+	let orphans = find(pool, config).await?;
+	for name in names {
+		if config.zones.contains_key(name) {
+			return Err(eyre!("Zone {name} is still in the config file"));
+		}
+		if !orphans.iter().any(|zone| &zone.name == name) {
+			return Err(eyre!("Zone {name} is not in the database"));
+		}
+	}
+	for zone in &orphans {
+		if names.is_empty() || names.contains(&zone.name) {
+			remove(pool, zone, archive).await?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::Config;
+	use crate::db;
+	use crate::orphans::apply_policy;
+	use crate::test_util::{TempDir, test_zone};
+	use crate::zone_file;
+
+	#[tokio::test]
+	async fn check_rename_unnormalized() {
+		let temp_dir = TempDir::new("orphans-rename");
+		let dir = temp_dir.path();
+		let pool = db::init_in_memory().await.expect("can create database");
+		let mut zone = test_zone("Example.ORG", dir);
+		zone.soa.serial = 5;
+		let mut tx = pool.begin().await.expect("can begin");
+		db::write_state(&zone, &mut tx).await.expect("can write");
+		tx.commit().await.expect("can commit");
+		std::fs::write(zone.file_path(), "").expect("can write");
+
+		let config = Config::parse(&format!(
+			r#"
+				orphan_policy = "delete"
+				reload_program_bin = "true"
+				[zones."Example.ORG"]
+				dir = "{}"
+				reload_program_args = []
+				ttl = "1d"
+				includes = []
+				soa = {{ mname = "ns1.example.org.", rname = "john.doe@example.org", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2h", expire = "1000h", minimum = "1h" }}
+			"#,
+			dir.display()
+		))
+		.expect("the config is valid");
+		apply_policy(&pool, &config).await.expect("can rename");
+
+		// The zone continues with its serial instead of being deleted as an orphan
+		let mut tx = pool.begin().await.expect("can begin");
+		let renamed = db::read_zone("example.org", &mut tx)
+			.await
+			.expect("can read")
+			.expect("the zone was renamed");
+		assert_eq!(renamed.soa.serial, 5);
+		assert!(zone_file::file_path(dir, "example.org").exists());
+		assert!(!zone.file_path().exists());
+	}
+}
@@ -0,0 +1,407 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Publish a zone through the HTTP API of PowerDNS Authoritative.
+// The records of all readable includes are parsed and compared with what the server currently has.
+// Only the sets of records which differ are replaced or deleted.
+
+use crate::master_file;
+use crate::zone_file;
+use color_eyre::{
+	Help, SectionExt,
+	eyre::{Result, WrapErr, eyre},
+};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Client {
+	// Base URL of the API like `http://127.0.0.1:8081`
+	pub url: String,
+	pub server_id: String,
+	pub api_key_file: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Record {
+	content: String,
+	#[serde(default)]
+	disabled: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Rrset {
+	name: String,
+	#[serde(rename = "type")]
+	rtype: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	ttl: Option<u32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	changetype: Option<String>,
+	#[serde(default)]
+	records: Vec<Record>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Rrsets {
+	rrsets: Vec<Rrset>,
+}
+
+type DesiredRrsets = BTreeMap<(String, String), master_file::Rrset>;
+
+fn seconds(zone_name: &str, field: &str, value: &str) -> Result<u32> {
+	master_file::parse_ttl(value)
+		.ok_or_else(|| eyre!("The {field} `{value}` of zone {zone_name} is not a valid duration"))
+}
+
+// Everything the server should serve for this zone
+async fn desired_rrsets(zone: &zone_file::Zone) -> Result<DesiredRrsets> {
+	let origin = format!("{}.", zone.name);
+	let default_ttl = seconds(&zone.name, "TTL", &zone.ttl)?;
+
+	let mut records = Vec::new();
+	for path in &zone.includes_ordered {
+		// Files which cannot be read are also left out of the zone file
+		if !matches!(
+			zone.includes.get(path),
+			Some(zone_file::Include::Readable(_))
+		) {
+			continue;
+		}
+		let contents = tokio::fs::read_to_string(path)
+			.await
+			.wrap_err_with(|| format!("Cannot read included file `{}`", path.display()))?;
+		let parsed = master_file::parse(&contents, &origin, default_ttl)
+			.wrap_err_with(|| format!("Cannot parse included file `{}`", path.display()))?;
+		for record in parsed {
+			if record.rtype == "SOA" {
+				warn!(
+					"Ignoring SOA record in file {} included in zone {}",
+					path.display(),
+					zone.name
+				);
+			} else {
+				records.push(record);
+			}
+		}
+	}
+
+	let soa = &zone.soa;
+	let soa_content = format!(
+		"{} {} {} {} {} {} {}",
+		soa.mname,
+		soa.rname,
+		soa.serial,
+		seconds(&zone.name, "SOA refresh", &soa.refresh)?,
+		seconds(&zone.name, "SOA retry", &soa.retry)?,
+		seconds(&zone.name, "SOA expire", &soa.expire)?,
+		seconds(&zone.name, "SOA minimum", &soa.minimum)?,
+	);
+	records.push(master_file::Record {
+		name: origin,
+		ttl: seconds(&zone.name, "SOA TTL", &soa.ttl)?,
+		rtype: "SOA".to_string(),
+		rdata: soa_content,
+	});
+
+	Ok(master_file::group(records))
+}
+
+// Compute the changes needed to turn `current` into `desired`
+fn diff(current: &[Rrset], desired: &DesiredRrsets) -> Vec<Rrset> {
+	let current: BTreeMap<(String, String), &Rrset> = current
+		.iter()
+		.map(|rrset| {
+			(
+				(rrset.name.to_ascii_lowercase(), rrset.rtype.clone()),
+				rrset,
+			)
+		})
+		.collect();
+
+	let mut changes = Vec::new();
+	for ((name, rtype), rrset) in desired {
+		let unchanged = current
+			.get(&(name.clone(), rtype.clone()))
+			.is_some_and(|old| {
+				let old_records: BTreeSet<&str> = old
+					.records
+					.iter()
+					.filter(|record| !record.disabled)
+					.map(|record| record.content.as_str())
+					.collect();
+				let new_records: BTreeSet<&str> =
+					rrset.records.iter().map(String::as_str).collect();
+				old.ttl == Some(rrset.ttl) && old_records == new_records
+			});
+		if !unchanged {
+			changes.push(Rrset {
+				name: name.clone(),
+				rtype: rtype.clone(),
+				ttl: Some(rrset.ttl),
+				changetype: Some("REPLACE".to_string()),
+				records: rrset
+					.records
+					.iter()
+					.map(|content| Record {
+						content: content.clone(),
+						disabled: false,
+					})
+					.collect(),
+			});
+		}
+	}
+	for (name, rtype) in current.keys() {
+		if !desired.contains_key(&(name.clone(), rtype.clone())) {
+			changes.push(Rrset {
+				name: name.clone(),
+				rtype: rtype.clone(),
+				ttl: None,
+				changetype: Some("DELETE".to_string()),
+				records: Vec::new(),
+			});
+		}
+	}
+	changes
+}
+
+async fn error_for_status(response: reqwest::Response, action: &str) -> Result<reqwest::Response> {
+	let status = response.status();
+	if status.is_success() {
+		return Ok(response);
+	}
+	let body = response.text().await.unwrap_or_default();
+	Err(eyre!(
+		"The server responded with status {status} while {action}"
+	))
+	.with_section(move || body.trim().to_string().header("Response:"))
+}
+
+// The id of a zone in the URLs of the API, built like `apiZoneNameToId()` of PowerDNS.
+// Everything except letters, digits, `.` and `-` is written as `=XX`, e.g. `/` in classless
+// reverse zones (RFC 2317) as `=2F`, so that the id stays a single segment of the path.
+fn zone_id(zone_name: &str) -> String {
+	format!("{zone_name}.")
+		.bytes()
+		.fold(String::new(), |mut id, byte| {
+			if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-') {
+				id.push(char::from(byte));
+			} else {
+				let escaped = format!("={byte:02X}");
+				id.push_str(&escaped);
+			}
+			id
+		})
+}
+
+impl Client {
+	fn zone_url(&self, zone_name: &str) -> String {
+		format!(
+			"{}/api/v1/servers/{}/zones/{}",
+			self.url.trim_end_matches('/'),
+			self.server_id,
+			zone_id(zone_name)
+		)
+	}
+
+	pub async fn publish(&self, zone: &zone_file::Zone) -> Result<()> {
+		let desired = desired_rrsets(zone).await?;
+		let api_key = tokio::fs::read_to_string(&self.api_key_file)
+			.await
+			.wrap_err_with(|| {
+				format!("Cannot read API key file `{}`", self.api_key_file.display())
+			})?;
+		let api_key = api_key.trim();
+		let http = reqwest::Client::new();
+		let url = self.zone_url(&zone.name);
+
+		let response = http
+			.get(&url)
+			.header("X-API-Key", api_key)
+			.send()
+			.await
+			.wrap_err_with(|| format!("Cannot request zone from {url}"))?;
+		let current: Rrsets = error_for_status(response, "requesting the zone")
+			.await?
+			.json()
+			.await
+			.wrap_err("Cannot decode the zone sent by the server")?;
+
+		let changes = diff(&current.rrsets, &desired);
+		if changes.is_empty() {
+			info!(
+				"The server already serves the current version of zone {}",
+				zone.name
+			);
+			return Ok(());
+		}
+		debug!(
+			"Replacing or deleting {} record set(s) of zone {}",
+			changes.len(),
+			zone.name
+		);
+
+		let response = http
+			.patch(&url)
+			.header("X-API-Key", api_key)
+			.json(&Rrsets { rrsets: changes })
+			.send()
+			.await
+			.wrap_err_with(|| format!("Cannot send changes to {url}"))?;
+		error_for_status(response, "updating the zone").await?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::powerdns::{Client, Rrsets};
+	use crate::test_util::{TempDir, test_zone};
+	use crate::zone_file;
+	use std::path::PathBuf;
+	use std::sync::{Arc, Mutex};
+	use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+	use tokio::net::TcpListener;
+
+	// Answer a single HTTP request and return the request line and body
+	async fn serve_one(listener: &TcpListener, status: &str, body: &str) -> (String, String) {
+		let (stream, _) = listener.accept().await.expect("can accept");
+		let mut stream = BufReader::new(stream);
+		let mut request_line = String::new();
+		stream.read_line(&mut request_line).await.expect("can read");
+		let mut content_length = 0;
+		loop {
+			let mut header = String::new();
+			stream.read_line(&mut header).await.expect("can read");
+			if header.trim().is_empty() {
+				break;
+			}
+			if let Some((name, value)) = header.split_once(':')
+				&& name.eq_ignore_ascii_case("content-length")
+			{
+				content_length = value.trim().parse().expect("valid length");
+			}
+		}
+		let mut request_body = vec![0; content_length];
+		stream
+			.read_exact(&mut request_body)
+			.await
+			.expect("can read");
+		let response = format!(
+			"HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+			body.len()
+		);
+		stream
+			.get_mut()
+			.write_all(response.as_bytes())
+			.await
+			.expect("can write");
+		(
+			request_line.trim().to_string(),
+			String::from_utf8(request_body).expect("valid UTF-8"),
+		)
+	}
+
+	#[tokio::test]
+	async fn check_publish() {
+		let temp_dir = TempDir::new("powerdns");
+		let dir = temp_dir.path();
+		let include = dir.join("records.zone");
+		std::fs::write(&include, "@ IN NS ns\nns IN A 192.0.2.1\n").expect("can write");
+		let api_key_file = dir.join("api-key");
+		std::fs::write(&api_key_file, "secret\n").expect("can write");
+
+		let mut zone = test_zone("example.org", dir);
+		// The same TTL as the unchanged A record on the server
+		zone.ttl = "1h".to_string();
+		zone.soa.serial = 2;
+		zone.includes.insert(
+			include.clone(),
+			zone_file::Include::Readable(blake3::hash(b"")),
+		);
+		zone.includes_ordered.push(include);
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+		let client = Client {
+			url: format!("http://{}", listener.local_addr().expect("has address")),
+			server_id: "localhost".to_string(),
+			api_key_file,
+		};
+
+		let requests = Arc::new(Mutex::new(Vec::new()));
+		let server_requests = requests.clone();
+		let server = tokio::spawn(async move {
+			let current = r#"{"rrsets": [
+				{"name": "ns.example.org.", "type": "A", "ttl": 3600, "records": [{"content": "192.0.2.1", "disabled": false}]},
+				{"name": "old.example.org.", "type": "A", "ttl": 3600, "records": [{"content": "192.0.2.2", "disabled": false}]}
+			]}"#;
+			for (status, body) in [("200 OK", current), ("204 No Content", "")] {
+				let request = serve_one(&listener, status, body).await;
+				server_requests.lock().expect("not poisoned").push(request);
+			}
+		});
+
+		client
+			.publish(&zone)
+			.await
+			.expect("publishing should succeed");
+		server.await.expect("server should not panic");
+
+		let requests = requests.lock().expect("not poisoned").clone();
+		assert_eq!(requests.len(), 2);
+		assert_eq!(
+			requests[1].0,
+			"PATCH /api/v1/servers/localhost/zones/example.org. HTTP/1.1"
+		);
+		let patch: Rrsets = serde_json::from_str(&requests[1].1).expect("valid JSON");
+		let changes: Vec<(&str, &str, Option<&str>)> = patch
+			.rrsets
+			.iter()
+			.map(|rrset| {
+				(
+					rrset.name.as_str(),
+					rrset.rtype.as_str(),
+					rrset.changetype.as_deref(),
+				)
+			})
+			.collect();
+		// The A record of ns.example.org. is unchanged and therefore not sent
+		assert_eq!(
+			changes,
+			vec![
+				("example.org.", "NS", Some("REPLACE")),
+				("example.org.", "SOA", Some("REPLACE")),
+				("old.example.org.", "A", Some("DELETE")),
+			]
+		);
+		assert_eq!(
+			patch.rrsets[1].records[0].content,
+			"ns1.example.org. john\\.doe.example.org. 2 86400 7200 3600000 3600"
+		);
+	}
+
+	#[test]
+	fn check_zone_url() {
+		let client = Client {
+			url: "http://127.0.0.1:8081/".to_string(),
+			server_id: "localhost".to_string(),
+			api_key_file: PathBuf::from("/run/secrets/api-key"),
+		};
+		assert_eq!(
+			client.zone_url("example.org"),
+			"http://127.0.0.1:8081/api/v1/servers/localhost/zones/example.org."
+		);
+		// Classless reverse zones (RFC 2317) contain a slash
+		assert_eq!(
+			client.zone_url("0/25.2.0.192.in-addr.arpa"),
+			"http://127.0.0.1:8081/api/v1/servers/localhost/zones/0=2F25.2.0.192.in-addr.arpa."
+		);
+		assert_eq!(
+			client.zone_url("_dmarc.example.org"),
+			"http://127.0.0.1:8081/api/v1/servers/localhost/zones/=5Fdmarc.example.org."
+		);
+	}
+}
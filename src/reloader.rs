@@ -1,50 +1,686 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
 // SPDX-License-Identifier: GPL-3.0-only
 
+use crate::config;
+use crate::coordinator;
+use crate::db;
+use crate::hooks::{self, Event};
+use crate::powerdns;
+use crate::rndc;
+use crate::webhook::{self, Notification};
+use crate::zone_file;
 use color_eyre::{
 	Help, SectionExt,
 	eyre::{Result, WrapErr, eyre},
 };
-use log::info;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use log::{debug, error, info, trace, warn};
+use sqlx::{Pool, Sqlite};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, MissedTickBehavior, interval_at};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Method {
+	Program { bin: PathBuf, args: Vec<String> },
+	Rndc(rndc::Client),
+	PowerDns(powerdns::Client),
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Reloader {
 	pub zone_name: String,
-	pub bin: PathBuf,
-	pub args: Vec<String>,
+	pub method: Method,
+	pub timeout: Duration,
+	pub retry: config::RetryPolicy,
 }
 
 impl Reloader {
-	pub fn execute(&self) -> Result<()> {
+	pub fn new(zone_name: &str, reload_program_bin: Option<&Path>, zone: &config::Zone) -> Self {
+		let method = match &zone.reload {
+			config::Reload::Program { bin, args } => Method::Program {
+				bin: bin
+					.as_deref()
+					.or(reload_program_bin)
+					.expect("The config validation ensures that the reload program is set")
+					.to_path_buf(),
+				args: args.clone(),
+			},
+			config::Reload::Rndc(rndc) => Method::Rndc(rndc::Client {
+				address: rndc::Address::parse(&rndc.address),
+				key_file: rndc.key_file.clone(),
+				key_name: rndc.key_name.clone(),
+			}),
+			config::Reload::PowerDns(powerdns) => Method::PowerDns(powerdns::Client {
+				url: powerdns.url.clone(),
+				server_id: powerdns.server_id.clone(),
+				api_key_file: powerdns.api_key_file.clone(),
+			}),
+		};
+		Self {
+			zone_name: zone_name.to_string(),
+			method,
+			timeout: zone.reload_timeout,
+			retry: zone.reload_retry,
+		}
+	}
+
+	async fn execute_once(
+		&self,
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+	) -> Result<()> {
+		match &self.method {
+			Method::Program { bin, args } => self.execute_program(bin, args, zone, old_zone).await,
+			Method::Rndc(client) => self.execute_rndc(client).await,
+			Method::PowerDns(client) => {
+				info!(
+					"Publishing zone {} through the PowerDNS API at {}",
+					self.zone_name, client.url
+				);
+				client.publish(zone).await
+			}
+		}
+	}
+
+	// `old_zone` is the version of the zone the DNS server knew about before, if known
+	pub async fn execute(
+		&self,
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+	) -> Result<()> {
+		with_retries(
+			&format!("reload zone {}", self.zone_name),
+			self.timeout,
+			self.retry,
+			|| self.execute_once(zone, old_zone),
+		)
+		.await
+	}
+
+	async fn execute_program(
+		&self,
+		bin: &Path,
+		args: &[String],
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+	) -> Result<()> {
+		let context = ProgramContext::new(zone, old_zone);
+		let args = context.expand_args(args);
+
 		info!(
 			"Reloading zone {} with command `{} {}`",
 			self.zone_name,
-			self.bin.display(),
-			self.args.join(" ")
+			bin.display(),
+			args.join(" ")
+		);
+
+		let mut command = Command::new(bin);
+		command.args(&args);
+		context.set_env(&mut command);
+		run_program(command).await
+	}
+
+	async fn execute_rndc(&self, client: &rndc::Client) -> Result<()> {
+		info!(
+			"Reloading zone {} through the control channel {}",
+			self.zone_name, client.address
 		);
 
-		let child = Command::new(&self.bin)
-			.args(&self.args)
-			.stdin(Stdio::null())
-			.stdout(Stdio::piped())
-			.stderr(Stdio::piped())
-			.spawn()
-			.wrap_err("Failed to spawn updater process")?;
+		let command = format!("reload {}", self.zone_name);
+		let text = client
+			.command(&command)
+			.await
+			.wrap_err_with(|| format!("Cannot send `{command}` to the control channel"))?;
+		debug!("Control channel responded to `{command}`: {}", text.trim());
+
+		Ok(())
+	}
+}
+
+// Try `attempt` until it succeeds or the attempts run out, waiting longer after every failure.
+// `what` completes sentences like "Attempt 1 of 3 to ... failed".
+pub async fn with_retries<F, Fut>(
+	what: &str,
+	timeout: Duration,
+	retry: config::RetryPolicy,
+	mut attempt: F,
+) -> Result<()>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<()>>,
+{
+	let mut delay = retry.initial_delay;
+	let mut number = 1;
+	loop {
+		// Dropping the future on timeout also kills a child process
+		let result = tokio::time::timeout(timeout, attempt())
+			.await
+			.unwrap_or_else(|_| Err(eyre!("Did not finish within {timeout:?}")));
+		match result {
+			Ok(()) => return Ok(()),
+			Err(e) if number < retry.attempts => {
+				warn!(
+					"Attempt {number} of {} to {what} failed, retrying in {delay:?}: {e:#}",
+					retry.attempts
+				);
+				tokio::time::sleep(delay).await;
+				delay = (delay * 2).min(retry.max_delay);
+				number += 1;
+			}
+			Err(e) => {
+				return Err(e).wrap_err_with(|| {
+					format!("Giving up trying to {what} after {number} attempt(s)")
+				});
+			}
+		}
+	}
+}
+
+// Run a program and turn a non-zero exit status into an error containing its output
+pub async fn run_program(mut command: Command) -> Result<()> {
+	let child = command
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+		.wrap_err("Failed to spawn process")?;
 
-		let output = child
-			.wait_with_output()
-			.wrap_err("Cannot wait for output from updater process")?;
+	let output = child
+		.wait_with_output()
+		.await
+		.wrap_err("Cannot wait for output from process")?;
 
-		if !output.status.success() {
-			let stdout = String::from_utf8_lossy(&output.stdout);
-			let stderr = String::from_utf8_lossy(&output.stderr);
-			return Err(eyre!("The reload command exited with non-zero status code"))
-				.with_section(move || stdout.trim().to_string().header("Stdout:"))
-				.with_section(move || stderr.trim().to_string().header("Stderr:"));
+	if !output.status.success() {
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		return Err(eyre!("The command exited with non-zero status code"))
+			.with_section(move || stdout.trim().to_string().header("Stdout:"))
+			.with_section(move || stderr.trim().to_string().header("Stderr:"));
+	}
+
+	Ok(())
+}
+
+// What programs run for a zone (the reload program and hooks) get to know about the change,
+// through placeholders in their arguments and environment variables
+pub struct ProgramContext {
+	zone: String,
+	serial: String,
+	old_serial: String,
+	zone_file: String,
+	// One line per include with the new state followed by the path
+	changed_includes: String,
+}
+
+impl ProgramContext {
+	pub fn new(zone: &zone_file::Zone, old_zone: Option<&zone_file::Zone>) -> Self {
+		Self {
+			zone: zone.name.clone(),
+			serial: zone.soa.serial.to_string(),
+			old_serial: old_zone
+				.map(|old_zone| old_zone.soa.serial.to_string())
+				.unwrap_or_default(),
+			zone_file: zone.file_path().display().to_string(),
+			changed_includes: changed_includes(zone, old_zone)
+				.iter()
+				.map(|(path, state)| format!("{state} {}", path.display()))
+				.collect::<Vec<_>>()
+				.join("\n"),
+		}
+	}
+
+	// When nothing but the name of the zone is known
+	pub fn from_zone_name(zone_name: &str) -> Self {
+		Self {
+			zone: zone_name.to_string(),
+			serial: String::new(),
+			old_serial: String::new(),
+			zone_file: String::new(),
+			changed_includes: String::new(),
+		}
+	}
+
+	pub fn zone(&self) -> &str {
+		&self.zone
+	}
+
+	pub fn expand_args(&self, args: &[String]) -> Vec<String> {
+		let values = [
+			("zone", self.zone.as_str()),
+			("serial", self.serial.as_str()),
+			("old_serial", self.old_serial.as_str()),
+			("zone_file", self.zone_file.as_str()),
+		];
+		args.iter().map(|arg| expand(arg, &values)).collect()
+	}
+
+	pub fn set_env(&self, command: &mut Command) {
+		command
+			.env("ZONEWATCH_ZONE", &self.zone)
+			.env("ZONEWATCH_SERIAL", &self.serial)
+			.env("ZONEWATCH_OLD_SERIAL", &self.old_serial)
+			.env("ZONEWATCH_ZONE_FILE", &self.zone_file)
+			.env("ZONEWATCH_CHANGED_INCLUDES", &self.changed_includes);
+	}
+}
+
+// Expand placeholders like `{zone}` in an argument of the reload program.
+// Unknown placeholders are kept as they are.
+fn expand(arg: &str, values: &[(&str, &str)]) -> String {
+	let mut expanded = String::with_capacity(arg.len());
+	let mut rest = arg;
+	while let Some(start) = rest.find('{') {
+		expanded.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let replacement = rest.find('}').and_then(|end| {
+			values
+				.iter()
+				.find(|(name, _)| *name == &rest[1..end])
+				.map(|(_, value)| (end, *value))
+		});
+		if let Some((end, value)) = replacement {
+			expanded.push_str(value);
+			rest = &rest[end + 1..];
+		} else {
+			expanded.push('{');
+			rest = &rest[1..];
 		}
+	}
+	expanded.push_str(rest);
+	expanded
+}
+
+// The includes whose state differs from the old version of the zone together with their new state.
+// Without an old version, all includes count as changed.
+fn changed_includes<'a>(
+	zone: &'a zone_file::Zone,
+	old_zone: Option<&'a zone_file::Zone>,
+) -> Vec<(&'a Path, &'static str)> {
+	let mut changed: Vec<(&Path, &str)> = zone
+		.includes_ordered
+		.iter()
+		.filter_map(|path| {
+			let include = zone.includes.get(path).expect(
+				"The includes map should always contain the same keys as in the includes_ordered list",
+			);
+			let unchanged =
+				old_zone.is_some_and(|old_zone| old_zone.includes.get(path) == Some(include));
+			(!unchanged).then_some((path.as_path(), include.state_name()))
+		})
+		.collect();
+	if let Some(old_zone) = old_zone {
+		for path in &old_zone.includes_ordered {
+			if !zone.includes.contains_key(path) {
+				changed.push((path.as_path(), "removed"));
+			}
+		}
+	}
+	changed
+}
 
+pub struct Request {
+	pub zone: zone_file::Zone,
+	// The version of the zone before the change, if there was one
+	pub old_zone: Option<zone_file::Zone>,
+}
+
+// Requests reloads from a background task.
+// If reloads are requested while a reload is still running, only a single reload of the newest
+// version of the zone follows once the running one is done.
+pub struct Queue {
+	sender: watch::Sender<Option<Arc<Request>>>,
+}
+
+impl Queue {
+	pub fn request(&self, request: Request) {
+		// Sending only fails if the receiving task is gone, which only happens when it panicked
+		if self.sender.send(Some(Arc::new(request))).is_err() {
+			error!("The reload task has stopped, cannot reload");
+		}
+	}
+}
+
+// Zones whose last reload failed are retried every `max_delay` of their `reload_retry` policy,
+// but not more often than this
+const MIN_PENDING_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Reloader {
+	// Reload the zone and remember that the DNS server knows about its serial number
+	async fn execute_and_record(
+		&self,
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+		coordinator: Option<&coordinator::Handle>,
+		post_reload_hooks: &[config::Hook],
+		pool: &Pool<Sqlite>,
+	) -> Result<()> {
+		match coordinator {
+			Some(coordinator) => coordinator.reload(self, zone, old_zone).await?,
+			None => self.execute(zone, old_zone).await?,
+		}
+
+		// An aborting hook leaves the zone marked as not reloaded, so everything is retried later
+		let context = ProgramContext::new(zone, old_zone);
+		hooks::run(post_reload_hooks, Event::PostReload, &context).await?;
+
+		let mut tx = pool.begin().await.wrap_err("Cannot begin transaction")?;
+		db::mark_reloaded(&zone.name, zone.soa.serial, &mut tx)
+			.await
+			.wrap_err("Cannot record the successful reload")?;
+		tx.commit().await.wrap_err("Cannot commit transaction")?;
 		Ok(())
 	}
+
+	// The zone as stored in the database if its serial number was never reloaded successfully
+	async fn pending_zone(&self, pool: &Pool<Sqlite>) -> Result<Option<Request>> {
+		let mut tx = pool.begin().await.wrap_err("Cannot begin transaction")?;
+		if !db::needs_reload(&self.zone_name, &mut tx).await? {
+			return Ok(None);
+		}
+		let Some(zone) = db::read_zone(&self.zone_name, &mut tx)
+			.await
+			.wrap_err("Cannot read zone info")?
+		else {
+			return Ok(None);
+		};
+		// Only the serial of the version the DNS server knows about is stored,
+		// so all includes count as changed
+		let old_zone = db::last_reloaded_serial(&self.zone_name, &mut tx)
+			.await?
+			.map(|serial| {
+				let mut old_zone = zone.clone();
+				old_zone.soa.serial = serial;
+				old_zone.includes.clear();
+				old_zone.includes_ordered.clear();
+				old_zone
+			});
+		Ok(Some(Request { zone, old_zone }))
+	}
+}
+
+pub struct Worker {
+	pub queue: Queue,
+	pub task: JoinHandle<bool>,
+}
+
+// The task returns whether the last reload succeeded once the queue is dropped.
+// Zones whose last reload failed are retried every `max_delay` of the retry policy, also across restarts
+// since the reload state is stored in the database.
+// With a coordinator, the reloads are executed by it, possibly together with other zones.
+// Reloads only start once the `previous` task, which reloaded the zone with its old configuration, is done.
+pub fn spawn(
+	reloader: Reloader,
+	hooks: config::Hooks,
+	coordinator: Option<coordinator::Handle>,
+	notifier: Option<webhook::Handle>,
+	pool: Pool<Sqlite>,
+	previous: Option<JoinHandle<bool>>,
+) -> Worker {
+	let (sender, mut receiver) = watch::channel(None);
+	let task = tokio::spawn(async move {
+		if let Some(previous) = previous {
+			trace!(
+				"Waiting for the previous reload task of zone {} to finish",
+				reloader.zone_name
+			);
+			// Its result was already logged
+			let _ = previous.await;
+		}
+		let mut succeeded = true;
+		// The version of the zone the DNS server knows about since the last successful reload
+		let mut last_reloaded: Option<zone_file::Zone> = None;
+		let pending_reload_interval = reloader.retry.max_delay.max(MIN_PENDING_RELOAD_INTERVAL);
+		let mut pending_check = interval_at(
+			Instant::now() + pending_reload_interval,
+			pending_reload_interval,
+		);
+		pending_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			let request = tokio::select! {
+				// This only returns an error once the queue was dropped and everything was reloaded
+				changed = receiver.changed() => {
+					if changed.is_err() {
+						break;
+					}
+					let Some(request) = receiver.borrow_and_update().clone() else {
+						continue;
+					};
+					request
+				}
+				_ = pending_check.tick() => {
+					match reloader.pending_zone(&pool).await {
+						Ok(Some(request)) => {
+							info!(
+								"Serial {} of zone {} was never reloaded successfully, retrying",
+								request.zone.soa.serial, reloader.zone_name
+							);
+							Arc::new(request)
+						}
+						Ok(None) => continue,
+						Err(e) => {
+							error!("{e:?}");
+							continue;
+						}
+					}
+				}
+			};
+			trace!(
+				"Will execute the reloading program for zone {}",
+				reloader.zone_name
+			);
+			let old_zone = last_reloaded.as_ref().or(request.old_zone.as_ref());
+			match reloader
+				.execute_and_record(
+					&request.zone,
+					old_zone,
+					coordinator.as_ref(),
+					&hooks.post_reload,
+					&pool,
+				)
+				.await
+			{
+				Ok(()) => {
+					last_reloaded = Some(request.zone.clone());
+					trace!(
+						"Done executing the reloading program for zone {}",
+						reloader.zone_name
+					);
+					succeeded = true;
+				}
+				Err(e) => {
+					error!("{e:?}");
+					let context = ProgramContext::new(&request.zone, old_zone);
+					if let Some(notifier) = &notifier {
+						notifier.notify(Notification::for_reload_failure(
+							&request.zone,
+							old_zone,
+							&e,
+						));
+					}
+					hooks::run_on_failure(&hooks.on_failure, &context, &e).await;
+					succeeded = false;
+				}
+			}
+		}
+		succeeded
+	});
+	Worker {
+		queue: Queue { sender },
+		task,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::{Hooks, RetryPolicy};
+	use crate::db;
+	use crate::reloader::{
+		Duration, Method, PathBuf, ProgramContext, Reloader, Request, expand, spawn,
+	};
+	use crate::test_util::{TempDir, test_zone};
+	use crate::zone_file;
+
+	fn reloader(script: &str, timeout: Duration) -> Reloader {
+		Reloader {
+			zone_name: "example.org".to_string(),
+			method: Method::Program {
+				bin: PathBuf::from("sh"),
+				args: vec!["-c".to_string(), script.to_string()],
+			},
+			timeout,
+			retry: RetryPolicy {
+				attempts: 3,
+				initial_delay: Duration::from_millis(1),
+				max_delay: Duration::from_millis(2),
+			},
+		}
+	}
+
+	#[tokio::test]
+	async fn check_retry_and_timeout() {
+		let temp_dir = TempDir::new("reloader");
+		let dir = temp_dir.path();
+		let counter = dir.join("attempts");
+		let zone = test_zone("example.org", dir);
+
+		// Fails twice and succeeds on the third attempt
+		let script = format!(
+			"echo x >> '{0}'; [ \"$(wc -l < '{0}')\" -ge 3 ]",
+			counter.display()
+		);
+		reloader(&script, Duration::from_secs(10))
+			.execute(&zone, None)
+			.await
+			.expect("the third attempt should succeed");
+		let attempts = std::fs::read_to_string(&counter).expect("can read");
+		assert_eq!(attempts.lines().count(), 3);
+
+		let start = std::time::Instant::now();
+		let result = reloader("sleep 10", Duration::from_millis(50))
+			.execute(&zone, None)
+			.await;
+		assert!(result.is_err());
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn check_expand() {
+		let values = [("zone", "example.org"), ("serial", "2")];
+		assert_eq!(expand("{zone}", &values), "example.org");
+		assert_eq!(expand("a{serial}b{zone}", &values), "a2bexample.org");
+		assert_eq!(expand("{unknown} {serial", &values), "{unknown} {serial");
+		assert_eq!(expand("${{serial}}", &values), "${2}");
+	}
+
+	#[tokio::test]
+	async fn check_program_context() {
+		let temp_dir = TempDir::new("context");
+		let dir = temp_dir.path();
+		let output = dir.join("output");
+		let old_zone = test_zone("example.org", dir);
+		let mut zone = test_zone("example.org", dir);
+		zone.soa.serial = 2;
+		let include = PathBuf::from("/missing.zone");
+		zone.includes
+			.insert(include.clone(), zone_file::Include::NotFound);
+		zone.includes_ordered.push(include);
+
+		let script = format!(
+			"echo \"{{zone}} {{serial}} {{old_serial}} $ZONEWATCH_ZONE_FILE $ZONEWATCH_CHANGED_INCLUDES\" > '{}'",
+			output.display()
+		);
+		reloader(&script, Duration::from_secs(10))
+			.execute(&zone, Some(&old_zone))
+			.await
+			.expect("the program should succeed");
+		assert_eq!(
+			std::fs::read_to_string(&output).expect("can read"),
+			format!(
+				"example.org 2 1 {} not_found /missing.zone\n",
+				dir.join("example.org.zone").display()
+			)
+		);
+	}
+
+	#[tokio::test]
+	async fn check_waits_for_previous() {
+		let temp_dir = TempDir::new("previous");
+		let dir = temp_dir.path();
+		let output = dir.join("output");
+		let pool = db::init_in_memory().await.expect("can create database");
+
+		let start = |script: String, previous| {
+			let worker = spawn(
+				reloader(&script, Duration::from_secs(10)),
+				Hooks::default(),
+				None,
+				None,
+				pool.clone(),
+				previous,
+			);
+			worker.queue.request(Request {
+				zone: test_zone("example.org", dir),
+				old_zone: None,
+			});
+			worker.task
+		};
+		let old = start(
+			format!("sleep 0.2; echo old >> '{}'", output.display()),
+			None,
+		);
+		let new = start(format!("echo new >> '{}'", output.display()), Some(old));
+		assert!(new.await.expect("the task does not panic"));
+		assert_eq!(
+			std::fs::read_to_string(&output).expect("can read"),
+			"old\nnew\n"
+		);
+	}
+
+	#[tokio::test]
+	async fn check_pending_zone() {
+		let temp_dir = TempDir::new("pending");
+		let mut zone = test_zone("example.org", temp_dir.path());
+		zone.soa.serial = 2;
+		let pool = db::init_in_memory().await.expect("can create database");
+		let reloader = reloader("true", Duration::from_secs(10));
+
+		let mut tx = pool.begin().await.expect("can begin transaction");
+		db::write_state(&zone, &mut tx)
+			.await
+			.expect("can write zone");
+		db::set_last_reloaded_serial(&zone.name, Some(1), &mut tx)
+			.await
+			.expect("can write serial");
+		tx.commit().await.expect("can commit");
+
+		// The old serial is known from the database even after a restart
+		let request = reloader
+			.pending_zone(&pool)
+			.await
+			.expect("can read zone")
+			.expect("serial 2 was never reloaded");
+		assert_eq!(request.zone.soa.serial, 2);
+		let old_zone = request.old_zone.expect("serial 1 was reloaded");
+		assert_eq!(old_zone.soa.serial, 1);
+		let context = ProgramContext::new(&request.zone, Some(&old_zone));
+		assert_eq!(context.old_serial, "1");
+
+		let mut tx = pool.begin().await.expect("can begin transaction");
+		db::mark_reloaded(&zone.name, 2, &mut tx)
+			.await
+			.expect("can write serial");
+		tx.commit().await.expect("can commit");
+		assert!(
+			reloader
+				.pending_zone(&pool)
+				.await
+				.expect("can read zone")
+				.is_none()
+		);
+	}
 }
@@ -0,0 +1,845 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// A minimal client for the control channel protocol of BIND, which is what `rndc` speaks.
+// A message is a length-prefixed, versioned table of key-value pairs.
+// Every message starts with an `_auth` table containing an HMAC over the rest of the message.
+// Before the actual command is accepted, the server has to hand out a nonce,
+// which is requested by sending a `null` command first.
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use color_eyre::{
+	Help, SectionExt,
+	eyre::{Result, WrapErr, eyre},
+};
+use hmac::{Hmac, KeyInit, Mac};
+use log::{debug, trace};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+const DEFAULT_PORT: u16 = 953;
+const PROTOCOL_VERSION: u32 = 1;
+const MAX_MESSAGE_LENGTH: u32 = 16 * 1024 * 1024;
+// How long the server should consider our messages valid
+const EXPIRY_SECONDS: u32 = 60;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_BINARY: u8 = 1;
+const TYPE_TABLE: u8 = 2;
+const TYPE_LIST: u8 = 3;
+
+// Length of the base64 encoded signatures in the `_auth` table
+const HMD5_LENGTH: usize = 22;
+const HSHA_LENGTH: usize = 88;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+	HmacMd5,
+	HmacSha1,
+	HmacSha224,
+	HmacSha256,
+	HmacSha384,
+	HmacSha512,
+}
+
+impl Algorithm {
+	fn from_name(name: &str) -> Option<Self> {
+		match name.to_ascii_lowercase().as_str() {
+			"hmac-md5" | "hmac-md5.sig-alg.reg.int" => Some(Self::HmacMd5),
+			"hmac-sha1" => Some(Self::HmacSha1),
+			"hmac-sha224" => Some(Self::HmacSha224),
+			"hmac-sha256" => Some(Self::HmacSha256),
+			"hmac-sha384" => Some(Self::HmacSha384),
+			"hmac-sha512" => Some(Self::HmacSha512),
+			_ => None,
+		}
+	}
+
+	// The numbers BIND uses to identify the algorithm inside of the `hsha` signature
+	const fn id(self) -> u8 {
+		match self {
+			Self::HmacMd5 => 157,
+			Self::HmacSha1 => 161,
+			Self::HmacSha224 => 162,
+			Self::HmacSha256 => 163,
+			Self::HmacSha384 => 164,
+			Self::HmacSha512 => 165,
+		}
+	}
+
+	fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+		fn compute<M: Mac + KeyInit>(secret: &[u8], data: &[u8]) -> Vec<u8> {
+			let mut mac =
+				<M as KeyInit>::new_from_slice(secret).expect("HMAC can take a key of any size");
+			mac.update(data);
+			mac.finalize().into_bytes().to_vec()
+		}
+
+		match self {
+			Self::HmacMd5 => compute::<Hmac<Md5>>(secret, data),
+			Self::HmacSha1 => compute::<Hmac<Sha1>>(secret, data),
+			Self::HmacSha224 => compute::<Hmac<Sha224>>(secret, data),
+			Self::HmacSha256 => compute::<Hmac<Sha256>>(secret, data),
+			Self::HmacSha384 => compute::<Hmac<Sha384>>(secret, data),
+			Self::HmacSha512 => compute::<Hmac<Sha512>>(secret, data),
+		}
+	}
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+	pub name: String,
+	pub algorithm: Algorithm,
+	pub secret: Vec<u8>,
+}
+
+// Don't leak the secret into the logs
+impl std::fmt::Debug for Key {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Key")
+			.field("name", &self.name)
+			.field("algorithm", &self.algorithm)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum KeyFileError {
+	#[error("Unterminated quoted string or comment")]
+	Unterminated,
+
+	#[error("Unexpected end of file")]
+	UnexpectedEnd,
+
+	#[error("Expected `{expected}` but found `{found}`")]
+	Unexpected { expected: String, found: String },
+
+	#[error("Key `{name}` has no algorithm")]
+	MissingAlgorithm { name: String },
+
+	#[error("Key `{name}` has no secret")]
+	MissingSecret { name: String },
+
+	#[error("Key `{name}` uses the unsupported algorithm `{algorithm}`")]
+	UnsupportedAlgorithm { name: String, algorithm: String },
+
+	#[error("The secret of key `{name}` is not valid base64")]
+	InvalidSecret { name: String },
+
+	#[error("No key named `{name}` was found")]
+	KeyNotFound { name: String },
+
+	#[error("No key was found")]
+	NoKey,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+	Word(String),
+	Quoted(String),
+	Open,
+	Close,
+	Semicolon,
+}
+
+impl Token {
+	fn text(&self) -> &str {
+		match self {
+			Self::Word(s) | Self::Quoted(s) => s,
+			Self::Open => "{",
+			Self::Close => "}",
+			Self::Semicolon => ";",
+		}
+	}
+}
+
+fn tokenize(contents: &str) -> Result<Vec<Token>, KeyFileError> {
+	let mut tokens = Vec::new();
+	let mut chars = contents.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			c if c.is_whitespace() => {}
+			'#' => {
+				chars.by_ref().find(|&c| c == '\n');
+			}
+			'/' if chars.peek() == Some(&'/') => {
+				chars.by_ref().find(|&c| c == '\n');
+			}
+			'/' if chars.peek() == Some(&'*') => {
+				chars.next();
+				let mut previous = ' ';
+				loop {
+					match chars.next() {
+						None => return Err(KeyFileError::Unterminated),
+						Some('/') if previous == '*' => break,
+						Some(c) => previous = c,
+					}
+				}
+			}
+			'"' => {
+				let mut s = String::new();
+				loop {
+					match chars.next() {
+						None => return Err(KeyFileError::Unterminated),
+						Some('"') => break,
+						Some('\\') => s.push(chars.next().ok_or(KeyFileError::Unterminated)?),
+						Some(c) => s.push(c),
+					}
+				}
+				tokens.push(Token::Quoted(s));
+			}
+			'{' => tokens.push(Token::Open),
+			'}' => tokens.push(Token::Close),
+			';' => tokens.push(Token::Semicolon),
+			c => {
+				let mut s = String::from(c);
+				while let Some(&c) = chars.peek() {
+					if c.is_whitespace() || matches!(c, '{' | '}' | ';' | '"') {
+						break;
+					}
+					s.push(c);
+					chars.next();
+				}
+				tokens.push(Token::Word(s));
+			}
+		}
+	}
+	Ok(tokens)
+}
+
+fn expect_token(
+	tokens: &mut impl Iterator<Item = Token>,
+	expected: &Token,
+) -> Result<(), KeyFileError> {
+	let token = tokens.next().ok_or(KeyFileError::UnexpectedEnd)?;
+	if token == *expected {
+		Ok(())
+	} else {
+		Err(KeyFileError::Unexpected {
+			expected: expected.text().to_string(),
+			found: token.text().to_string(),
+		})
+	}
+}
+
+// Skip a statement we're not interested in, including any nested blocks
+fn skip_statement(tokens: &mut impl Iterator<Item = Token>) -> Result<(), KeyFileError> {
+	let mut depth: usize = 0;
+	loop {
+		match tokens.next().ok_or(KeyFileError::UnexpectedEnd)? {
+			Token::Open => depth += 1,
+			Token::Close => {
+				depth = depth
+					.checked_sub(1)
+					.ok_or_else(|| KeyFileError::Unexpected {
+						expected: ";".to_string(),
+						found: "}".to_string(),
+					})?;
+			}
+			Token::Semicolon if depth == 0 => return Ok(()),
+			Token::Word(_) | Token::Quoted(_) | Token::Semicolon => {}
+		}
+	}
+}
+
+fn parse_key_block(
+	name: String,
+	tokens: &mut impl Iterator<Item = Token>,
+) -> Result<Key, KeyFileError> {
+	expect_token(tokens, &Token::Open)?;
+	let mut algorithm = None;
+	let mut secret = None;
+	loop {
+		let token = tokens.next().ok_or(KeyFileError::UnexpectedEnd)?;
+		match token {
+			Token::Close => break,
+			Token::Word(option) if option == "algorithm" || option == "secret" => {
+				let value = tokens.next().ok_or(KeyFileError::UnexpectedEnd)?;
+				let value = match value {
+					Token::Word(s) | Token::Quoted(s) => s,
+					token => {
+						return Err(KeyFileError::Unexpected {
+							expected: format!("value for {option}"),
+							found: token.text().to_string(),
+						});
+					}
+				};
+				expect_token(tokens, &Token::Semicolon)?;
+				if option == "algorithm" {
+					algorithm = Some(value);
+				} else {
+					secret = Some(value);
+				}
+			}
+			_ => skip_statement(tokens)?,
+		}
+	}
+	expect_token(tokens, &Token::Semicolon)?;
+
+	let algorithm_name =
+		algorithm.ok_or_else(|| KeyFileError::MissingAlgorithm { name: name.clone() })?;
+	let algorithm = Algorithm::from_name(&algorithm_name).ok_or_else(|| {
+		KeyFileError::UnsupportedAlgorithm {
+			name: name.clone(),
+			algorithm: algorithm_name,
+		}
+	})?;
+	let secret = secret.ok_or_else(|| KeyFileError::MissingSecret { name: name.clone() })?;
+	let secret = BASE64
+		.decode(secret)
+		.map_err(|_| KeyFileError::InvalidSecret { name: name.clone() })?;
+
+	Ok(Key {
+		name,
+		algorithm,
+		secret,
+	})
+}
+
+impl Key {
+	// Parse a file in the format of `rndc.key` or `rndc.conf` and return the requested key
+	// or the first key if no name was given
+	pub fn parse(contents: &str, key_name: Option<&str>) -> Result<Self, KeyFileError> {
+		let mut tokens = tokenize(contents)?.into_iter();
+		while let Some(token) = tokens.next() {
+			match token {
+				Token::Word(statement) if statement == "key" => {
+					let name = match tokens.next().ok_or(KeyFileError::UnexpectedEnd)? {
+						Token::Word(s) | Token::Quoted(s) => s,
+						token => {
+							return Err(KeyFileError::Unexpected {
+								expected: "key name".to_string(),
+								found: token.text().to_string(),
+							});
+						}
+					};
+					let key = parse_key_block(name, &mut tokens)?;
+					if key_name.is_none_or(|key_name| key_name == key.name) {
+						return Ok(key);
+					}
+				}
+				Token::Semicolon => {}
+				_ => skip_statement(&mut tokens)?,
+			}
+		}
+		Err(
+			key_name.map_or(KeyFileError::NoKey, |name| KeyFileError::KeyNotFound {
+				name: name.to_string(),
+			}),
+		)
+	}
+
+	pub async fn read(path: &Path, key_name: Option<&str>) -> Result<Self> {
+		let contents = tokio::fs::read_to_string(path)
+			.await
+			.wrap_err_with(|| format!("Cannot read key file `{}`", path.display()))?;
+		Self::parse(&contents, key_name)
+			.wrap_err_with(|| format!("Cannot parse key file `{}`", path.display()))
+	}
+
+	// Compute the signature the way BIND expects it: base64 encoded and padded with zeros
+	fn sign(&self, data: &[u8]) -> Vec<u8> {
+		let mac = self.algorithm.mac(&self.secret, data);
+		let mut signature = BASE64.encode(mac).into_bytes();
+		let length = match self.algorithm {
+			Algorithm::HmacMd5 => HMD5_LENGTH,
+			_ => HSHA_LENGTH,
+		};
+		signature.resize(length, 0);
+		signature
+	}
+
+	fn auth_table(&self, signature: Vec<u8>) -> Value {
+		if self.algorithm == Algorithm::HmacMd5 {
+			return Value::Table(vec![("hmd5".to_string(), Value::Binary(signature))]);
+		}
+		let mut value = vec![self.algorithm.id()];
+		value.extend(signature);
+		Value::Table(vec![("hsha".to_string(), Value::Binary(value))])
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+	Binary(Vec<u8>),
+	Table(Vec<(String, Self)>),
+	List(Vec<Self>),
+}
+
+impl Value {
+	fn lookup(&self, key: &str) -> Option<&Self> {
+		match self {
+			Self::Table(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+			Self::Binary(_) | Self::List(_) => None,
+		}
+	}
+
+	fn as_string(&self) -> Option<String> {
+		match self {
+			Self::Binary(data) => Some(String::from_utf8_lossy(data).into_owned()),
+			Self::Table(_) | Self::List(_) => None,
+		}
+	}
+}
+
+fn put_length(length: usize, out: &mut Vec<u8>) {
+	let length = u32::try_from(length).expect("rndc messages are much smaller than 4 GiB");
+	out.extend(length.to_be_bytes());
+}
+
+fn encode_table(entries: &[(String, Value)], out: &mut Vec<u8>) {
+	for (key, value) in entries {
+		let key_length = u8::try_from(key.len()).expect("keys are short constants");
+		out.push(key_length);
+		out.extend(key.as_bytes());
+		encode_value(value, out);
+	}
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+	let mut inner = Vec::new();
+	let value_type = match value {
+		Value::Binary(data) => {
+			inner.extend(data);
+			TYPE_BINARY
+		}
+		Value::Table(entries) => {
+			encode_table(entries, &mut inner);
+			TYPE_TABLE
+		}
+		Value::List(items) => {
+			for item in items {
+				encode_value(item, &mut inner);
+			}
+			TYPE_LIST
+		}
+	};
+	out.push(value_type);
+	put_length(inner.len(), out);
+	out.extend(inner);
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	position: usize,
+}
+
+impl<'a> Reader<'a> {
+	const fn new(data: &'a [u8]) -> Self {
+		Self { data, position: 0 }
+	}
+
+	const fn is_empty(&self) -> bool {
+		self.position >= self.data.len()
+	}
+
+	fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+		let end = self
+			.position
+			.checked_add(count)
+			.filter(|&end| end <= self.data.len())
+			.ok_or_else(|| eyre!("Message is truncated"))?;
+		let slice = &self.data[self.position..end];
+		self.position = end;
+		Ok(slice)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		let bytes: [u8; 4] = self.take(4)?.try_into()?;
+		Ok(u32::from_be_bytes(bytes))
+	}
+
+	fn key(&mut self) -> Result<String> {
+		let length = self.u8()?;
+		let key = self.take(length.into())?;
+		Ok(String::from_utf8_lossy(key).into_owned())
+	}
+
+	fn value(&mut self) -> Result<Value> {
+		let value_type = self.u8()?;
+		let length = self.u32()?;
+		let mut inner = Reader::new(self.take(length.try_into()?)?);
+		match value_type {
+			TYPE_STRING | TYPE_BINARY => Ok(Value::Binary(inner.data.to_vec())),
+			TYPE_TABLE => inner.table(),
+			TYPE_LIST => {
+				let mut items = Vec::new();
+				while !inner.is_empty() {
+					items.push(inner.value()?);
+				}
+				Ok(Value::List(items))
+			}
+			_ => Err(eyre!("Unknown value type {value_type}")),
+		}
+	}
+
+	fn table(&mut self) -> Result<Value> {
+		let mut entries = Vec::new();
+		while !self.is_empty() {
+			let key = self.key()?;
+			let value = self.value()?;
+			entries.push((key, value));
+		}
+		Ok(Value::Table(entries))
+	}
+}
+
+fn encode_message(key: &Key, body: &[(String, Value)]) -> Vec<u8> {
+	let mut signed = Vec::new();
+	encode_table(body, &mut signed);
+	let auth = vec![("_auth".to_string(), key.auth_table(key.sign(&signed)))];
+
+	let mut message = PROTOCOL_VERSION.to_be_bytes().to_vec();
+	encode_table(&auth, &mut message);
+	message.extend(signed);
+
+	let mut framed = Vec::new();
+	put_length(message.len(), &mut framed);
+	framed.extend(message);
+	framed
+}
+
+// Decode a message (without the length prefix) and check its signature
+fn decode_message(key: &Key, message: &[u8]) -> Result<Value> {
+	let mut reader = Reader::new(message);
+	let version = reader.u32()?;
+	if version != PROTOCOL_VERSION {
+		return Err(eyre!("Unsupported protocol version {version}"));
+	}
+
+	if reader.key()? != "_auth" {
+		return Err(eyre!("Message does not start with an _auth section"));
+	}
+	let auth = reader.value()?;
+	let signed = &message[reader.position..];
+
+	let expected = key.auth_table(key.sign(signed));
+	if auth != expected {
+		return Err(eyre!(
+			"The signature of the message is invalid, does the server use the same key?"
+		));
+	}
+
+	reader.table()
+}
+
+fn lookup_string(message: &Value, section: &str, key: &str) -> Option<String> {
+	message
+		.lookup(section)
+		.and_then(|section| section.lookup(key))
+		.and_then(Value::as_string)
+}
+
+fn unix_time() -> u32 {
+	let seconds = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |duration| duration.as_secs());
+	// The protocol only has 32 bits for timestamps
+	u32::try_from(seconds).unwrap_or(u32::MAX)
+}
+
+fn request(serial: u32, nonce: Option<u32>, command: &str) -> Vec<(String, Value)> {
+	let string = |s: String| Value::Binary(s.into_bytes());
+	let now = unix_time();
+	let mut ctrl = vec![
+		("_ser".to_string(), string(serial.to_string())),
+		("_tim".to_string(), string(now.to_string())),
+		(
+			"_exp".to_string(),
+			string(now.saturating_add(EXPIRY_SECONDS).to_string()),
+		),
+	];
+	if let Some(nonce) = nonce {
+		ctrl.push(("_nonce".to_string(), string(nonce.to_string())));
+	}
+	vec![
+		("_ctrl".to_string(), Value::Table(ctrl)),
+		(
+			"_data".to_string(),
+			Value::Table(vec![("type".to_string(), string(command.to_string()))]),
+		),
+	]
+}
+
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<()> {
+	stream
+		.write_all(data)
+		.await
+		.wrap_err("Cannot send message to the control channel")?;
+	stream
+		.flush()
+		.await
+		.wrap_err("Cannot send message to the control channel")
+}
+
+async fn receive<S: AsyncRead + Unpin>(stream: &mut S, key: &Key) -> Result<Value> {
+	let length = stream
+		.read_u32()
+		.await
+		.wrap_err("Cannot read the response from the control channel")?;
+	if length > MAX_MESSAGE_LENGTH {
+		return Err(eyre!("The response is too long ({length} bytes)"));
+	}
+	let mut message = vec![0; length.try_into()?];
+	stream
+		.read_exact(&mut message)
+		.await
+		.wrap_err("Cannot read the response from the control channel")?;
+	decode_message(key, &message).wrap_err("Cannot decode the response from the control channel")
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: &mut S,
+	key: &Key,
+	command: &str,
+) -> Result<String> {
+	let serial: u32 = rand::random();
+
+	trace!("Requesting nonce from the control channel");
+	send(stream, &encode_message(key, &request(serial, None, "null"))).await?;
+	let response = receive(stream, key).await?;
+	let nonce = lookup_string(&response, "_ctrl", "_nonce")
+		.ok_or_else(|| eyre!("The server did not send a nonce"))?;
+	let nonce: u32 = nonce
+		.parse()
+		.wrap_err_with(|| format!("The nonce `{nonce}` sent by the server is invalid"))?;
+
+	trace!("Sending command `{command}` to the control channel");
+	let message = encode_message(key, &request(serial.wrapping_add(1), Some(nonce), command));
+	send(stream, &message).await?;
+	let response = receive(stream, key).await?;
+
+	let text = lookup_string(&response, "_data", "text").unwrap_or_default();
+	let result = lookup_string(&response, "_data", "result");
+	let error = lookup_string(&response, "_data", "err");
+	if error.is_some() || result.as_ref().is_some_and(|result| result != "0") {
+		let error = error.unwrap_or_default();
+		let result = result.unwrap_or_default();
+		return Err(eyre!(
+			"The server reported an error for command `{command}`"
+		))
+		.with_section(move || result.header("Result:"))
+		.with_section(move || error.trim().to_string().header("Error:"))
+		.with_section(move || text.trim().to_string().header("Text:"));
+	}
+
+	Ok(text)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+	Tcp(String),
+	Unix(PathBuf),
+}
+
+impl Address {
+	// Accept a path to a Unix socket, an IP address with or without a port or a host name with or without a port
+	pub fn parse(address: &str) -> Self {
+		if address.starts_with('/') {
+			return Self::Unix(PathBuf::from(address));
+		}
+		if address.parse::<SocketAddr>().is_ok() {
+			return Self::Tcp(address.to_string());
+		}
+		if let Ok(ip) = address.parse::<IpAddr>() {
+			return Self::Tcp(SocketAddr::new(ip, DEFAULT_PORT).to_string());
+		}
+		if address.contains(':') {
+			return Self::Tcp(address.to_string());
+		}
+		Self::Tcp(format!("{address}:{DEFAULT_PORT}"))
+	}
+}
+
+impl std::fmt::Display for Address {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Tcp(address) => write!(f, "{address}"),
+			Self::Unix(path) => write!(f, "{}", path.display()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Client {
+	pub address: Address,
+	pub key_file: PathBuf,
+	pub key_name: Option<String>,
+}
+
+impl Client {
+	// Send a command like `reload example.org` and return the text the server responded with
+	pub async fn command(&self, command: &str) -> Result<String> {
+		// Read the key every time so that it can be rotated without restarting
+		let key = Key::read(&self.key_file, self.key_name.as_deref()).await?;
+		debug!(
+			"Connecting to control channel {} using key {}",
+			self.address, key.name
+		);
+		match &self.address {
+			Address::Tcp(address) => {
+				let mut stream = TcpStream::connect(address)
+					.await
+					.wrap_err_with(|| format!("Cannot connect to control channel {address}"))?;
+				exchange(&mut stream, &key, command).await
+			}
+			Address::Unix(path) => {
+				let mut stream = UnixStream::connect(path).await.wrap_err_with(|| {
+					format!("Cannot connect to control channel {}", path.display())
+				})?;
+				exchange(&mut stream, &key, command).await
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::rndc::{
+		Algorithm, BASE64, Engine, Key, KeyFileError, Value, decode_message, encode_message,
+		exchange, receive, request, send,
+	};
+
+	fn test_key(algorithm: Algorithm) -> Key {
+		Key {
+			name: "rndc-key".to_string(),
+			algorithm,
+			secret: b"some secret".to_vec(),
+		}
+	}
+
+	#[test]
+	fn check_parse_key() {
+		let contents = indoc::indoc! {r#"
+			# Generated by rndc-confgen
+			options {
+				default-key "rndc-key";
+				default-server 127.0.0.1;
+			};
+			key "other" {
+				algorithm hmac-md5;
+				secret "c29tZSBzZWNyZXQ=";
+			};
+			/* The key we want */
+			key "rndc-key" {
+				algorithm hmac-sha256;
+				secret "c29tZSBzZWNyZXQ="; // base64
+			};
+		"#};
+
+		assert_eq!(
+			Key::parse(contents, Some("rndc-key")),
+			Ok(test_key(Algorithm::HmacSha256))
+		);
+		assert_eq!(
+			Key::parse(contents, None).map(|key| key.algorithm),
+			Ok(Algorithm::HmacMd5)
+		);
+		assert_eq!(
+			Key::parse(contents, Some("missing")),
+			Err(KeyFileError::KeyNotFound {
+				name: "missing".to_string()
+			})
+		);
+		assert_eq!(
+			Key::parse("key \"k\" { secret \"c29tZQ==\"; };", None),
+			Err(KeyFileError::MissingAlgorithm {
+				name: "k".to_string()
+			})
+		);
+		assert_eq!(
+			Key::parse(
+				"key \"k\" { algorithm hmac-sha256; secret \"c29tZQ==\";",
+				None
+			),
+			Err(KeyFileError::UnexpectedEnd)
+		);
+	}
+
+	#[test]
+	fn check_message_signature() {
+		for algorithm in [Algorithm::HmacMd5, Algorithm::HmacSha256] {
+			let key = test_key(algorithm);
+			let framed = encode_message(&key, &request(1, Some(2), "reload example.org"));
+			let decoded = decode_message(&key, &framed[4..]).expect("message should be valid");
+			assert_eq!(
+				decoded
+					.lookup("_data")
+					.and_then(|data| data.lookup("type"))
+					.and_then(Value::as_string),
+				Some("reload example.org".to_string())
+			);
+
+			let mut tampered = framed[4..].to_vec();
+			*tampered.last_mut().expect("message is not empty") ^= 1;
+			assert!(decode_message(&key, &tampered).is_err());
+
+			let mut other_key = key.clone();
+			other_key.secret = BASE64.decode("b3RoZXI=").expect("valid base64");
+			assert!(decode_message(&other_key, &framed[4..]).is_err());
+		}
+	}
+
+	#[tokio::test]
+	async fn check_exchange() {
+		let key = test_key(Algorithm::HmacSha512);
+		let (mut client, mut server) = tokio::io::duplex(4096);
+
+		let server_key = key.clone();
+		let server_task = tokio::spawn(async move {
+			let string = |s: &str| Value::Binary(s.as_bytes().to_vec());
+			let null = receive(&mut server, &server_key)
+				.await
+				.expect("valid request");
+			assert_eq!(
+				null.lookup("_data")
+					.and_then(|data| data.lookup("type"))
+					.and_then(Value::as_string),
+				Some("null".to_string())
+			);
+			let response = vec![(
+				"_ctrl".to_string(),
+				Value::Table(vec![("_nonce".to_string(), string("1234"))]),
+			)];
+			send(&mut server, &encode_message(&server_key, &response))
+				.await
+				.expect("can send");
+
+			let command = receive(&mut server, &server_key)
+				.await
+				.expect("valid request");
+			assert_eq!(
+				command
+					.lookup("_ctrl")
+					.and_then(|ctrl| ctrl.lookup("_nonce"))
+					.and_then(Value::as_string),
+				Some("1234".to_string())
+			);
+			let response = vec![(
+				"_data".to_string(),
+				Value::Table(vec![
+					("result".to_string(), string("1")),
+					("err".to_string(), string("zone not found")),
+				]),
+			)];
+			send(&mut server, &encode_message(&server_key, &response))
+				.await
+				.expect("can send");
+		});
+
+		let result = exchange(&mut client, &key, "reload example.org").await;
+		server_task.await.expect("server task should not panic");
+		let error = result.expect_err("the server reported an error");
+		assert!(error.to_string().contains("reload example.org"));
+	}
+}
@@ -0,0 +1,533 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Export and import of the state in the database as a JSON document, e.g. to move zonewatch to another host
+// without copying the SQLite database while it is in use.
+//
+// Version 1 of the document looks like this:
+// {
+//   "version": 1,
+//   "zones": [
+//     {
+//       "name": "example.org",
+//       "dir": "/var/lib/bind/zones",
+//       "ttl": "86400",
+//       "soa": {
+//         "ttl": "86400",
+//         "mname": "ns1.example.org.",
+//         "rname": "john\\.doe.example.org.",
+//         "serial": 42,
+//         "refresh": "86400",
+//         "retry": "7200",
+//         "expire": "3600000",
+//         "minimum": "3600"
+//       },
+//       "last_reloaded_serial": 42,
+//       "includes": [
+//         { "path": "/path/to/file 1.zone", "state": "readable", "hash": "<BLAKE3 hash as 64 hex digits>" },
+//         { "path": "/path/to/file 2.zone", "state": "not_found" },
+//         { "path": "/path/to/file 3.zone", "state": "error", "errno": 5, "message": "Input/output error (os error 5)" }
+//       ]
+//     }
+//   ]
+// }
+//
+// The state of an include is one of "readable", "not_found", "permission_denied", "is_a_directory",
+// "too_many_symlinks", "invalid_data", "timeout" or "error". Only readable includes have a hash and only
+// includes with the state "error" have a message and, if the operating system reported one, an errno.
+// `last_reloaded_serial` is null if the zone was never reloaded.
+//
+// Paths are escaped like the state in the zone files, since they don't need to be valid UTF-8:
+// backslashes and quotes are escaped with a backslash and invalid bytes are written as `\DDD`.
+
+use crate::config::Config;
+use crate::db;
+use crate::domain_name::{NameError, normalize_origin};
+use crate::zone_file::{self, Include, IoError, escape_path, unescape_path};
+use color_eyre::eyre::{Result, WrapErr, eyre};
+use log::{error, info, warn};
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::{
+	collections::{HashMap, HashSet},
+	fs,
+	io::{Read, Write},
+	path::{Path, PathBuf},
+};
+
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Document {
+	version: u32,
+	zones: Vec<ZoneState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct ZoneState {
+	name: String,
+	#[serde(with = "escaped_path")]
+	dir: PathBuf,
+	ttl: String,
+	soa: SoaState,
+	last_reloaded_serial: Option<u32>,
+	includes: Vec<IncludeState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct SoaState {
+	ttl: String,
+	mname: String,
+	rname: String,
+	serial: u32,
+	refresh: String,
+	retry: String,
+	expire: String,
+	minimum: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct IncludeState {
+	#[serde(with = "escaped_path")]
+	path: PathBuf,
+	state: IncludeStateName,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	hash: Option<String>,
+	// Only for the state `error`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	errno: Option<i32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum IncludeStateName {
+	Readable,
+	NotFound,
+	PermissionDenied,
+	IsADirectory,
+	TooManySymlinks,
+	InvalidData,
+	Error,
+	Timeout,
+}
+
+// Serializing a `PathBuf` fails if it is not valid UTF-8, so paths are escaped with `escape_path()`
+mod escaped_path {
+	use super::{Deserializer, Path, PathBuf, Serializer, escape_path, unescape_path};
+	use serde::Deserialize;
+	use serde::de::Error;
+
+	pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&escape_path(path))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+		let escaped = String::deserialize(deserializer)?;
+		unescape_path(&escaped).map_err(D::Error::custom)
+	}
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ImportError {
+	#[error("Unsupported version {version}, only version {VERSION} is supported")]
+	UnsupportedVersion { version: u32 },
+
+	#[error("Invalid zone name `{name}`")]
+	InvalidZoneName {
+		name: String,
+		#[source]
+		source: NameError,
+	},
+
+	#[error("The zone name `{name}` is not in its canonical form `{normalized}`")]
+	NotNormalized { name: String, normalized: String },
+
+	#[error("The zone `{name}` is in the document more than once")]
+	DuplicateZone { name: String },
+
+	#[error("Zone `{zone}`: `{field}` is empty")]
+	EmptyField { zone: String, field: &'static str },
+
+	#[error("Zone `{zone}`: the include `{}` is in the list more than once", path.display())]
+	DuplicateInclude { zone: String, path: PathBuf },
+
+	#[error("Zone `{zone}`: the readable include `{}` has no hash", path.display())]
+	MissingHash { zone: String, path: PathBuf },
+
+	#[error("Zone `{zone}`: the include `{}` has a hash but is not readable", path.display())]
+	UnexpectedHash { zone: String, path: PathBuf },
+
+	#[error("Zone `{zone}`: the include `{}` has error details but its state is not `error`", path.display())]
+	UnexpectedErrorDetails { zone: String, path: PathBuf },
+
+	#[error("Zone `{zone}`: invalid hash of the include `{}`", path.display())]
+	InvalidHash {
+		zone: String,
+		path: PathBuf,
+		#[source]
+		source: blake3::HexError,
+	},
+}
+
+impl IncludeState {
+	fn new(path: &Path, include: &Include) -> Self {
+		let (state, hash) = match include {
+			Include::Readable(hash) => {
+				(IncludeStateName::Readable, Some(hash.to_hex().to_string()))
+			}
+			Include::NotFound => (IncludeStateName::NotFound, None),
+			Include::PermissionDenied => (IncludeStateName::PermissionDenied, None),
+			Include::IsADirectory => (IncludeStateName::IsADirectory, None),
+			Include::TooManySymlinks => (IncludeStateName::TooManySymlinks, None),
+			Include::InvalidData => (IncludeStateName::InvalidData, None),
+			Include::OtherError(_) => (IncludeStateName::Error, None),
+			Include::Timeout => (IncludeStateName::Timeout, None),
+		};
+		let (errno, message) = match include {
+			Include::OtherError(e) => (e.errno, Some(e.message.clone())),
+			_ => (None, None),
+		};
+		Self {
+			path: path.to_path_buf(),
+			state,
+			hash,
+			errno,
+			message,
+		}
+	}
+}
+
+impl ZoneState {
+	fn new(zone: zone_file::Zone, last_reloaded_serial: Option<u32>) -> Self {
+		let includes = zone
+			.includes_ordered
+			.iter()
+			.map(|path| IncludeState::new(path, &zone.includes[path]))
+			.collect();
+		Self {
+			name: zone.name,
+			dir: zone.dir,
+			ttl: zone.ttl,
+			soa: SoaState {
+				ttl: zone.soa.ttl,
+				mname: zone.soa.mname,
+				rname: zone.soa.rname,
+				serial: zone.soa.serial,
+				refresh: zone.soa.refresh,
+				retry: zone.soa.retry,
+				expire: zone.soa.expire,
+				minimum: zone.soa.minimum,
+			},
+			last_reloaded_serial,
+			includes,
+		}
+	}
+
+	fn includes(&self, errors: &mut Vec<ImportError>) -> HashMap<PathBuf, Include> {
+		let mut includes = HashMap::new();
+		for include in &self.includes {
+			let zone = self.name.clone();
+			let path = include.path.clone();
+			let converted = match (include.state, &include.hash) {
+				(state, _)
+					if state != IncludeStateName::Error
+						&& (include.errno.is_some() || include.message.is_some()) =>
+				{
+					Err(ImportError::UnexpectedErrorDetails { zone, path })
+				}
+				(IncludeStateName::Readable, None) => Err(ImportError::MissingHash { zone, path }),
+				(IncludeStateName::Readable, Some(hash)) => blake3::Hash::from_hex(hash)
+					.map(Include::Readable)
+					.map_err(|source| ImportError::InvalidHash { zone, path, source }),
+				(_, Some(_)) => Err(ImportError::UnexpectedHash { zone, path }),
+				(IncludeStateName::Error, None) => Ok(Include::OtherError(IoError {
+					errno: include.errno,
+					// The message is only for humans, so a document written by hand may leave it out
+					message: include
+						.message
+						.clone()
+						.unwrap_or_else(|| "unknown error".to_string()),
+				})),
+				(IncludeStateName::NotFound, None) => Ok(Include::NotFound),
+				(IncludeStateName::PermissionDenied, None) => Ok(Include::PermissionDenied),
+				(IncludeStateName::IsADirectory, None) => Ok(Include::IsADirectory),
+				(IncludeStateName::TooManySymlinks, None) => Ok(Include::TooManySymlinks),
+				(IncludeStateName::InvalidData, None) => Ok(Include::InvalidData),
+				(IncludeStateName::Timeout, None) => Ok(Include::Timeout),
+			};
+			match converted {
+				Ok(converted) => {
+					if includes.insert(include.path.clone(), converted).is_some() {
+						errors.push(ImportError::DuplicateInclude {
+							zone: self.name.clone(),
+							path: include.path.clone(),
+						});
+					}
+				}
+				Err(e) => errors.push(e),
+			}
+		}
+		includes
+	}
+
+	fn to_zone(&self, errors: &mut Vec<ImportError>) -> zone_file::Zone {
+		let fields = [
+			("ttl", &self.ttl),
+			("soa.ttl", &self.soa.ttl),
+			("soa.mname", &self.soa.mname),
+			("soa.rname", &self.soa.rname),
+			("soa.refresh", &self.soa.refresh),
+			("soa.retry", &self.soa.retry),
+			("soa.expire", &self.soa.expire),
+			("soa.minimum", &self.soa.minimum),
+		];
+		for (field, value) in fields {
+			if value.trim().is_empty() {
+				errors.push(ImportError::EmptyField {
+					zone: self.name.clone(),
+					field,
+				});
+			}
+		}
+		if self.dir.as_os_str().is_empty() {
+			errors.push(ImportError::EmptyField {
+				zone: self.name.clone(),
+				field: "dir",
+			});
+		}
+
+		zone_file::Zone {
+			name: self.name.clone(),
+			dir: self.dir.clone(),
+			ttl: self.ttl.clone(),
+			includes: self.includes(errors),
+			includes_ordered: self.includes.iter().map(|i| i.path.clone()).collect(),
+			soa: zone_file::Soa {
+				ttl: self.soa.ttl.clone(),
+				mname: self.soa.mname.clone(),
+				rname: self.soa.rname.clone(),
+				serial: self.soa.serial,
+				refresh: self.soa.refresh.clone(),
+				retry: self.soa.retry.clone(),
+				expire: self.soa.expire.clone(),
+				minimum: self.soa.minimum.clone(),
+			},
+		}
+	}
+}
+
+// Check the document and convert it into the zones and their last reloaded serials
+fn validate(document: &Document) -> (Vec<(zone_file::Zone, Option<u32>)>, Vec<ImportError>) {
+	let mut errors = Vec::new();
+	if document.version != VERSION {
+		errors.push(ImportError::UnsupportedVersion {
+			version: document.version,
+		});
+		return (Vec::new(), errors);
+	}
+
+	let mut names = HashSet::new();
+	let mut zones = Vec::new();
+	for zone in &document.zones {
+		match normalize_origin(&zone.name) {
+			Ok(normalized) if normalized != zone.name => errors.push(ImportError::NotNormalized {
+				name: zone.name.clone(),
+				normalized,
+			}),
+			Ok(_) => {}
+			Err(source) => errors.push(ImportError::InvalidZoneName {
+				name: zone.name.clone(),
+				source,
+			}),
+		}
+		if !names.insert(&zone.name) {
+			errors.push(ImportError::DuplicateZone {
+				name: zone.name.clone(),
+			});
+		}
+		zones.push((zone.to_zone(&mut errors), zone.last_reloaded_serial));
+	}
+	(zones, errors)
+}
+
+pub async fn export(pool: &Pool<Sqlite>, output: Option<&Path>) -> Result<()> {
+	let mut tx = pool.begin().await?;
+	let mut zones = Vec::new();
+	for stored in db::list_zones(&mut tx).await? {
+		let zone = db::read_zone(&stored.name, &mut tx)
+			.await?
+			.ok_or_else(|| eyre!("Zone {} disappeared from the database", stored.name))?;
+		let last_reloaded_serial = db::last_reloaded_serial(&stored.name, &mut tx).await?;
+		zones.push(ZoneState::new(zone, last_reloaded_serial));
+	}
+	tx.commit().await?;
+
+	let count = zones.len();
+	let document = Document {
+		version: VERSION,
+		zones,
+	};
+	let mut json =
+		serde_json::to_string_pretty(&document).wrap_err("Cannot serialize the state")?;
+	json.push('\n');
+
+	if let Some(output) = output {
+		fs::write(output, json)
+			.wrap_err_with(|| format!("Cannot write state to `{}`", output.display()))?;
+		info!("Exported {count} zone(s) to `{}`", output.display());
+	} else {
+		std::io::stdout()
+			.write_all(json.as_bytes())
+			.wrap_err("Cannot write state to stdout")?;
+	}
+	Ok(())
+}
+
+// Replace the state of the zones in the document, zones which are only in the database are left alone
+pub async fn import(
+	pool: &Pool<Sqlite>,
+	config: &Config,
+	input: &Path,
+	dry_run: bool,
+) -> Result<()> {
+	let json = if input == Path::new("-") {
+		let mut json = String::new();
+		std::io::stdin()
+			.read_to_string(&mut json)
+			.wrap_err("Cannot read state from stdin")?;
+		json
+	} else {
+		fs::read_to_string(input)
+			.wrap_err_with(|| format!("Cannot read state from `{}`", input.display()))?
+	};
+	let document: Document = serde_json::from_str(&json)
+		.wrap_err_with(|| format!("Cannot parse state in `{}`", input.display()))?;
+
+	let (zones, errors) = validate(&document);
+	if !errors.is_empty() {
+		for e in &errors {
+			error!("{e}");
+		}
+		return Err(eyre!(
+			"Found {} problem(s) in the state in `{}`, nothing was imported",
+			errors.len(),
+			input.display()
+		));
+	}
+
+	let mut tx = pool.begin().await?;
+	for (zone, last_reloaded_serial) in &zones {
+		if !config.zones.contains_key(&zone.name) {
+			warn!("Zone {} is not in the config file", zone.name);
+		}
+		match db::read_zone(&zone.name, &mut tx).await? {
+			Some(old_zone) => info!(
+				"Importing zone {} with serial {}, replacing serial {}",
+				zone.name, zone.soa.serial, old_zone.soa.serial
+			),
+			None => info!(
+				"Importing zone {} with serial {}",
+				zone.name, zone.soa.serial
+			),
+		}
+		db::write_state(zone, &mut tx).await?;
+		db::set_last_reloaded_serial(&zone.name, *last_reloaded_serial, &mut tx).await?;
+	}
+
+	if dry_run {
+		// Dropping the transaction rolls it back
+		info!("Dry run, not importing {} zone(s)", zones.len());
+	} else {
+		tx.commit().await.wrap_err("Cannot commit transaction")?;
+		info!("Imported {} zone(s)", zones.len());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use crate::state::{Document, ImportError, VERSION, ZoneState, validate};
+	use crate::test_util::test_zone;
+	use crate::zone_file::{Include, IoError};
+	use std::collections::HashMap;
+	use std::path::{Path, PathBuf};
+
+	#[test]
+	fn check_round_trip_and_validation() {
+		let path = PathBuf::from("/path/to/file.zone");
+		let broken_path = PathBuf::from("/path/to/broken.zone");
+		let broken = Include::OtherError(IoError {
+			errno: Some(5),
+			message: "Input/output error (os error 5)".to_string(),
+		});
+		let mut zone = test_zone("example.org", Path::new("/var/lib/bind/zones"));
+		zone.soa.serial = 42;
+		zone.includes = HashMap::from([
+			(path.clone(), Include::Readable(blake3::hash(b"a"))),
+			(broken_path.clone(), broken),
+		]);
+		zone.includes_ordered = vec![path, broken_path];
+		let document = Document {
+			version: VERSION,
+			zones: vec![ZoneState::new(zone.clone(), Some(41))],
+		};
+		let json = serde_json::to_string(&document).expect("can serialize");
+		let parsed: Document = serde_json::from_str(&json).expect("can parse");
+		let (zones, errors) = validate(&parsed);
+		assert!(errors.is_empty());
+		assert_eq!(zones, [(zone, Some(41))]);
+
+		let invalid = json
+			.replace("\"readable\"", "\"not_found\"")
+			.replace("example.org\"", "Example.org\"");
+		let (_, errors) = validate(&serde_json::from_str(&invalid).expect("can parse"));
+		assert!(matches!(
+			errors[..],
+			[
+				ImportError::NotNormalized { .. },
+				ImportError::UnexpectedHash { .. }
+			]
+		));
+
+		let newer_version = json.replace(&format!("\"version\":{VERSION}"), "\"version\":2");
+		let (_, errors) = validate(&serde_json::from_str(&newer_version).expect("can parse"));
+		assert!(matches!(
+			errors[..],
+			[ImportError::UnsupportedVersion { version: 2 }]
+		));
+	}
+
+	#[test]
+	fn check_escaped_paths() {
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+
+		// Paths don't need to be valid UTF-8
+		let dir = Path::new(OsStr::from_bytes(b"/var/lib/caf\xe9"));
+		let path = PathBuf::from(OsStr::from_bytes(b"/path/to/\"quoted\" \\ caf\xe9.zone"));
+		let mut zone = test_zone("example.org", dir);
+		zone.includes = HashMap::from([(path.clone(), Include::NotFound)]);
+		zone.includes_ordered = vec![path];
+		let document = Document {
+			version: VERSION,
+			zones: vec![ZoneState::new(zone.clone(), None)],
+		};
+		let json = serde_json::to_string(&document).expect("can serialize");
+		assert!(json.contains(r#""dir":"/var/lib/caf\\233""#));
+		assert!(json.contains(r#""path":"/path/to/\\\"quoted\\\" \\\\ caf\\233.zone""#));
+		let parsed: Document = serde_json::from_str(&json).expect("can parse");
+		let (zones, errors) = validate(&parsed);
+		assert!(errors.is_empty());
+		assert_eq!(zones, [(zone, None)]);
+
+		let invalid = json.replace(r"\\233", r"\\256");
+		assert!(serde_json::from_str::<Document>(&invalid).is_err());
+	}
+}
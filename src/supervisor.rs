@@ -0,0 +1,558 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Owns the tasks of all zones and applies changes of the configuration without restarting.
+// Only the tasks of zones whose configuration actually changed are restarted,
+// so the other zones are neither rewritten nor reloaded.
+
+use crate::config::{self, Config};
+use crate::coordinator;
+use crate::reloader::{self, Reloader};
+use crate::watcher::{DEBOUNCE_TIME, watch};
+use crate::webhook;
+use color_eyre::eyre::{Result, WrapErr, eyre};
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc::channel;
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{Instant, timeout_at};
+
+// The tasks shared by all zones
+struct Services {
+	coordinator: Option<coordinator::Handle>,
+	notifier: Option<(webhook::Handle, JoinHandle<()>)>,
+}
+
+impl Services {
+	fn start(config: &Config) -> Self {
+		// Without the coordinator every zone is reloaded on its own
+		let coordinator = config.reload_batch.clone().map(|batch| {
+			info!("Starting reload coordinator");
+			// The task stops by itself once all zones are done
+			let (handle, _task) = coordinator::spawn(batch);
+			handle
+		});
+
+		let notifier = (!config.webhooks.is_empty()).then(|| {
+			info!("Starting webhook notifier");
+			webhook::spawn(config.webhooks.clone())
+		});
+
+		Self {
+			coordinator,
+			notifier,
+		}
+	}
+}
+
+// The tasks of a single zone
+struct ZoneTasks {
+	watch: task::Id,
+	// Tells the watch task to stop once it is done publishing the current changes.
+	// Aborting it instead could leave a zone file behind which is not in the database.
+	shutdown: oneshot::Sender<()>,
+	// Resolves once the watch task stopped, since it drops the sender
+	watch_finished: oneshot::Receiver<()>,
+	// Keeps running until a running reload is done after the watch task stopped
+	reload: JoinHandle<bool>,
+}
+
+// The tasks of a zone which was stopped but may still be publishing or reloading
+struct StoppingZone {
+	watch_finished: oneshot::Receiver<()>,
+	reload: JoinHandle<bool>,
+}
+
+pub struct Supervisor {
+	pool: Pool<Sqlite>,
+	only_init: bool,
+	config: Config,
+	services: Services,
+	tasks: JoinSet<Result<()>>,
+	zones: HashMap<String, ZoneTasks>,
+	// The tasks of stopped zones, which a restarted zone waits for
+	stopping: HashMap<String, StoppingZone>,
+}
+
+impl Supervisor {
+	pub fn start(pool: Pool<Sqlite>, config: Config, only_init: bool) -> Self {
+		let services = Services::start(&config);
+		let mut supervisor = Self {
+			pool,
+			only_init,
+			config,
+			services,
+			tasks: JoinSet::new(),
+			zones: HashMap::new(),
+			stopping: HashMap::new(),
+		};
+		let zones = supervisor.config.zones.clone();
+		for (origin, zone) in zones {
+			supervisor.start_zone(origin, zone);
+		}
+		supervisor
+	}
+
+	fn start_zone(&mut self, origin: String, zone: config::Zone) {
+		info!("Starting task for zone {origin}");
+		// TODO: find a way to pass these variables without .clone()
+		let pool = self.pool.clone();
+		let nix_dir = self.config.nix_dir.clone();
+		let reloader = Reloader::new(&origin, self.config.reload_program_bin.as_deref(), &zone);
+		let notifier = self
+			.services
+			.notifier
+			.as_ref()
+			.map(|(handle, _)| handle.clone());
+		let (previous_watch, previous_reload) = self
+			.stopping
+			.remove(&origin)
+			.map(|stopping| (stopping.watch_finished, stopping.reload))
+			.unzip();
+		// Reloading the zone with the old and the new configuration at the same time could finish in the wrong order
+		let reloader::Worker {
+			queue: reload_queue,
+			task: reload_task,
+		} = reloader::spawn(
+			reloader,
+			zone.hooks.clone(),
+			self.services.coordinator.clone(),
+			notifier.clone(),
+			pool.clone(),
+			previous_reload,
+		);
+		let (shutdown, shutdown_receiver) = oneshot::channel();
+		let (watch_finished_sender, watch_finished) = oneshot::channel::<()>();
+		let only_init = self.only_init;
+		let zone_name = origin.clone();
+		let watch_task = self.tasks.spawn(async move {
+			let _watch_finished = watch_finished_sender;
+			// Publishing the zone with the old and the new configuration at the same time would
+			// mix up the state in the database
+			if let Some(previous_watch) = previous_watch {
+				debug!("Waiting for the previous task of zone {origin} to stop");
+				let _ = previous_watch.await;
+			}
+			info!("Task {} for zone {origin} started", task::id());
+			watch(
+				pool,
+				&nix_dir,
+				&origin,
+				zone,
+				reload_queue,
+				notifier,
+				(!only_init).then_some(shutdown_receiver),
+			)
+			.await
+			.wrap_err_with(|| format!("While watching zone `{origin}`"))
+		});
+		self.zones.insert(
+			zone_name,
+			ZoneTasks {
+				watch: watch_task.id(),
+				shutdown,
+				watch_finished,
+				reload: reload_task,
+			},
+		);
+	}
+
+	fn stop_zone(&mut self, origin: &str) {
+		if let Some(tasks) = self.zones.remove(origin) {
+			info!("Stopping task {} for zone {origin}", tasks.watch);
+			// The watch task may already have stopped with an error
+			let _ = tasks.shutdown.send(());
+			// The reload task of the zone stops by itself after finishing a running reload
+			self.stopping.insert(
+				origin.to_string(),
+				StoppingZone {
+					watch_finished: tasks.watch_finished,
+					reload: tasks.reload,
+				},
+			);
+		}
+	}
+
+	// Whether the task of the zone needs to be restarted to apply the new configuration
+	fn zone_changed(&self, new_config: &Config, origin: &str, new_zone: &config::Zone) -> bool {
+		let Some(old_zone) = self.config.zones.get(origin) else {
+			return true;
+		};
+		let old_reloader =
+			Reloader::new(origin, self.config.reload_program_bin.as_deref(), old_zone);
+		let new_reloader =
+			Reloader::new(origin, new_config.reload_program_bin.as_deref(), new_zone);
+		old_zone != new_zone || old_reloader != new_reloader
+	}
+
+	fn apply(&mut self, mut new_config: Config) {
+		if new_config.db != self.config.db {
+			if let Some(db) = &self.config.db {
+				warn!(
+					"Changing `db` requires restarting zonewatch, still using {}",
+					db.display()
+				);
+			} else {
+				warn!("Changing `db` requires restarting zonewatch, still running statelessly");
+			}
+			new_config.db.clone_from(&self.config.db);
+			// Whether the state is embedded depends on the database which is actually used
+			for zone in new_config.zones.values_mut() {
+				zone.embed_state = new_config.db.is_none();
+			}
+		}
+
+		// Settings which affect every zone
+		let restart_all = new_config.nix_dir != self.config.nix_dir
+			|| new_config.reload_batch != self.config.reload_batch
+			|| new_config.webhooks != self.config.webhooks;
+
+		let removed: Vec<String> = self
+			.zones
+			.keys()
+			.filter(|origin| !new_config.zones.contains_key(*origin))
+			.cloned()
+			.collect();
+		for origin in removed {
+			self.stop_zone(&origin);
+		}
+
+		let changed: Vec<(String, config::Zone)> = new_config
+			.zones
+			.iter()
+			.filter(|(origin, zone)| {
+				restart_all
+					|| !self.zones.contains_key(*origin)
+					|| self.zone_changed(&new_config, origin, zone)
+			})
+			.map(|(origin, zone)| (origin.clone(), zone.clone()))
+			.collect();
+		for (origin, _) in &changed {
+			self.stop_zone(origin);
+		}
+		// The reload task only stops after the watch task stopped
+		self.stopping
+			.retain(|_, stopping| !stopping.reload.is_finished());
+
+		self.config = new_config;
+		if restart_all {
+			// The old tasks stop once the zones using them are gone
+			self.services = Services::start(&self.config);
+		}
+		if changed.is_empty() {
+			info!("No zone needs to be restarted");
+		}
+		for (origin, zone) in changed {
+			self.start_zone(origin, zone);
+		}
+	}
+
+	fn reload_config(&mut self, config_path: &Path) {
+		info!("Reloading config file {}", config_path.display());
+		match Config::read(config_path).wrap_err("Cannot read config file") {
+			Ok(new_config) => self.apply(new_config),
+			Err(e) => error!("{e:?}\nKeeping the previous configuration"),
+		}
+	}
+
+	// The error of a zone task is fatal, just like before any configuration was reloaded
+	fn check_finished(res: std::result::Result<Result<()>, tokio::task::JoinError>) -> Result<()> {
+		match res {
+			Ok(inner_res) => inner_res,
+			Err(e) => Err(e).wrap_err("While joining all tasks"),
+		}
+	}
+
+	// Wait until all zones are done, which only happens with `--only-init`
+	pub async fn wait(mut self) -> Result<()> {
+		while let Some(res) = self.tasks.join_next().await {
+			Self::check_finished(res)?;
+		}
+
+		// The reload tasks finish once the zones are written
+		for (origin, tasks) in self.zones {
+			let reload_succeeded = tasks
+				.reload
+				.await
+				.wrap_err_with(|| format!("The reload task of zone {origin} panicked"))?;
+			if !reload_succeeded {
+				return Err(eyre!("Reloading zone {origin} failed"));
+			}
+		}
+
+		if let Some((handle, task)) = self.services.notifier {
+			// Deliver the remaining notifications before exiting
+			drop(handle);
+			task.await.wrap_err("The webhook task panicked")?;
+		}
+
+		Ok(())
+	}
+
+	// Follow the include_dir of the current configuration
+	fn watch_include_dir(
+		&self,
+		watcher: &mut RecommendedWatcher,
+		current_dir: &mut Option<PathBuf>,
+	) {
+		if *current_dir == self.config.include_dir {
+			return;
+		}
+		if let Some(dir) = current_dir.take() {
+			// The directory may have been removed already
+			let _ = watcher.unwatch(&dir);
+		}
+		if let Some(dir) = &self.config.include_dir {
+			match watcher.watch(dir, RecursiveMode::NonRecursive) {
+				Ok(()) => *current_dir = Some(dir.clone()),
+				Err(e) => warn!(
+					"Cannot start watching include_dir `{}`, changes are only applied on SIGHUP: {e}",
+					dir.display()
+				),
+			}
+		}
+	}
+
+	// Apply changes of the config files when they're modified or when receiving SIGHUP
+	pub async fn run(mut self, config_path: &Path) -> Result<()> {
+		let mut hangup = signal(SignalKind::hangup()).wrap_err("Cannot listen for SIGHUP")?;
+
+		let (tx, mut rx) = channel(1);
+		let mut config_watcher = RecommendedWatcher::new(
+			move |res| {
+				futures::executor::block_on(async {
+					tx.send(res)
+						.await
+						.expect("could not send event into channel");
+				});
+			},
+			notify::Config::default(),
+		)
+		.wrap_err("Cannot create watcher for the config file")?;
+		// Watch the parent directory since editors often replace the file instead of modifying it
+		let config_dir = match config_path.parent() {
+			Some(dir) if dir != Path::new("") => dir,
+			_ => Path::new("."),
+		};
+		config_watcher
+			.watch(config_dir, RecursiveMode::NonRecursive)
+			.wrap_err_with(|| format!("Cannot start watching path `{}`", config_dir.display()))?;
+		let config_file_name = config_path.file_name();
+		let mut include_dir = None;
+		self.watch_include_dir(&mut config_watcher, &mut include_dir);
+
+		loop {
+			tokio::select! {
+				Some(res) = self.tasks.join_next() => Self::check_finished(res)?,
+				_ = hangup.recv() => {
+					info!("Received SIGHUP");
+					self.reload_config(config_path);
+				}
+				Some(res) = rx.recv() => {
+					let event = res.wrap_err("Error from RecommendedWatcher")?;
+					let is_config_file = |path: &PathBuf| {
+						path.file_name() == config_file_name
+							|| (path.parent() == include_dir.as_deref()
+								&& path.extension().is_some_and(|extension| extension == "toml"))
+					};
+					let relevant = !matches!(event.kind, EventKind::Access(_))
+						&& event.paths.iter().any(is_config_file);
+					if !relevant {
+						continue;
+					}
+					// Editors may write the file in multiple steps
+					debug!("A config file changed, waiting for other events for {DEBOUNCE_TIME:?}");
+					let end_time = Instant::now() + DEBOUNCE_TIME;
+					while timeout_at(end_time, rx.recv()).await.is_ok() {}
+					self.reload_config(config_path);
+				}
+				else => return Err(eyre!("The config file watcher stopped unexpectedly")),
+			}
+			self.watch_include_dir(&mut config_watcher, &mut include_dir);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::Config;
+	use crate::db;
+	use crate::supervisor::Supervisor;
+	use crate::test_util::TempDir;
+	use std::path::Path;
+
+	// `zones` are the names and TTLs of the zones
+	fn config(dir: &Path, reload_program_bin: &str, zones: &[(&str, &str)]) -> Config {
+		let mut contents = format!(
+			r#"
+				reload_program_bin = "{reload_program_bin}"
+				[defaults]
+				dir = "{}"
+				reload_program_args = []
+				reload_retry = {{ attempts = 1 }}
+				includes = []
+				soa = {{ mname = "ns1.example.org.", rname = "john.doe@example.org", initial_serial = 1, ttl = "1d", refresh = "1d", retry = "2h", expire = "1000h", minimum = "1h" }}
+			"#,
+			dir.display()
+		);
+		for (name, ttl) in zones {
+			let zone = format!("[zones.\"{name}\"]\nttl = \"{ttl}\"\n");
+			contents.push_str(&zone);
+		}
+		Config::parse(&contents).expect("the config is valid")
+	}
+
+	#[tokio::test]
+	async fn check_zone_changed() {
+		let temp_dir = TempDir::new("zone-changed");
+		let dir = temp_dir.path();
+		let pool = db::init_in_memory().await.expect("can create database");
+		let supervisor =
+			Supervisor::start(pool, config(dir, "true", &[("example.org", "1d")]), false);
+
+		let new_config = config(dir, "true", &[("example.org", "1d")]);
+		assert!(!supervisor.zone_changed(
+			&new_config,
+			"example.org",
+			&new_config.zones["example.org"]
+		));
+
+		let new_config = config(dir, "true", &[("example.org", "2d")]);
+		assert!(supervisor.zone_changed(
+			&new_config,
+			"example.org",
+			&new_config.zones["example.org"]
+		));
+
+		// The zone itself is the same, but it is reloaded with a different program
+		let new_config = config(dir, "false", &[("example.org", "1d")]);
+		assert!(supervisor.zone_changed(
+			&new_config,
+			"example.org",
+			&new_config.zones["example.org"]
+		));
+
+		let new_config = config(dir, "true", &[("example.com", "1d")]);
+		assert!(supervisor.zone_changed(
+			&new_config,
+			"example.com",
+			&new_config.zones["example.com"]
+		));
+	}
+
+	#[tokio::test]
+	async fn check_apply() {
+		let temp_dir = TempDir::new("apply");
+		let dir = temp_dir.path();
+		let pool = db::init_in_memory().await.expect("can create database");
+		let old_config = config(
+			dir,
+			"true",
+			&[
+				("a.example", "1d"),
+				("b.example", "1d"),
+				("c.example", "1d"),
+			],
+		);
+		let mut supervisor = Supervisor::start(pool, old_config, false);
+		let old_ids: Vec<_> = ["a.example", "b.example"]
+			.iter()
+			.map(|origin| supervisor.zones[*origin].watch)
+			.collect();
+
+		supervisor.apply(config(
+			dir,
+			"true",
+			&[
+				("a.example", "1d"),
+				("b.example", "2d"),
+				("d.example", "1d"),
+			],
+		));
+		let mut origins: Vec<&String> = supervisor.zones.keys().collect();
+		origins.sort();
+		assert_eq!(origins, ["a.example", "b.example", "d.example"]);
+		// Only the changed zone was restarted
+		assert_eq!(supervisor.zones["a.example"].watch, old_ids[0]);
+		assert_ne!(supervisor.zones["b.example"].watch, old_ids[1]);
+		assert_eq!(supervisor.config.zones["b.example"].ttl, "172800");
+
+		// The database is only opened at startup, so the zones keep embedding their state
+		let mut new_config = config(dir, "true", &[("a.example", "1d")]);
+		new_config.db = Some(dir.join("db.sqlite"));
+		for zone in new_config.zones.values_mut() {
+			zone.embed_state = false;
+		}
+		supervisor.apply(new_config);
+		assert!(supervisor.config.db.is_none());
+		assert!(supervisor.config.zones["a.example"].embed_state);
+		assert_eq!(supervisor.zones["a.example"].watch, old_ids[0]);
+	}
+
+	#[tokio::test]
+	async fn check_wait() {
+		let temp_dir = TempDir::new("wait");
+		let dir = temp_dir.path();
+
+		let pool = db::init_in_memory().await.expect("can create database");
+		let supervisor =
+			Supervisor::start(pool, config(dir, "true", &[("example.org", "1d")]), true);
+		supervisor
+			.wait()
+			.await
+			.expect("the zone is written and reloaded");
+		assert!(dir.join("example.org.zone").exists());
+
+		let pool = db::init_in_memory().await.expect("can create database");
+		let supervisor =
+			Supervisor::start(pool, config(dir, "false", &[("example.org", "1d")]), true);
+		let error = supervisor.wait().await.expect_err("reloading fails");
+		assert_eq!(error.to_string(), "Reloading zone example.org failed");
+	}
+
+	#[tokio::test]
+	async fn check_stop_zone() {
+		use std::time::Duration;
+		use tokio::time::timeout;
+
+		let temp_dir = TempDir::new("stop-zone");
+		let dir = temp_dir.path();
+		let pool = db::init_in_memory().await.expect("can create database");
+		let mut supervisor =
+			Supervisor::start(pool, config(dir, "true", &[("example.org", "1d")]), false);
+		let zone_file = dir.join("example.org.zone");
+		let contents = async |ttl: &str| {
+			let expected = format!("$TTL {ttl}\n");
+			timeout(Duration::from_secs(10), async {
+				while !std::fs::read_to_string(&zone_file).is_ok_and(|c| c.contains(&expected)) {
+					tokio::time::sleep(Duration::from_millis(10)).await;
+				}
+			})
+			.await
+			.expect("the zone file is written");
+		};
+		contents("86400").await;
+
+		// The restarted task only publishes after the old one stopped by itself
+		supervisor.apply(config(dir, "true", &[("example.org", "2d")]));
+		let res = timeout(Duration::from_secs(10), supervisor.tasks.join_next())
+			.await
+			.expect("the old task stops")
+			.expect("there is a task");
+		assert!(matches!(res, Ok(Ok(()))));
+		contents("172800").await;
+
+		supervisor.apply(config(dir, "true", &[("example.com", "1d")]));
+		let res = timeout(Duration::from_secs(10), supervisor.tasks.join_next())
+			.await
+			.expect("the task of the removed zone stops")
+			.expect("there is a task");
+		assert!(matches!(res, Ok(Ok(()))));
+		assert_eq!(supervisor.tasks.len(), 1);
+	}
+}
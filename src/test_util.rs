@@ -0,0 +1,58 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Helpers shared by the tests of all modules
+
+use crate::zone_file::{Soa, Zone};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A directory which is removed again when the test ends, even if it panics
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	pub fn new(name: &str) -> Self {
+		// Tests run in parallel within the same process
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"zonewatch-{name}-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		std::fs::create_dir_all(&path).expect("can create temporary dir");
+		Self(path)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		// Panicking while already panicking would abort the tests
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
+
+// A zone without includes and with serial 1
+pub fn test_zone(name: &str, dir: &Path) -> Zone {
+	Zone {
+		name: name.to_string(),
+		dir: dir.to_path_buf(),
+		ttl: "1d".to_string(),
+		includes: HashMap::new(),
+		includes_ordered: Vec::new(),
+		soa: Soa {
+			ttl: "1d".to_string(),
+			mname: "ns1.example.org.".to_string(),
+			rname: "john\\.doe.example.org.".to_string(),
+			serial: 1,
+			refresh: "1d".to_string(),
+			retry: "2h".to_string(),
+			expire: "1000h".to_string(),
+			minimum: "1h".to_string(),
+		},
+	}
+}
@@ -4,20 +4,21 @@
 use crate::config;
 use crate::event_analyzer::{Changes, analyze_event};
 use crate::event_processor::process_probably_changed_includes;
-use crate::reloader::Reloader;
+use crate::reloader;
+use crate::webhook;
 use color_eyre::eyre::{Result, WrapErr, eyre};
-use log::{debug, trace};
+use log::{debug, info, trace};
 use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
 use sqlx::{Pool, Sqlite};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use tokio::{
-	sync::mpsc::channel,
+	sync::{mpsc::channel, oneshot},
 	time::{Duration, Instant, timeout_at},
 };
 
 // TODO: make timeout configurable
-const DEBOUNCE_TIME: Duration = Duration::from_millis(100);
+pub const DEBOUNCE_TIME: Duration = Duration::from_millis(100);
 
 fn detect_change(
 	zone_name: &str,
@@ -34,18 +35,62 @@ fn detect_change(
 	Ok(changes)
 }
 
+fn watch_include_dirs(
+	watcher: &mut RecommendedWatcher,
+	nix_dir: &Path,
+	zone_name: &str,
+	zone: &config::Zone,
+) -> Result<()> {
+	let include_parent_dirs: Result<HashSet<PathBuf>> = zone
+		.includes
+		.iter()
+		.map(|path| {
+			path.parent().map_or_else(
+				|| {
+					Err(eyre!(format!(
+						"Cannot get the parent directory of path `{}`",
+						path.display()
+					)))
+				},
+				|dir| Ok(dir.to_path_buf()),
+			)
+		})
+		.collect();
+
+	for dir in include_parent_dirs? {
+		if dir.starts_with(nix_dir) {
+			// Special case for files in the Nix Store:
+			// The contents of files in the Nix Store will never change since the files in the Nix store are immutable.
+			debug!(
+				"Not watching {} since it's in the Nix store (included in zone {zone_name})",
+				dir.display()
+			);
+		} else {
+			trace!("Watching {} (included in zone {zone_name})", dir.display());
+			watcher
+				// We're watching the parent directories of files so that we can also observe if the file is renamed, created or deleted
+				.watch(dir.as_ref(), RecursiveMode::NonRecursive)
+				.wrap_err_with(|| format!("Cannot start watching path `{}`", dir.display()))?;
+		}
+	}
+
+	Ok(())
+}
+
 pub async fn watch(
 	pool: Pool<Sqlite>,
 	nix_dir: &Path,
 	zone_name: &str,
 	zone: config::Zone,
-	reloader: Reloader,
-	only_init: bool,
+	reload_queue: reloader::Queue,
+	notifier: Option<webhook::Handle>,
+	// Stops the task between publishing changes, `None` only publishes the zone once (`--only-init`)
+	shutdown: Option<oneshot::Receiver<()>>,
 ) -> Result<()> {
 	let (tx, mut rx) = channel(1);
 
-	let mut watcher; // Keep this variable here so it is not dropped at the end of the `if !only_init {` block
-	if !only_init {
+	let mut watcher; // Keep this variable here so it is not dropped at the end of the `if shutdown.is_some() {` block
+	if shutdown.is_some() {
 		watcher = RecommendedWatcher::new(
 			move |res| {
 				futures::executor::block_on(async {
@@ -58,52 +103,38 @@ pub async fn watch(
 		)
 		.wrap_err("Cannot create watcher")?;
 
-		let include_parent_dirs: Result<HashSet<PathBuf>> = zone
-			.includes
-			.iter()
-			.map(|path| {
-				path.parent().map_or_else(
-					|| {
-						Err(eyre!(format!(
-							"Cannot get the parent directory of path `{}`",
-							path.display()
-						)))
-					},
-					|dir| Ok(dir.to_path_buf()),
-				)
-			})
-			.collect();
-
-		for dir in include_parent_dirs? {
-			if dir.starts_with(nix_dir) {
-				// Special case for files in the Nix Store:
-				// The contents of files in the Nix Store will never change since the files in the Nix store are immutable.
-				debug!(
-					"Not watching {} since it's in the Nix store (included in zone {zone_name})",
-					dir.display()
-				);
-			} else {
-				trace!("Watching {} (included in zone {zone_name})", dir.display());
-				watcher
-					// We're watching the parent directories of files so that we can also observe if the file is renamed, created or deleted
-					.watch(dir.as_ref(), RecursiveMode::NonRecursive)
-					.wrap_err_with(|| format!("Cannot start watching path `{}`", dir.display()))?;
-			}
-		}
+		watch_include_dirs(&mut watcher, nix_dir, zone_name, &zone)?;
 	}
 
-	process_probably_changed_includes(zone_name, &zone, Changes::All, &reloader, true, &pool)
-		.await?;
+	// Includes rejected by the pre-publish hook, which need to be checked again with the next change
+	let mut rejected = process_probably_changed_includes(
+		zone_name,
+		&zone,
+		Changes::All,
+		&reload_queue,
+		notifier.as_ref(),
+		true,
+		&pool,
+	)
+	.await?;
 
-	if only_init {
+	let Some(mut shutdown) = shutdown else {
+		// Dropping the queue lets the reload task finish, the supervisor waits for it
 		return Ok(());
-	}
+	};
 
 	loop {
 		// Wait til some file we're interested in changes
 		let mut changes = Changes::None;
 		loop {
-			let res = rx.recv().await;
+			// Stopping only between publishing changes leaves the zone file and the database consistent
+			let res = tokio::select! {
+				res = rx.recv() => res,
+				_ = &mut shutdown => {
+					info!("Task for zone {zone_name} stopped");
+					return Ok(());
+				}
+			};
 			changes = detect_change(zone_name, &zone.includes_set, res, changes)?;
 			match &changes {
 				Changes::Some(_) | Changes::All => {
@@ -136,9 +167,18 @@ pub async fn watch(
 				"This code should never be executed (there were no changes)"
 			));
 		}
-		process_probably_changed_includes(zone_name, &zone, changes, &reloader, false, &pool)
-			.await
-			.wrap_err("Cannot process probably changed includes")?;
+		let changes = rejected.union(changes);
+		rejected = process_probably_changed_includes(
+			zone_name,
+			&zone,
+			changes,
+			&reload_queue,
+			notifier.as_ref(),
+			false,
+			&pool,
+		)
+		.await
+		.wrap_err("Cannot process probably changed includes")?;
 
 		trace!("loop (zone {zone_name})");
 	}
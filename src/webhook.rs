@@ -0,0 +1,276 @@
+// SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
+// SPDX-License-Identifier: GPL-3.0-only
+
+// Notify web services like chat bots about changes of zones and failures.
+// The notifications are delivered by a background task so that a slow server
+// does not hold up publishing the zones.
+
+use crate::config::{self, WebhookEvent};
+use crate::reloader;
+use crate::zone_file;
+use color_eyre::{
+	Help, SectionExt,
+	eyre::{Report, Result, WrapErr, eyre},
+};
+use futures::StreamExt;
+use hmac::{Hmac, KeyInit, Mac};
+use log::{debug, error};
+use serde_derive::Serialize;
+use sha2::Sha256;
+use std::fmt::Write;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IncludeChange {
+	path: String,
+	// `None` if the file was not included before or is no longer included
+	old_state: Option<&'static str>,
+	new_state: Option<&'static str>,
+	// Why the file cannot be included anymore
+	reason: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Notification {
+	event: WebhookEvent,
+	zone: String,
+	serial: u32,
+	old_serial: Option<u32>,
+	includes: Vec<IncludeChange>,
+	error: Option<String>,
+}
+
+fn include_changes(
+	zone: &zone_file::Zone,
+	old_zone: Option<&zone_file::Zone>,
+) -> Vec<IncludeChange> {
+	let old_include = |path| old_zone.and_then(|old_zone| old_zone.includes.get(path));
+	let mut changes: Vec<IncludeChange> = zone
+		.includes_ordered
+		.iter()
+		.filter_map(|path| {
+			let new = zone.includes.get(path);
+			let old = old_include(path);
+			(old != new).then(|| IncludeChange {
+				path: path.display().to_string(),
+				old_state: old.map(zone_file::Include::state_name),
+				new_state: new.map(zone_file::Include::state_name),
+				reason: new.and_then(zone_file::Include::error_reason),
+			})
+		})
+		.collect();
+	if let Some(old_zone) = old_zone {
+		for path in &old_zone.includes_ordered {
+			if !zone.includes.contains_key(path) {
+				changes.push(IncludeChange {
+					path: path.display().to_string(),
+					old_state: old_zone
+						.includes
+						.get(path)
+						.map(zone_file::Include::state_name),
+					new_state: None,
+					reason: None,
+				});
+			}
+		}
+	}
+	changes
+}
+
+impl Notification {
+	// The notifications about publishing a new version of the zone
+	pub fn for_changes(zone: &zone_file::Zone, old_zone: Option<&zone_file::Zone>) -> Vec<Self> {
+		let includes = include_changes(zone, old_zone);
+		let notification = |event, includes| Self {
+			event,
+			zone: zone.name.clone(),
+			serial: zone.soa.serial,
+			old_serial: old_zone.map(|old_zone| old_zone.soa.serial),
+			includes,
+			error: None,
+		};
+
+		let mut notifications = Vec::new();
+		if old_zone.is_none_or(|old_zone| old_zone.soa.serial != zone.soa.serial) {
+			notifications.push(notification(WebhookEvent::SerialChange, includes.clone()));
+		}
+		// Only a changed hash doesn't count as a change of the state.
+		// Without an old version there is nothing to compare to.
+		let state_changes: Vec<IncludeChange> = includes
+			.into_iter()
+			.filter(|change| change.old_state != change.new_state)
+			.collect();
+		if old_zone.is_some() && !state_changes.is_empty() {
+			notifications.push(notification(
+				WebhookEvent::IncludeStateChange,
+				state_changes,
+			));
+		}
+		notifications
+	}
+
+	pub fn for_reload_failure(
+		zone: &zone_file::Zone,
+		old_zone: Option<&zone_file::Zone>,
+		error: &Report,
+	) -> Self {
+		Self {
+			event: WebhookEvent::ReloadFailure,
+			zone: zone.name.clone(),
+			serial: zone.soa.serial,
+			old_serial: old_zone.map(|old_zone| old_zone.soa.serial),
+			includes: include_changes(zone, old_zone),
+			error: Some(format!("{error:#}")),
+		}
+	}
+}
+
+// Hex encoded HMAC-SHA256 of the body
+fn sign(secret: &[u8], body: &[u8]) -> String {
+	let mut mac =
+		<Hmac<Sha256> as KeyInit>::new_from_slice(secret).expect("HMAC can take a key of any size");
+	mac.update(body);
+	mac.finalize()
+		.into_bytes()
+		.iter()
+		.fold(String::new(), |mut hex, byte| {
+			let _ = write!(hex, "{byte:02x}");
+			hex
+		})
+}
+
+async fn read_secret(secret_file: &Path) -> Result<String> {
+	let secret = tokio::fs::read_to_string(secret_file)
+		.await
+		.wrap_err_with(|| format!("Cannot read secret file `{}`", secret_file.display()))?;
+	Ok(secret.trim().to_string())
+}
+
+async fn deliver_once(webhook: &config::Webhook, body: &[u8]) -> Result<()> {
+	let mut request = reqwest::Client::new()
+		.post(&webhook.url)
+		.header("Content-Type", "application/json")
+		.body(body.to_vec());
+	if let Some(secret_file) = &webhook.secret_file {
+		let secret = read_secret(secret_file).await?;
+		request = request.header(
+			"X-Zonewatch-Signature",
+			format!("sha256={}", sign(secret.as_bytes(), body)),
+		);
+	}
+	let response = request
+		.send()
+		.await
+		.wrap_err_with(|| format!("Cannot send notification to {}", webhook.url))?;
+	let status = response.status();
+	if !status.is_success() {
+		let body = response.text().await.unwrap_or_default();
+		return Err(eyre!("The server responded with status {status}"))
+			.with_section(move || body.trim().to_string().header("Response:"));
+	}
+	Ok(())
+}
+
+async fn deliver(webhook: &config::Webhook, notification: &Notification) {
+	if !webhook.events.contains(&notification.event) {
+		return;
+	}
+	let body = serde_json::to_vec(notification).expect("The notification can always be serialized");
+	let what = format!("notify {} about zone {}", webhook.url, notification.zone);
+	match reloader::with_retries(&what, webhook.timeout, webhook.retry, || {
+		deliver_once(webhook, &body)
+	})
+	.await
+	{
+		Ok(()) => debug!("Notified {} about zone {}", webhook.url, notification.zone),
+		Err(e) => error!("{e:?}"),
+	}
+}
+
+#[derive(Clone)]
+pub struct Handle {
+	sender: mpsc::UnboundedSender<Notification>,
+}
+
+impl Handle {
+	pub fn notify(&self, notification: Notification) {
+		// Sending only fails if the receiving task is gone, which only happens when it panicked
+		if self.sender.send(notification).is_err() {
+			error!("The webhook task has stopped, cannot send notification");
+		}
+	}
+}
+
+// The task stops once every handle was dropped and all notifications were delivered
+pub fn spawn(webhooks: Vec<config::Webhook>) -> (Handle, JoinHandle<()>) {
+	let (sender, mut receiver) = mpsc::unbounded_channel::<Notification>();
+	let task = tokio::spawn(async move {
+		while let Some(notification) = receiver.recv().await {
+			futures::stream::iter(&webhooks)
+				.for_each_concurrent(None, |webhook| deliver(webhook, &notification))
+				.await;
+		}
+	});
+	(Handle { sender }, task)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::config::WebhookEvent;
+	use crate::test_util::test_zone;
+	use crate::webhook::{IncludeChange, Notification, sign};
+	use crate::zone_file;
+	use std::path::{Path, PathBuf};
+
+	#[test]
+	fn check_sign() {
+		// Test case 2 of RFC 4231
+		assert_eq!(
+			sign(b"Jefe", b"what do ya want for nothing?"),
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[test]
+	fn check_notifications() {
+		let path = PathBuf::from("/mx.zone");
+		let mut old_zone = test_zone("example.org", Path::new("/var/lib/zonewatch"));
+		old_zone.includes.insert(
+			path.clone(),
+			zone_file::Include::Readable(blake3::hash(b"")),
+		);
+		old_zone.includes_ordered.push(path.clone());
+		let mut zone = old_zone.clone();
+		zone.soa.serial = 2;
+		zone.includes.insert(path, zone_file::Include::NotFound);
+
+		let change = IncludeChange {
+			path: "/mx.zone".to_string(),
+			old_state: Some("readable"),
+			new_state: Some("not_found"),
+			reason: Some("the file was not found".to_string()),
+		};
+		let notification = |event| Notification {
+			event,
+			zone: "example.org".to_string(),
+			serial: 2,
+			old_serial: Some(1),
+			includes: vec![change.clone()],
+			error: None,
+		};
+		assert_eq!(
+			Notification::for_changes(&zone, Some(&old_zone)),
+			vec![
+				notification(WebhookEvent::SerialChange),
+				notification(WebhookEvent::IncludeStateChange),
+			]
+		);
+		assert_eq!(
+			serde_json::to_value(notification(WebhookEvent::IncludeStateChange))
+				.expect("can serialize")["event"],
+			"include_state_change"
+		);
+	}
+}
@@ -1,20 +1,33 @@
 // SPDX-FileCopyrightText: 2024 Luflosi <zonewatch@luflosi.de>
 // SPDX-License-Identifier: GPL-3.0-only
 
+use crate::config::StaleFilePolicy;
 use crate::db;
+use crate::master_file::parse_ttl;
 use atomic_write_file::{AtomicWriteFile, unix::OpenOptionsExt as AtomicOpenOptionsExt};
 use blake3::Hash;
 use color_eyre::eyre::{Result, WrapErr, eyre};
 use indoc::formatdoc;
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 use sqlx::{Sqlite, Transaction};
 use std::{
 	collections::HashMap,
+	ffi::OsString,
 	fs,
-	io::Write,
-	os::unix::fs::OpenOptionsExt as UnixOpenOptionsExt,
+	io::{ErrorKind, Write},
+	num::NonZeroUsize,
+	os::unix::{
+		ffi::{OsStrExt, OsStringExt},
+		fs::{MetadataExt, OpenOptionsExt as UnixOpenOptionsExt, symlink},
+	},
 	path::{Path, PathBuf},
+	sync::{
+		Arc, LazyLock, Mutex, PoisonError,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::Semaphore;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Zone {
@@ -29,10 +42,32 @@ pub struct Zone {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Include {
 	Readable(Hash),
-	// Provide a better user experience by explicitly handling the two probably most common errors
+	// Provide a better user experience by explicitly handling the most common errors
 	NotFound,
 	PermissionDenied,
-	OtherError,
+	IsADirectory,
+	TooManySymlinks,
+	InvalidData,
+	// Reading the file took too long, e.g. because it is on a hung network file system
+	Timeout,
+	OtherError(IoError),
+}
+
+// What went wrong reading a file, for errors without their own variant of `Include`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoError {
+	// Not every error comes from the operating system
+	pub errno: Option<i32>,
+	pub message: String,
+}
+
+impl From<&std::io::Error> for IoError {
+	fn from(e: &std::io::Error) -> Self {
+		Self {
+			errno: e.raw_os_error(),
+			message: e.to_string(),
+		}
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -47,10 +82,181 @@ pub struct Soa {
 	pub minimum: String,
 }
 
+// Limits how many files are hashed at the same time
+static HASHING_PERMITS: LazyLock<Semaphore> = LazyLock::new(|| {
+	Semaphore::new(std::thread::available_parallelism().map_or(4, NonZeroUsize::get))
+});
+
+// Included files whose reading did not finish within the timeout but is still going on in a blocking thread,
+// with the number of such reads. They are not read again until then, so that files on a hung
+// file system don't use up more and more threads.
+static STUCK_READS: LazyLock<Mutex<HashMap<PathBuf, usize>>> = LazyLock::new(Mutex::default);
+
+fn stuck_reads() -> std::sync::MutexGuard<'static, HashMap<PathBuf, usize>> {
+	// The map stays consistent even if a thread panicked while holding the lock
+	STUCK_READS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn mark_stuck(path: &Path) {
+	*stuck_reads().entry(path.to_path_buf()).or_default() += 1;
+}
+
+fn unmark_stuck(path: &Path) {
+	let mut stuck_reads = stuck_reads();
+	if let Some(count) = stuck_reads.get_mut(path) {
+		*count -= 1;
+		if *count == 0 {
+			stuck_reads.remove(path);
+		}
+	}
+}
+
+// Identifies a version of a file without reading it.
+// As long as none of these change, the contents of the file are assumed to be unchanged too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+	pub dev: u64,
+	pub ino: u64,
+	pub size: u64,
+	pub mtime_ns: i64,
+	pub ctime_ns: i64,
+}
+
+// The metadata of the readable included files
+pub type Metadata = HashMap<PathBuf, FileMetadata>;
+
+// File systems store timestamps with a coarse granularity (up to 2 seconds) and the kernel takes them
+// from a clock which may lag behind the system time
+const TIMESTAMP_GRANULARITY: Duration = Duration::from_secs(2);
+
+impl FileMetadata {
+	fn read(path: &Path) -> Option<Self> {
+		let metadata = fs::metadata(path).ok()?;
+		let nanoseconds = |seconds: i64, nanoseconds: i64| {
+			seconds
+				.saturating_mul(1_000_000_000)
+				.saturating_add(nanoseconds)
+		};
+		Some(Self {
+			dev: metadata.dev(),
+			ino: metadata.ino(),
+			size: metadata.size(),
+			mtime_ns: nanoseconds(metadata.mtime(), metadata.mtime_nsec()),
+			ctime_ns: nanoseconds(metadata.ctime(), metadata.ctime_nsec()),
+		})
+	}
+
+	// Like the "racy git" problem: a file modified again shortly after it was hashed may keep
+	// the same metadata, so metadata which isn't clearly older than the start of hashing is not trusted
+	fn is_racy(&self, hashing_start: SystemTime) -> bool {
+		let threshold = hashing_start
+			.checked_sub(TIMESTAMP_GRANULARITY)
+			.and_then(|threshold| threshold.duration_since(UNIX_EPOCH).ok())
+			.and_then(|threshold| i64::try_from(threshold.as_nanos()).ok())
+			.unwrap_or(i64::MAX);
+		self.mtime_ns >= threshold || self.ctime_ns >= threshold
+	}
+}
+
+// Where the zone file of the zone with this name is written.
+// Slashes of classless reverse zones are replaced by `%2F`, which cannot occur in the name otherwise.
+pub fn file_path(dir: &Path, zone_name: &str) -> PathBuf {
+	let file_name = zone_name.replace('/', "%2F");
+	dir.join(format!("{file_name}.zone"))
+}
+
+impl Zone {
+	pub fn file_path(&self) -> PathBuf {
+		file_path(&self.dir, &self.name)
+	}
+
+	// Older versions wrote durations like `1d` as they were configured, now they are written as seconds.
+	// Comparing the zone in this form keeps the upgrade from incrementing the serial of every zone.
+	pub fn with_durations_in_seconds(&self) -> Self {
+		let seconds = |value: &String| {
+			parse_ttl(value).map_or_else(|| value.clone(), |seconds| seconds.to_string())
+		};
+		Self {
+			ttl: seconds(&self.ttl),
+			soa: Soa {
+				ttl: seconds(&self.soa.ttl),
+				refresh: seconds(&self.soa.refresh),
+				retry: seconds(&self.soa.retry),
+				expire: seconds(&self.soa.expire),
+				minimum: seconds(&self.soa.minimum),
+				..self.soa.clone()
+			},
+			..self.clone()
+		}
+	}
+}
+
 impl Include {
-	pub fn read_from_fs(zone_name: &str, file_path: &Path) -> Self {
+	// Short description of the state, passed to the reload program
+	pub const fn state_name(&self) -> &'static str {
+		match self {
+			Self::Readable(_) => "readable",
+			Self::NotFound => "not_found",
+			Self::PermissionDenied => "permission_denied",
+			Self::IsADirectory => "is_a_directory",
+			Self::TooManySymlinks => "too_many_symlinks",
+			Self::InvalidData => "invalid_data",
+			Self::Timeout => "timeout",
+			Self::OtherError(_) => "error",
+		}
+	}
+
+	// Why the file cannot be included, `None` if it can
+	pub fn error_reason(&self) -> Option<String> {
+		let reason = match self {
+			Self::Readable(_) => return None,
+			Self::NotFound => "the file was not found",
+			Self::PermissionDenied => "we didn't have permission to read the file",
+			Self::IsADirectory => "it is a directory",
+			Self::TooManySymlinks => "resolving it ran into too many levels of symbolic links",
+			Self::InvalidData => "the file contains invalid data",
+			Self::Timeout => "reading the file took too long",
+			Self::OtherError(e) => {
+				// The message ends up in a comment, which has to stay on one line
+				let message = e.message.lines().collect::<Vec<_>>().join(" ");
+				return Some(format!("reading the file failed: {message}"));
+			}
+		};
+		Some(reason.to_string())
+	}
+
+	// Hashes the file unless its metadata shows that it is still the previously read version.
+	// The metadata is only returned if it can be trusted the next time.
+	pub fn read_from_fs(
+		zone_name: &str,
+		file_path: &Path,
+		previous: Option<(&Self, &FileMetadata)>,
+		hashing_start: SystemTime,
+	) -> (Self, Option<FileMetadata>) {
+		let metadata = FileMetadata::read(file_path);
+		if let (Some(metadata), Some((include @ Self::Readable(_), previous_metadata))) =
+			(metadata, previous)
+			&& metadata == *previous_metadata
+		{
+			debug!(
+				"File {} (included in zone {zone_name}) is unchanged according to its metadata, not hashing it",
+				file_path.display()
+			);
+			return (include.clone(), Some(metadata));
+		}
+
+		let include = Self::hash(zone_name, file_path);
+		let metadata = metadata.filter(|metadata| {
+			matches!(include, Self::Readable(_)) && !metadata.is_racy(hashing_start)
+		});
+		(include, metadata)
+	}
+
+	fn hash(zone_name: &str, file_path: &Path) -> Self {
 		trace!("Hashing file {}", file_path.display());
 		let mut hasher = blake3::Hasher::new();
+		// Files which cannot be mapped into memory, like pipes or files on some file systems,
+		// are read normally by blake3
 		match hasher.update_mmap(file_path) {
 			Ok(hasher) => {
 				let hash = hasher.finalize();
@@ -61,44 +267,130 @@ impl Include {
 				);
 				Self::Readable(hash)
 			}
-			Err(e) => match e.kind() {
-				std::io::ErrorKind::NotFound => {
-					warn!(
-						"File {} included in zone {zone_name} is missing",
+			Err(e) => {
+				let include = match e.kind() {
+					ErrorKind::NotFound => Self::NotFound,
+					ErrorKind::PermissionDenied => Self::PermissionDenied,
+					ErrorKind::IsADirectory => Self::IsADirectory,
+					ErrorKind::InvalidData => Self::InvalidData,
+					// `ErrorKind::FilesystemLoop` is not stable yet
+					_ if e.raw_os_error() == Some(libc::ELOOP) => Self::TooManySymlinks,
+					_ => Self::OtherError(IoError::from(&e)),
+				};
+				if matches!(include, Self::OtherError(_)) {
+					error!(
+						"Error reading file {} included in zone {zone_name}: {e}",
 						file_path.display()
 					);
-					Self::NotFound
-				}
-				std::io::ErrorKind::PermissionDenied => {
+				} else {
 					warn!(
-						"Permission denied reading file {} included in zone {zone_name}",
+						"Cannot read file {} included in zone {zone_name}: {e}",
 						file_path.display()
 					);
-					Self::PermissionDenied
 				}
-				_ => {
-					error!(
-						"Error reading file {} included in {zone_name}: {}",
-						file_path.display(),
-						e
-					);
-					Self::OtherError
+				include
+			}
+		}
+	}
+
+	// Hash the file on the blocking thread pool, giving up after `timeout`
+	async fn read_in_background(
+		zone_name: &str,
+		path: &Path,
+		previous: Option<(Self, FileMetadata)>,
+		hashing_start: SystemTime,
+		timeout: Duration,
+	) -> (Self, Option<FileMetadata>) {
+		if stuck_reads().contains_key(path) {
+			warn!(
+				"Still reading file {} included in zone {zone_name} since an earlier attempt timed out, not reading it again",
+				path.display()
+			);
+			return (Self::Timeout, None);
+		}
+
+		// The permit is released once the hashing finishes or gives up, so that a file on a hung
+		// file system doesn't hold back the other files forever
+		let _permit = HASHING_PERMITS
+			.acquire()
+			.await
+			.expect("The semaphore is never closed");
+		// Set by whichever comes first: the read finishing or giving up on it
+		let settled = Arc::new(AtomicBool::new(false));
+		let task = tokio::task::spawn_blocking({
+			let zone_name = zone_name.to_string();
+			let path = path.to_path_buf();
+			let settled = Arc::clone(&settled);
+			move || {
+				let previous = previous
+					.as_ref()
+					.map(|(include, metadata)| (include, metadata));
+				let result = Self::read_from_fs(&zone_name, &path, previous, hashing_start);
+				if settled.swap(true, Ordering::SeqCst) {
+					unmark_stuck(&path);
 				}
-			},
+				result
+			}
+		});
+		match tokio::time::timeout(timeout, task).await {
+			Ok(Ok(result)) => result,
+			Ok(Err(e)) => {
+				error!(
+					"Hashing file {} included in zone {zone_name} failed: {e}",
+					path.display()
+				);
+				let error = IoError {
+					errno: None,
+					message: e.to_string(),
+				};
+				(Self::OtherError(error), None)
+			}
+			Err(_) => {
+				error!(
+					"Reading file {} included in zone {zone_name} did not finish within {timeout:?}, giving up",
+					path.display()
+				);
+				// Marked before settling, so the thread can't finish in between without unmarking it
+				mark_stuck(path);
+				if settled.swap(true, Ordering::SeqCst) {
+					unmark_stuck(path);
+				}
+				(Self::Timeout, None)
+			}
 		}
 	}
 
-	pub fn files_from_paths<'a>(
+	// Hashes the files in parallel.
+	// `previous` are the includes and the metadata from the last time they were read, if hashing may be skipped
+	pub async fn files_from_paths<'a>(
 		zone_name: &str,
 		paths: impl Iterator<Item = &'a PathBuf>,
-	) -> HashMap<PathBuf, Self> {
-		paths
-			.map(|path| (path.clone(), Self::read_from_fs(zone_name, path)))
-			.collect()
+		previous: Option<(&HashMap<PathBuf, Self>, &Metadata)>,
+		timeout: Duration,
+	) -> (HashMap<PathBuf, Self>, Metadata) {
+		let hashing_start = SystemTime::now();
+		let reads = paths.map(|path| async move {
+			let previous = previous.and_then(|(includes, metadata)| {
+				Some((includes.get(path)?.clone(), *metadata.get(path)?))
+			});
+			let result =
+				Self::read_in_background(zone_name, path, previous, hashing_start, timeout).await;
+			(path, result)
+		});
+
+		let mut includes = HashMap::new();
+		let mut metadata = Metadata::new();
+		for (path, (include, file_metadata)) in futures::future::join_all(reads).await {
+			includes.insert(path.clone(), include);
+			if let Some(file_metadata) = file_metadata {
+				metadata.insert(path.clone(), file_metadata);
+			}
+		}
+		(includes, metadata)
 	}
 }
 
-fn construct_contents(zone: &Zone) -> String {
+fn construct_contents(zone: &Zone, embed_state: bool) -> String {
 	let mut zone_data = formatdoc! {"
 		; This file was automatically generated by zonewatch.
 		; Do not edit or your changes will be overwritten!
@@ -117,28 +409,219 @@ fn construct_contents(zone: &Zone) -> String {
 		let include = zone.includes.get(path).expect(
 			"The includes map should always contain the same keys as in the includes_ordered list",
 		);
-		let line = match include {
-			Include::Readable(_) => format!("$INCLUDE {}", path.display()),
-			Include::NotFound => format!(
-				"; $INCLUDE {} ; Commented out because the file was not found",
-				path.display()
-			),
-			Include::PermissionDenied => format!(
-				"; $INCLUDE {} ; Commented out because we didn't have permission to read the file",
-				path.display()
-			),
-			Include::OtherError => format!(
-				"; $INCLUDE {} ; Commented out because we couldn't read the file for some reason. Check the logs of zonewatch to find out more",
-				path.display()
-			),
-		};
+		let path = quote_path(path);
+		let line = include.error_reason().map_or_else(
+			|| format!("$INCLUDE {path}"),
+			|reason| format!("; $INCLUDE {path} ; Commented out because {reason}"),
+		);
 		zone_data.push('\n');
 		zone_data.push_str(line.as_str());
 	}
 	zone_data.push('\n');
+
+	// Lets zonewatch recover the state of the zone from this file when there is no database
+	if !embed_state {
+		return zone_data;
+	}
+	if !zone.includes_ordered.is_empty() {
+		zone_data
+			.push_str("\n; The state of the included files, used by zonewatch to detect changes\n");
+	}
+	for path in &zone.includes_ordered {
+		let include = &zone.includes[path];
+		let state = match include {
+			Include::Readable(hash) => format!("{} {}", include.state_name(), hash.to_hex()),
+			// The message is JSON encoded since it may contain spaces
+			Include::OtherError(e) => format!(
+				"{} {} {}",
+				include.state_name(),
+				e.errno
+					.map_or_else(|| "-".to_string(), |errno| errno.to_string()),
+				serde_json::Value::from(e.message.as_str()),
+			),
+			_ => include.state_name().to_string(),
+		};
+		let line = format!("{STATE_PREFIX}{state} {}\n", escape_path(path));
+		zone_data.push_str(&line);
+	}
 	zone_data
 }
 
+const STATE_PREFIX: &str = "; zonewatch-state: ";
+
+// Paths don't need to be valid UTF-8, but the zone file does.
+// Invalid bytes are written as `\DDD` like in RFC 1035, backslashes and quotes are escaped with a backslash.
+pub fn escape_path(path: &Path) -> String {
+	let mut escaped = String::new();
+	for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+		let valid = chunk.valid().replace('\\', "\\\\").replace('"', "\\\"");
+		escaped.push_str(&valid);
+		for byte in chunk.invalid() {
+			let byte = format!("\\{byte:03}");
+			escaped.push_str(&byte);
+		}
+	}
+	escaped
+}
+
+// A path as a quoted <character-string> of RFC 1035, so that spaces, `;` and parentheses
+// don't end it. Control characters are rejected when loading the config.
+fn quote_path(path: &Path) -> String {
+	format!("\"{}\"", escape_path(path))
+}
+
+// The reverse of `escape_path`
+pub fn unescape_path(escaped: &str) -> Result<PathBuf> {
+	let mut bytes = Vec::new();
+	let mut rest = escaped.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		rest = tail;
+		if byte != b'\\' {
+			bytes.push(byte);
+			continue;
+		}
+		match rest {
+			[a, b, c, tail @ ..] if [a, b, c].iter().all(|digit| digit.is_ascii_digit()) => {
+				let value = u8::try_from(
+					u32::from(a - b'0') * 100 + u32::from(b - b'0') * 10 + u32::from(c - b'0'),
+				)
+				.map_err(|_| eyre!("Invalid escape sequence in path `{escaped}`"))?;
+				bytes.push(value);
+				rest = tail;
+			}
+			[escaped_byte, tail @ ..] => {
+				bytes.push(*escaped_byte);
+				rest = tail;
+			}
+			[] => return Err(eyre!("Path `{escaped}` ends with a backslash")),
+		}
+	}
+	Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// Parse a line written for an include by `construct_contents`
+fn parse_include_state(state: &str) -> Result<(PathBuf, Include)> {
+	let (name, rest) = state
+		.split_once(' ')
+		.ok_or_else(|| eyre!("Missing path in include state `{state}`"))?;
+	let (include, path) = match name {
+		"readable" => {
+			let (hash, path) = rest
+				.split_once(' ')
+				.ok_or_else(|| eyre!("Missing path in include state `{state}`"))?;
+			let hash = Hash::from_hex(hash)
+				.wrap_err_with(|| format!("Invalid hash in include state `{state}`"))?;
+			(Include::Readable(hash), path)
+		}
+		"not_found" => (Include::NotFound, rest),
+		"permission_denied" => (Include::PermissionDenied, rest),
+		"is_a_directory" => (Include::IsADirectory, rest),
+		"too_many_symlinks" => (Include::TooManySymlinks, rest),
+		"invalid_data" => (Include::InvalidData, rest),
+		"timeout" => (Include::Timeout, rest),
+		"error" => {
+			let (errno, rest) = rest
+				.split_once(' ')
+				.ok_or_else(|| eyre!("Missing path in include state `{state}`"))?;
+			let errno = match errno {
+				"-" => None,
+				errno => Some(
+					errno
+						.parse()
+						.wrap_err_with(|| format!("Invalid errno in include state `{state}`"))?,
+				),
+			};
+			let mut messages = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+			let message = messages
+				.next()
+				.ok_or_else(|| eyre!("Missing error message in include state `{state}`"))?
+				.wrap_err_with(|| format!("Invalid error message in include state `{state}`"))?;
+			let path = rest[messages.byte_offset()..]
+				.strip_prefix(' ')
+				.ok_or_else(|| eyre!("Missing path in include state `{state}`"))?;
+			(Include::OtherError(IoError { errno, message }), path)
+		}
+		_ => return Err(eyre!("Unknown include state `{name}`")),
+	};
+	Ok((unescape_path(path)?, include))
+}
+
+// The value in front of the comment on one of the lines of the SOA record
+fn soa_value<'a>(lines: &mut impl Iterator<Item = &'a str>, field: &str) -> Result<String> {
+	lines
+		.next()
+		.and_then(|line| line.split(';').next())
+		.map(str::trim)
+		.filter(|value| !value.is_empty())
+		.map(str::to_string)
+		.ok_or_else(|| eyre!("Missing {field} in SOA record"))
+}
+
+// Recover the zone from a file written by `construct_contents`.
+// Files written with a database have no include states, so the includes appear to have changed.
+fn parse_contents(zone_name: &str, dir: &Path, contents: &str) -> Result<Zone> {
+	let mut lines = contents.lines();
+	let mut ttl = None;
+	let mut soa = None;
+	let mut includes = HashMap::new();
+	let mut includes_ordered = Vec::new();
+	while let Some(line) = lines.next() {
+		if let Some(origin) = line.strip_prefix("$ORIGIN ") {
+			if origin.trim() != format!("{zone_name}.") {
+				return Err(eyre!("The file is for the zone `{}`", origin.trim()));
+			}
+		} else if let Some(value) = line.strip_prefix("$TTL ") {
+			ttl = Some(value.trim().to_string());
+		} else if line.starts_with("@ ") {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			let [_, soa_ttl, "IN", "SOA", mname, rname, "("] = fields[..] else {
+				return Err(eyre!("Cannot parse SOA record `{line}`"));
+			};
+			let serial = soa_value(&mut lines, "serial")?;
+			soa = Some(Soa {
+				ttl: soa_ttl.to_string(),
+				mname: mname.to_string(),
+				rname: rname.to_string(),
+				serial: serial
+					.parse()
+					.wrap_err_with(|| format!("Invalid serial `{serial}`"))?,
+				refresh: soa_value(&mut lines, "refresh")?,
+				retry: soa_value(&mut lines, "retry")?,
+				expire: soa_value(&mut lines, "expire")?,
+				minimum: soa_value(&mut lines, "minimum")?,
+			});
+		} else if let Some(state) = line.strip_prefix(STATE_PREFIX) {
+			let (path, include) = parse_include_state(state)?;
+			includes_ordered.push(path.clone());
+			includes.insert(path, include);
+		}
+	}
+
+	Ok(Zone {
+		name: zone_name.to_string(),
+		dir: dir.to_path_buf(),
+		ttl: ttl.ok_or_else(|| eyre!("Missing $TTL"))?,
+		includes,
+		includes_ordered,
+		soa: soa.ok_or_else(|| eyre!("Missing SOA record"))?,
+	})
+}
+
+// The state of the zone according to its existing zone file, used when the database doesn't know the zone
+pub fn read_from_disc(zone_name: &str, dir: &Path) -> Result<Option<Zone>> {
+	let path = file_path(dir, zone_name);
+	let contents = match fs::read_to_string(&path) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+		Err(e) => {
+			return Err(e).wrap_err_with(|| format!("Cannot read zone file {}", path.display()));
+		}
+	};
+	parse_contents(zone_name, dir, &contents)
+		.wrap_err_with(|| format!("Cannot parse zone file {}", path.display()))
+		.map(Some)
+}
+
 fn write(zone_name: &str, path: &Path, data: &str) -> Result<()> {
 	debug!("Saving file {} (zone {zone_name})", path.display());
 
@@ -182,37 +665,335 @@ fn write(zone_name: &str, path: &Path, data: &str) -> Result<()> {
 	Ok(())
 }
 
-pub async fn sync_state_to_disc(zone: Zone, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
-	db::write_state(&zone, tx)
+pub async fn sync_state_to_disc(
+	zone: &Zone,
+	embed_state: bool,
+	tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+	db::write_state(zone, tx)
 		.await
 		.wrap_err("Cannot sync state to database")?;
 
-	let zone_file_contents = construct_contents(&zone);
+	let zone_file_contents = construct_contents(zone, embed_state);
 	trace!(
 		"Writing contents (zone {})\n{zone_file_contents}",
 		zone.name
 	);
 
-	let zone_file_name = format!("{}.zone", zone.name);
-	let zone_file_path = Path::new(&zone.dir).join(zone_file_name);
+	let zone_file_path = zone.file_path();
 	write(&zone.name, &zone_file_path, &zone_file_contents)
 		.wrap_err("Cannot write new zone file")?;
 
 	Ok(())
 }
 
+// Called after the zone file was written to a new path, e.g. because the `dir` of the zone changed
+pub fn handle_stale_file(
+	zone_name: &str,
+	old_path: &Path,
+	new_path: &Path,
+	policy: StaleFilePolicy,
+) -> Result<()> {
+	info!(
+		"Zone file of zone {zone_name} moved from {} to {}",
+		old_path.display(),
+		new_path.display()
+	);
+	match policy {
+		StaleFilePolicy::Keep => {
+			warn!(
+				"The old zone file {} of zone {zone_name} is left behind",
+				old_path.display()
+			);
+		}
+		StaleFilePolicy::Remove => {
+			match fs::remove_file(old_path) {
+				Err(e) if e.kind() != ErrorKind::NotFound => {
+					return Err(e).wrap_err_with(|| {
+						format!("Cannot remove the old zone file {}", old_path.display())
+					});
+				}
+				_ => {}
+			}
+			info!(
+				"Removed the old zone file {} of zone {zone_name}",
+				old_path.display()
+			);
+		}
+		StaleFilePolicy::Symlink => {
+			// Create the symlink next to the old file first, so that the old path never disappears
+			let mut tmp_path = old_path.as_os_str().to_owned();
+			tmp_path.push(".zonewatch-tmp");
+			let tmp_path = PathBuf::from(tmp_path);
+			match fs::remove_file(&tmp_path) {
+				Err(e) if e.kind() != ErrorKind::NotFound => {
+					return Err(e).wrap_err_with(|| {
+						format!("Cannot remove the leftover file {}", tmp_path.display())
+					});
+				}
+				_ => {}
+			}
+			symlink(new_path, &tmp_path)
+				.wrap_err_with(|| format!("Cannot create symlink {}", tmp_path.display()))?;
+			fs::rename(&tmp_path, old_path).wrap_err_with(|| {
+				format!(
+					"Cannot replace the old zone file {} with a symlink",
+					old_path.display()
+				)
+			})?;
+			info!(
+				"Replaced the old zone file {} of zone {zone_name} with a symlink to {}",
+				old_path.display(),
+				new_path.display()
+			);
+		}
+	}
+	Ok(())
+}
+
 #[cfg(test)]
 mod test {
-	use crate::zone_file::{HashMap, Include, PathBuf};
+	use crate::config::StaleFilePolicy;
+	use crate::test_util::{TempDir, test_zone};
+	use crate::zone_file::{
+		Duration, FileMetadata, HashMap, Include, IoError, PathBuf, SystemTime, construct_contents,
+		file_path, handle_stale_file, parse_contents, stuck_reads,
+	};
+	use nix::{sys::stat::Mode, unistd::mkfifo};
 
-	#[test]
-	fn check_files_from_paths_empty() {
+	#[tokio::test]
+	async fn check_files_from_paths_empty() {
 		use std::iter;
 
 		let zone_name = "test";
 		let input = iter::empty::<&PathBuf>();
-		let expected_output = HashMap::new();
-		let actual_output = Include::files_from_paths(zone_name, input);
+		let expected_output = (HashMap::new(), HashMap::new());
+		let actual_output =
+			Include::files_from_paths(zone_name, input, None, Duration::from_secs(1)).await;
 		assert_eq!(actual_output, expected_output);
 	}
+
+	#[test]
+	fn check_file_path() {
+		let dir = PathBuf::from("/var/lib/zonewatch");
+		assert_eq!(file_path(&dir, "example.org"), dir.join("example.org.zone"));
+		assert_eq!(
+			file_path(&dir, "0/25.2.0.192.in-addr.arpa"),
+			dir.join("0%2F25.2.0.192.in-addr.arpa.zone")
+		);
+	}
+
+	#[test]
+	fn check_handle_stale_file() {
+		let temp_dir = TempDir::new("stale");
+		let dir = temp_dir.path();
+		let old_path = dir.join("old.zone");
+		let new_path = dir.join("new.zone");
+		std::fs::write(&new_path, "new").expect("can write");
+
+		std::fs::write(&old_path, "old").expect("can write");
+		handle_stale_file("test", &old_path, &new_path, StaleFilePolicy::Keep).expect("can keep");
+		assert_eq!(std::fs::read_to_string(&old_path).expect("can read"), "old");
+
+		handle_stale_file("test", &old_path, &new_path, StaleFilePolicy::Symlink)
+			.expect("can replace");
+		assert_eq!(
+			std::fs::read_link(&old_path).expect("is a symlink"),
+			new_path
+		);
+		assert_eq!(std::fs::read_to_string(&old_path).expect("can read"), "new");
+
+		handle_stale_file("test", &old_path, &new_path, StaleFilePolicy::Remove)
+			.expect("can remove");
+		assert!(!old_path.exists());
+		// Removing it again is fine
+		handle_stale_file("test", &old_path, &new_path, StaleFilePolicy::Remove)
+			.expect("can remove");
+	}
+
+	#[test]
+	fn check_parse_contents() {
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+
+		let dir = PathBuf::from("/var/lib/zones");
+		// Latin-1 instead of UTF-8
+		let latin1 = PathBuf::from(OsStr::from_bytes(b"/path/to/caf\xe9\\1.zone"));
+		let mut zone = test_zone("example.org", &dir);
+		zone.soa.serial = 42;
+		zone.includes = HashMap::from([
+			(
+				PathBuf::from("/path/to/file 1.zone"),
+				Include::Readable(blake3::hash(b"contents")),
+			),
+			(PathBuf::from("/path/to/file 2.zone"), Include::NotFound),
+			(latin1.clone(), Include::Readable(blake3::hash(b"latin1"))),
+			(
+				PathBuf::from("/path/to/\"file\" 3.zone"),
+				Include::OtherError(IoError {
+					errno: Some(5),
+					message: "Input/output \"error\" (os error 5)".to_string(),
+				}),
+			),
+		]);
+		zone.includes_ordered = vec![
+			PathBuf::from("/path/to/file 1.zone"),
+			PathBuf::from("/path/to/file 2.zone"),
+			latin1,
+			PathBuf::from("/path/to/\"file\" 3.zone"),
+		];
+		let contents = construct_contents(&zone, true);
+		assert!(contents.contains("\n$INCLUDE \"/path/to/file 1.zone\"\n"));
+		assert!(contents.contains("\n$INCLUDE \"/path/to/caf\\233\\\\1.zone\"\n"));
+		assert!(contents.contains("\n; $INCLUDE \"/path/to/\\\"file\\\" 3.zone\" ; Commented out"));
+		assert_eq!(
+			parse_contents("example.org", &dir, &contents).expect("can parse"),
+			zone
+		);
+		assert!(parse_contents("example.com", &dir, &contents).is_err());
+	}
+
+	#[test]
+	fn check_metadata_fast_path() {
+		let temp_dir = TempDir::new("metadata");
+		let dir = temp_dir.path();
+		let path = dir.join("include.zone");
+		std::fs::write(&path, "contents").expect("can write");
+
+		// The file was just written, so it may still change without changing its metadata
+		let (include, metadata) = Include::read_from_fs("test", &path, None, SystemTime::now());
+		assert_eq!(include, Include::Readable(blake3::hash(b"contents")));
+		assert_eq!(metadata, None);
+
+		// Pretend that hashing started a while after the file was written
+		let later = SystemTime::now() + Duration::from_mins(1);
+		let (_, metadata) = Include::read_from_fs("test", &path, None, later);
+		let metadata = metadata.expect("readable files have metadata");
+
+		// With unchanged metadata, the previous hash is trusted without reading the file
+		let previous = Include::Readable(blake3::hash(b"previous"));
+		let (include, _) =
+			Include::read_from_fs("test", &path, Some((&previous, &metadata)), later);
+		assert_eq!(include, previous);
+
+		let outdated = FileMetadata {
+			size: metadata.size + 1,
+			..metadata
+		};
+		let (include, _) =
+			Include::read_from_fs("test", &path, Some((&previous, &outdated)), later);
+		assert_eq!(include, Include::Readable(blake3::hash(b"contents")));
+	}
+
+	#[tokio::test]
+	async fn check_timeout() {
+		let temp_dir = TempDir::new("timeout");
+		let dir = temp_dir.path();
+		// Opening a FIFO blocks until something opens it for writing
+		let fifo = dir.join("fifo.zone");
+		mkfifo(&fifo, Mode::S_IRWXU).expect("can create FIFO");
+		let paths: Vec<PathBuf> = (0..3)
+			.map(|index| {
+				let path = dir.join(format!("{index}.zone"));
+				std::fs::write(&path, index.to_string()).expect("can write");
+				path
+			})
+			.collect();
+		let timeout = Duration::from_millis(500);
+
+		// The other files are hashed while reading the FIFO hangs
+		let (includes, _) =
+			Include::files_from_paths("test", paths.iter().chain([&fifo]), None, timeout).await;
+		assert_eq!(includes[&fifo], Include::Timeout);
+		for (index, path) in paths.iter().enumerate() {
+			assert_eq!(
+				includes[path],
+				Include::Readable(blake3::hash(index.to_string().as_bytes()))
+			);
+		}
+
+		// The FIFO is not read again while the first attempt still hangs
+		let start = std::time::Instant::now();
+		let (includes, _) =
+			Include::files_from_paths("test", [&fifo].into_iter(), None, timeout).await;
+		assert_eq!(includes[&fifo], Include::Timeout);
+		assert!(start.elapsed() < timeout);
+
+		// Let the hanging read finish
+		drop(
+			std::fs::OpenOptions::new()
+				.write(true)
+				.open(&fifo)
+				.expect("can open FIFO"),
+		);
+		let start = std::time::Instant::now();
+		while stuck_reads().contains_key(&fifo) {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+	}
+
+	#[test]
+	fn check_hash() {
+		use std::io::Write;
+
+		let temp_dir = TempDir::new("hash");
+		let dir = temp_dir.path();
+
+		// Big enough to be mapped into memory
+		let big = dir.join("big.zone");
+		let contents = "a 1 IN A 192.0.2.1\n".repeat(10_000);
+		std::fs::write(&big, &contents).expect("can write");
+		assert_eq!(
+			Include::hash("test", &big),
+			Include::Readable(blake3::hash(contents.as_bytes()))
+		);
+
+		// Pipes cannot be mapped into memory and are read normally
+		let fifo = dir.join("fifo.zone");
+		mkfifo(&fifo, Mode::S_IRWXU).expect("can create FIFO");
+		let writer = std::thread::spawn({
+			let fifo = fifo.clone();
+			move || {
+				let mut file = std::fs::OpenOptions::new()
+					.write(true)
+					.open(fifo)
+					.expect("can open FIFO");
+				file.write_all(b"contents").expect("can write");
+			}
+		});
+		assert_eq!(
+			Include::hash("test", &fifo),
+			Include::Readable(blake3::hash(b"contents"))
+		);
+		writer.join().expect("the writer does not panic");
+
+		assert_eq!(Include::hash("test", dir), Include::IsADirectory);
+		assert_eq!(
+			Include::hash("test", &dir.join("missing.zone")),
+			Include::NotFound
+		);
+	}
+
+	#[test]
+	fn check_with_durations_in_seconds() {
+		use crate::test_util::test_zone;
+
+		// Written by an older version
+		let old_zone = test_zone("example.org", &PathBuf::from("/var/lib/zonewatch"));
+		let zone = old_zone.with_durations_in_seconds();
+		assert_eq!(zone.ttl, "86400");
+		assert_eq!(
+			[
+				&zone.soa.ttl,
+				&zone.soa.refresh,
+				&zone.soa.retry,
+				&zone.soa.expire,
+				&zone.soa.minimum
+			],
+			["86400", "86400", "7200", "3600000", "3600"]
+		);
+		assert_eq!(zone.soa.serial, old_zone.soa.serial);
+		assert_eq!(zone.with_durations_in_seconds(), zone);
+	}
 }